@@ -72,6 +72,15 @@ fn main() -> io::Result<()> {
                 .required(false)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("import")
+                .long("import")
+                .help("Bulk loads a GeoJSON FeatureCollection or a lat,lng,name CSV file into a geo key.")
+                .required(false)
+                .takes_value(true)
+                .number_of_values(2)
+                .value_names(&["KEY", "FILE"]),
+        )
         .arg(
             Arg::with_name("command")
                 .help("command...")
@@ -98,6 +107,12 @@ fn main() -> io::Result<()> {
         hostname = _hostname;
     }
 
+    if let Some(mut import) = matches.values_of("import") {
+        let key = import.next().unwrap();
+        let file = import.next().unwrap();
+        return import_geo(hostname, port, password, db, key, file);
+    }
+
     let mut interface = Interface::new("Escanor client 0.0.1")?;
     interface.set_report_signal(Signal::Interrupt, true);
 
@@ -150,7 +165,7 @@ fn run_program(client: &mut Client, interface: &mut Interface<DefaultTerminal>)
                 match client.cmd(&ref_commands) {
                     Ok(v) => {
                         let va = v.to_beautify_string();
-                        if cmd.to_uppercase() == "JGET" || cmd.to_uppercase() == "GEOJSON" || cmd.to_uppercase() == "JPATH" {
+                        if cmd.to_uppercase() == "JGET" || cmd.to_uppercase() == "GEOJSON" || cmd.to_uppercase() == "JPATH" || cmd.to_uppercase() == "GEOGETATTR" || cmd.to_uppercase() == "JDIFF" {
                             let jva = va.to_owned();
                             let json : JsonValue = serde_json::from_str(&jva.trim_matches(&['"'] as &[_])).unwrap_or(JsonValue::Null);
                             if json.is_null() || json.is_f64() {
@@ -177,6 +192,22 @@ fn run_program(client: &mut Client, interface: &mut Interface<DefaultTerminal>)
     Ok(())
 }
 
+fn import_geo(hostname: &str, port: u16, password: &str, db: u16, key: &str, file: &str) -> io::Result<()> {
+    let contents = std::fs::read_to_string(file)?;
+    let format = if file.to_lowercase().ends_with(".csv") { "CSV" } else { "GEOJSON" };
+    let mut client = create_client(hostname, port, password, db)?;
+    match client.cmd(&["GEOIMPORT", key, format, &contents])? {
+        Value::Error(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        v => {
+            println!("{}", v.to_beautify_string());
+        }
+    }
+    Ok(())
+}
+
 fn ex_sys_cmd(command: &str, _rinterface: &mut Interface<DefaultTerminal>) {
     match command {
         "exit" => {
@@ -15,7 +15,10 @@ pub struct RespCodec;
 pub struct ClientRequest {
     #[serde(skip_serializing)]
     pub context : Arc<RwLock<Context>>,
-    pub frame : Frame
+    pub frame : Frame,
+    /// Milliseconds since the epoch when the request was proposed, every replica applies it with this clock
+    #[serde(default)]
+    pub timestamp : u64
 }
 
 #[derive( Debug, Clone,Serialize, Deserialize)]
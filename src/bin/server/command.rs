@@ -9,9 +9,16 @@ use crate::syntax_analyzer;
 
 
 use crate::unit_conv::Units;
+use crate::geo::{GeoFilter, SpatialOp};
+use crate::json::JsonFormat;
+use crate::json_index::FindQuery;
+use crate::json_agg::AggQuery;
+use crate::json_search::SearchQuery;
+use crate::json_history::HistoryPolicy;
 use crate::storage;
 
 use redis_protocol::types::Frame;
+use bytes::BytesMut;
 use serde_json::Value;
 use crate::printer::*;
 
@@ -23,6 +30,38 @@ pub fn compile_frame(frame: &Frame) -> Result<Box<dyn Command>, error::SyntaxErr
     }
 }
 
+/// Read only commands that are answered from this node's state instead of going through the raft log,
+/// they scan many keys and would otherwise hold up the writes queued behind them
+const LOCAL_READS: [&str; 1] = ["jagg"];
+
+pub fn is_local_read(frame: &Frame) -> bool {
+    let tokens = tokenizer::generate_token_from_frame(frame);
+    tokens.first().map_or(false, |cmd| LOCAL_READS.contains(&cmd.to_lowercase().as_str()))
+}
+
+/// Commands only the server itself proposes, client connections get a syntax error for them
+const INTERNAL_COMMANDS: [&str; 1] = ["geosweep"];
+
+pub fn is_internal(frame: &Frame) -> bool {
+    let tokens = tokenizer::generate_token_from_frame(frame);
+    tokens.first().map_or(false, |cmd| INTERNAL_COMMANDS.contains(&cmd.to_lowercase().as_str()))
+}
+
+/// Compiles and runs a frame outside of raft, see `LOCAL_READS`
+pub fn execute_frame(frame: &Frame, context: Arc<RwLock<Context>>) -> Frame {
+    let response_message = match compile_frame(frame) {
+        Ok(cmd) => cmd.execute(context),
+        Err(err) => {
+            return Frame::Error(err.to_string());
+        }
+    };
+    let buf: BytesMut = BytesMut::from(response_message.as_bytes());
+    match redis_protocol::decode::decode_bytes(&buf) {
+        Ok((Some(frame), _)) => frame,
+        _ => Frame::Null
+    }
+}
+
 pub fn compile(buf: &[u8]) -> Result<Box<dyn Command>, error::SyntaxError> {
     let _empty_string = String::new();
     let first_char = buf[0] as char;
@@ -145,6 +184,21 @@ pub enum ArgOrder {
     DESC,
     UNSPECIFIED,
 }
+#[derive(Debug, Clone, Copy)]
+pub enum GeoImportFormat {
+    GeoJson,
+    Csv,
+}
+#[derive(Debug, Clone, Copy)]
+pub enum GeoJoinMode {
+    Within(f64),
+    Nearest(usize, Option<f64>),
+}
+#[derive(Debug, Clone)]
+pub enum GeoQueryTarget {
+    Geometry(String),
+    Member(String),
+}
 pub type CmdGeoItem = (f64, f64, String);
 
 pub type JSetArgItem = (String, Value);
@@ -190,21 +244,70 @@ make_command!(ExpireAtCmd{arg_key: String, arg_value : i64} -> db::expire_at);
 make_command!(KeysCmd{pattern : String} -> db::keys);
 make_command!(ExistsCmd{keys : Vec<String>} -> db::exists);
 // Geo Spatial Commands
-make_command!(GeoAddCmd{arg_key : String, items : Vec<CmdGeoItem>} -> db::geo_add);
-make_command!(GeoRadiusCmd{arg_key: String, arg_lng: f64,arg_lat: f64,arg_radius: f64,arg_unit: Units,arg_order: ArgOrder} -> db::geo_radius);
+make_command!(GeoAddCmd{arg_key : String, arg_expires_at : Option<u64>, arg_track : bool, arg_timestamp : u64, items : Vec<CmdGeoItem>} -> db::geo_add);
+make_command!(GeoAddShapeCmd{arg_key : String, arg_member : String, arg_value : String} -> db::geo_add_shape);
+make_command!(GeoRadiusCmd{arg_key: String, arg_lng: f64,arg_lat: f64,arg_radius: f64,arg_unit: Units,arg_order: ArgOrder,arg_filter: Option<GeoFilter>,arg_geo_json: Option<GeoJsonOptions>} -> db::geo_radius);
 make_command!(GeoHashCmd{arg_key : String, items : Vec<String>} -> db::geo_hash);
 make_command!(GeoPosCmd{arg_key : String, items : Vec<String>} -> db::geo_pos);
-make_command!(GeoRadiusByMemberCmd{arg_key: String,member: String,arg_radius: f64,arg_unit: Units,arg_order: ArgOrder} -> db::geo_radius_by_member);
-make_command!(GeoDistCmd{arg_key: String,arg_mem_1: String,arg_mem_2: String,arg_unit: Units} -> db::geo_dist);
+make_command!(GeoRadiusByMemberCmd{arg_key: String,member: String,arg_radius: f64,arg_unit: Units,arg_order: ArgOrder,arg_filter: Option<GeoFilter>,arg_geo_json: Option<GeoJsonOptions>} -> db::geo_radius_by_member);
+make_command!(GeoNearestCmd{arg_key: String,arg_lng: f64,arg_lat: f64,arg_count: usize,arg_unit: Units,arg_max_dist: Option<f64>,arg_filter: Option<GeoFilter>,arg_geo_json: Option<GeoJsonOptions>} -> db::geo_nearest);
+make_command!(GeoBoxCmd{arg_key: String,arg_min_lng: f64,arg_min_lat: f64,arg_max_lng: f64,arg_max_lat: f64,arg_filter: Option<GeoFilter>,arg_geo_json: Option<GeoJsonOptions>} -> db::geo_box);
+make_command!(GeoPolygonCmd{arg_key: String,arg_vertices: Vec<(f64, f64)>,arg_filter: Option<GeoFilter>,arg_geo_json: Option<GeoJsonOptions>} -> db::geo_polygon);
+make_command!(GeoClusterCmd{arg_key : String, arg_min_lng : f64, arg_min_lat : f64, arg_max_lng : f64, arg_max_lat : f64, arg_zoom : u8, arg_bounds : bool, arg_filter : Option<GeoFilter>, arg_geo_json : Option<GeoJsonOptions>} -> db::geo_cluster);
+make_command!(GeoQueryCmd{arg_key : String, arg_op : SpatialOp, arg_target : GeoQueryTarget, arg_filter : Option<GeoFilter>, arg_geo_json : Option<GeoJsonOptions>} -> db::geo_query);
+make_command!(GeoDistCmd{arg_key: String,arg_mem_1: String,arg_mem_2: String,arg_unit: Units,arg_wgs84: bool} -> db::geo_dist);
 make_command!(GeoDelCmd{arg_key: String} -> db::geo_del);
 make_command!(GeoRemoveCmd{arg_key : String, items : Vec<String>} -> db::geo_remove);
-make_command!(GeoJsonCmd{arg_key : String,items : Vec<String>} -> db::geo_json);
+make_command!(GeoTrackCmd{arg_key : String, arg_member : String, arg_from : u64, arg_to : u64, arg_geo_json : Option<GeoJsonOptions>} -> db::geo_track);
+make_command!(GeoJoinCmd{arg_left_key : String, arg_right_key : String, arg_mode : GeoJoinMode, arg_unit : Units, arg_store : Option<String>} -> db::geo_join);
+make_command!(GeoSweepCmd{arg_timestamp : u64} -> db::geo_sweep);
+make_command!(GeoJsonCmd{arg_key : String,items : Vec<String>,arg_options : GeoJsonOptions} -> db::geo_json);
+make_command!(GeoImportCmd{arg_key : String, arg_format : GeoImportFormat, arg_value : String} -> db::geo_import);
+make_command!(GeoSetAttrCmd{arg_key : String, arg_member : String, arg_value : String} -> db::geo_set_attr);
+make_command!(GeoMergeAttrCmd{arg_key : String, arg_member : String, arg_value : String} -> db::geo_merge_attr);
+make_command!(GeoGetAttrCmd{arg_key : String, arg_member : String, arg_dot_path : Option<String>} -> db::geo_get_attr);
 // json commands
 make_command!(JSetRawCmd{arg_key : String, arg_value: String} -> db::jset_raw);
 make_command!(JSetCmd{arg_key : String, arg_set_items : Vec<JSetArgItem>} -> db::jset);
 make_command!(JMergeCmd{arg_key : String,  arg_value : String} -> db::jmerge);
-make_command!(JGetCmd{arg_key : String, arg_dot_path : Option<String>} -> db::jget);
+make_command!(JPatchCmd{arg_key : String, arg_value : String} -> db::jpatch);
+make_command!(JGetCmd{arg_key : String, arg_paths : Vec<String>, arg_format : JsonFormat, arg_project : bool} -> db::jget);
 make_command!(JPathCmd{arg_key : String, arg_selector : String} -> db::jpath);
+make_command!(JPathScanCmd{arg_pattern : String, arg_selector : String} -> db::jpath_scan);
+make_command!(JMGetCmd{arg_keys : Vec<String>, arg_path : String} -> db::jmget);
+make_command!(JPathSetCmd{arg_key : String, arg_selector : String, arg_value : Value} -> db::jpath_set);
+make_command!(JPathDelCmd{arg_key : String, arg_selector : String} -> db::jpath_del);
+make_command!(JPathIncrCmd{arg_key : String, arg_selector : String, arg_increment_value : f64} -> db::jpath_incr);
+make_command!(JArrAppendCmd{arg_key : String, arg_path : String, arg_values : Vec<Value>} -> db::jarr_append);
+make_command!(JArrInsertCmd{arg_key : String, arg_path : String, arg_index : i64, arg_values : Vec<Value>} -> db::jarr_insert);
+make_command!(JArrPopCmd{arg_key : String, arg_path : String, arg_index : i64} -> db::jarr_pop);
+make_command!(JArrLenCmd{arg_key : String, arg_path : String} -> db::jarr_len);
+make_command!(JArrIndexCmd{arg_key : String, arg_path : String, arg_value : Value, arg_start : i64, arg_stop : Option<i64>} -> db::jarr_index);
+make_command!(JArrTrimCmd{arg_key : String, arg_path : String, arg_start : i64, arg_stop : i64} -> db::jarr_trim);
+make_command!(JTypeCmd{arg_key : String, arg_path : String} -> db::jtype);
+make_command!(JObjKeysCmd{arg_key : String, arg_path : String} -> db::jobj_keys);
+make_command!(JObjLenCmd{arg_key : String, arg_path : String} -> db::jobj_len);
+make_command!(JStrLenCmd{arg_key : String, arg_path : String} -> db::jstr_len);
+make_command!(JStrAppendCmd{arg_key : String, arg_path : String, arg_value : String} -> db::jstr_append);
+make_command!(JToggleCmd{arg_key : String, arg_path : String} -> db::jtoggle);
+make_command!(JIndexCmd{arg_name : String, arg_prefix : String, arg_path : String} -> db::jindex);
+make_command!(JIndexDropCmd{arg_name : String} -> db::jindex_drop);
+make_command!(JIndexListCmd; -> db::jindex_list);
+make_command!(JFindCmd{arg_query : FindQuery} -> db::jfind);
+make_command!(JAggCmd{arg_query : AggQuery} -> db::jagg);
+make_command!(JFtIndexCmd{arg_name : String, arg_prefix : String, arg_paths : Vec<String>} -> db::jft_index);
+make_command!(JFtDropCmd{arg_name : String} -> db::jft_drop);
+make_command!(JFtListCmd; -> db::jft_list);
+make_command!(JFtSearchCmd{arg_query : SearchQuery} -> db::jft_search);
+make_command!(JHistoryCmd{arg_key : String, arg_policy : Option<HistoryPolicy>} -> db::jhistory);
+make_command!(JRevListCmd{arg_key : String} -> db::jrev_list);
+make_command!(JRevGetCmd{arg_key : String, arg_revision : u64} -> db::jrev_get);
+make_command!(JRevDiffCmd{arg_key : String, arg_from : u64, arg_to : u64} -> db::jrev_diff);
+make_command!(JRevertCmd{arg_key : String, arg_revision : u64} -> db::jrevert);
+make_command!(JDiffCmd{arg_key : String, arg_other_key : Option<String>, arg_value : Option<Value>, arg_paths : bool} -> db::jdiff);
+make_command!(JSchemaCmd{arg_pattern : String, arg_schema : Value} -> db::jschema);
+make_command!(JSchemaDelCmd{arg_pattern : String} -> db::jschema_del);
+make_command!(JSchemaListCmd; -> db::jschema_list);
 make_command!(JDelCmd{arg_key :String} -> db::jdel);
 make_command!(JRemCmd{arg_key : String, arg_paths : Vec<String>} -> db::jrem);
 make_command!(JIncrByCmd{arg_key: String, arg_path: String,arg_increment_value: i64} -> db::jincr_by);
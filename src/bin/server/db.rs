@@ -6,17 +6,19 @@ extern crate regex;
 use sled;
 use sled::Db;
 use crate::error::DatabaseError;
-use crate::{config, unit_conv};
+use crate::{config, unit_conv, geo, geo_store, json, json_agg, json_index, json_pack, json_schema, json_search, json_history, storage};
+use crate::geo_store::{GeoTree, LegacyGeoTree};
+use crate::json_index::IndexDef;
+use crate::json_search::FtIndexDef;
 use crate::network::Context;
 use crate::command::SetCmd;
 use serde::{Serialize, Deserialize};
 use serde_json::Value;
-use crate::geo::{GeoPoint2D, Circle};
+use crate::geo::{GeoPoint2D, GeoShape, GeoMember, Geometry, Polygon, Trajectory};
 use crate::command::*;
 use crate::printer::*;
 use crate::error::ParseDataError;
 use crate::util;
-use rstar::{RTree, Point};
 use crate::util::Location;
 
 extern crate nanoid;
@@ -62,12 +64,6 @@ trait RespResponse {
     fn to_resp(&self) -> String;
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct GeoTree {
-    rtree: RTree<GeoPoint2D>,
-    hash: HashSet<GeoPoint2D>,
-}
-
 pub fn export_db() -> Result<Vec<u8>> {
     let export= DB.export();
     let mut items: Vec<(Vec<u8>, Vec<u8>, Vec<Vec<Vec<u8>>>)> = Vec::new();
@@ -93,73 +89,14 @@ pub fn input(data : &[u8]) -> Result<()>{
         (k1.clone(),k2.clone(),m)
     }).collect();
     DB.import(export);
+    json_pack::migrate()?;
+    geo_store::init()?;
+    json_index::init()?;
+    json_schema::init()?;
+    json_search::init()?;
     Ok(())
 }
 
-impl GeoTree {
-    pub fn new() -> Self {
-        GeoTree {
-            rtree: RTree::new(),
-            hash: HashSet::new(),
-        }
-    }
-
-    pub fn with_items(items: Vec<GeoPoint2D>) -> Self {
-        let mut btree = HashSet::new();
-        btree.extend(items.iter().map(|i| { i.to_owned() }));
-        GeoTree {
-            rtree: RTree::bulk_load(items),
-            hash: btree,
-        }
-    }
-    pub fn insert(&mut self, p: GeoPoint2D) {
-        self.hash.insert(p.clone());
-        self.rtree.insert(p);
-    }
-    pub fn delete(&mut self, tag: &str) -> bool {
-        let point = GeoPoint2D::new(tag.to_owned());
-        let saved_point = match self.hash.get(&point) {
-            None => {
-                return false;
-            }
-            Some(s) => {
-                s.to_owned()
-            }
-        };
-
-        let r = match self.rtree.remove(&saved_point) {
-            None => {
-                false
-            }
-            Some(_) => {
-                true
-            }
-        };
-        let l = self.hash.remove(&saved_point);
-        return r && l;
-    }
-    pub fn get(&self, tag: &str) -> Option<&GeoPoint2D> {
-        let point = GeoPoint2D::new(tag.to_owned());
-        self.hash.get(&point)
-    }
-    pub fn locate_at_point(&self, point: &[f64; 2]) -> Option<&GeoPoint2D> {
-        self.rtree.locate_at_point(&point)
-    }
-    pub fn merge(&mut self, other: &Self) {
-        other.rtree.iter().for_each(|point| {
-            self.rtree.insert(point.clone())
-        });
-        self.hash.extend(other.hash.iter().map(|v| v.clone()))
-    }
-}
-
-impl GeoTree {
-    pub fn iter(self: &Self) -> impl Iterator<Item=&GeoPoint2D>
-    {
-        self.hash.iter()
-    }
-}
-
 impl std::fmt::Display for Data {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", "ERR syntax error")
@@ -172,9 +109,15 @@ pub enum Data {
     String(String),
     Int(i64),
     Float(f64),
-    Json(Vec<u8>),
-    GeoTree(GeoTree),
+    /// Json text of documents written before they were stored as msgpack, only read to migrate
+    JsonText(Vec<u8>),
+    /// Whole tree layout of geo keys before members got their own records, only read to migrate
+    GeoTree(LegacyGeoTree),
     Null,
+    /// Marks a geo key, its members are stored by `geo_store`
+    GeoSet,
+    /// Json document encoded as msgpack by `json_pack`
+    Json(Vec<u8>),
 }
 
 impl FromStr for Data {
@@ -210,7 +153,7 @@ impl FromStr for Data {
 }
 
 impl Data {
-    fn from_vec(vec: &[u8]) -> Result<Self, ParseDataError> {
+    pub fn from_vec(vec: &[u8]) -> Result<Self, ParseDataError> {
         return match bincode::deserialize::<Data>(vec) {
             Ok(d) => {
                 Ok(d)
@@ -237,18 +180,10 @@ impl RespResponse for Data {
                 print_string(&d.to_string())
             }
             Data::Json(d) => {
-                let json_string = serde_json::to_string_pretty(d).unwrap_or("nil".to_string());
+                let json_string = serde_json::to_string_pretty(&json_pack::unpack(d)).unwrap_or("nil".to_string());
                 print_string(&json_string)
             }
-            Data::GeoTree(d) => {
-                let mut points_array: Vec<Vec<String>> = vec![];
-                d.iter().for_each(|t| {
-                    let point_array: Vec<String> = vec![t.x_cord().to_string(), t.y_cord().to_string()];
-                    points_array.push(point_array)
-                });
-                print_nested_arr(points_array)
-            }
-            Data::Null => {
+            Data::Null | Data::JsonText(_) | Data::GeoTree(_) | Data::GeoSet => {
                 print_str("nil")
             }
         }
@@ -306,16 +241,17 @@ pub async fn init() {
                 Data::Float(n)
             }
 
-            (Data::Json(mut o), Data::Json(n)) => {
-                let mut a: Value = serde_json::from_slice(&o).unwrap();
-                let b: Value = serde_json::from_slice(&n).unwrap_or(Value::Null);
-                util::merge(&mut a, &b);
-                Data::Json(serde_json::to_vec(&a).unwrap())
+            (Data::Json(o), Data::Json(n)) => {
+                let mut a = json_pack::unpack(&o);
+                json::merge_patch(&mut a, &json_pack::unpack(&n));
+                Data::Json(json_pack::pack(&a))
             }
 
-            (Data::GeoTree(mut o), Data::GeoTree(n)) => {
-                o.merge(&n);
-                Data::GeoTree(o)
+            // a merge patch applied to a missing key still drops its null members
+            (Data::Null, Data::Json(n)) => {
+                let mut a = Value::Null;
+                json::merge_patch(&mut a, &json_pack::unpack(&n));
+                Data::Json(json_pack::pack(&a))
             }
 
             (Data::Null, n) => {
@@ -329,6 +265,27 @@ pub async fn init() {
         Some(v)
     }
     DB.set_merge_operator(data_merge);
+
+    if let Err(e) = json_pack::migrate() {
+        error!("Failed to migrate json keys: {}", e);
+    }
+
+    if let Err(e) = geo_store::init() {
+        error!("Failed to load geo index: {}", e);
+    }
+    if let Err(e) = json_index::init() {
+        error!("Failed to load json indexes: {}", e);
+    }
+    if let Err(e) = json_schema::init() {
+        error!("Failed to load json schemas: {}", e);
+    }
+    if let Err(e) = json_search::init() {
+        error!("Failed to load full-text indexes: {}", e);
+    }
+}
+
+pub fn get_db() -> Arc<Db> {
+    DB.clone()
 }
 
 fn _get(key: &str) -> Result<Data, String> {
@@ -397,7 +354,9 @@ pub fn select() {}
 pub fn set(context: Arc<RwLock<Context>>, cmd: &SetCmd) -> String {
     let v = bincode::serialize(&cmd.arg_value).unwrap();
     let k = cmd.arg_key.as_bytes();
+    _drop_geo_members(&cmd.arg_key);
     DB.insert(k, v);
+    _track_json(&cmd.arg_key);
     print_ok()
 }
 
@@ -405,7 +364,10 @@ pub fn get_set(context: Arc<RwLock<Context>>, cmd: &GetSetCmd) -> String {
     let k = cmd.arg_key.as_bytes();
     let v = bincode::serialize(&cmd.arg_value).unwrap();
 
-    match DB.insert(k, v) {
+    _drop_geo_members(&cmd.arg_key);
+    let inserted = DB.insert(k, v);
+    _track_json(&cmd.arg_key);
+    match inserted {
         Ok(r) => {
             let old_raw_data = match r {
                 None => {
@@ -431,6 +393,9 @@ pub fn random_key(context: Arc<RwLock<Context>>, cmd: &RandomKeyCmd) -> String {
 
 pub fn get(context: Arc<RwLock<Context>>, cmd: &GetCmd) -> String {
     return match _get(&cmd.arg_key){
+        Ok(Data::GeoSet) => {
+            _with_geo_tree(&cmd.arg_key, _print_points)
+        }
         Ok(data) => {
             data.to_resp()
         }
@@ -472,12 +437,14 @@ pub fn db_size(context: Arc<RwLock<Context>>, _cmd: &DBSizeCmd) -> String {
 pub fn del(context: Arc<RwLock<Context>>, cmd: &DelCmd) -> String {
     let k = cmd.arg_key.as_bytes();
     let mut count: i64 = 0;
+    _drop_geo_members(&cmd.arg_key);
     match DB.remove(k) {
         Ok(_) => {
             count += 1
         }
         Err(_) => {}
     }
+    _track_json(&cmd.arg_key);
     print_integer(&count)
 }
 
@@ -534,7 +501,7 @@ pub fn incr_by(context: Arc<RwLock<Context>>, cmd: &IncrByCmd) -> String {
             Data::Json(_) => {
                 None
             }
-            Data::GeoTree(_) => {
+            Data::JsonText(_) | Data::GeoTree(_) | Data::GeoSet => {
                 None
             }
             Data::Null => {
@@ -562,17 +529,7 @@ pub fn incr_by(context: Arc<RwLock<Context>>, cmd: &IncrByCmd) -> String {
 }
 
 pub fn keys(context: Arc<RwLock<Context>>, cmd: &KeysCmd) -> String {
-    let mut prefix = String::new();
-    for c in cmd.pattern.chars() {
-        match c {
-            '*' | '?' | '[' => {
-                break;
-            }
-            c => {
-                prefix.push(c);
-            }
-        }
-    }
+    let prefix = util::glob_prefix(&cmd.pattern);
     let pattern_marcher = match glob::Pattern::new(&cmd.pattern) {
         Ok(p) => p,
         Err(_e) => {
@@ -597,412 +554,644 @@ pub fn keys(context: Arc<RwLock<Context>>, cmd: &KeysCmd) -> String {
 }
 
 pub fn geo_add(context: Arc<RwLock<Context>>, cmd: &GeoAddCmd) -> String {
-    let k = cmd.arg_key.as_bytes();
-
-    //db.fetch_and_update()
-
-    let mut geo_tree = GeoTree::new();
-
     let items_count = cmd.items.len() as i64;
+    let points: Vec<GeoPoint2D> = cmd.items.iter().map(|(lat, lng, tag)| {
+        let mut point = GeoPoint2D::with_cord(tag.to_owned(), lat.to_owned(), lng.to_owned());
+        point.expires_at = cmd.arg_expires_at;
+        point
+    }).collect();
 
-    cmd.items.iter().for_each(|(lat, lng, tag)| {
-        let tag = tag.to_owned();
-        let lat = lat.to_owned();
-        let lng = lng.to_owned();
-        let point = GeoPoint2D::with_cord(tag, lat, lng);
-        geo_tree.insert(point);
-    });
+    if cmd.arg_track {
+        if let Err(e) = geo_store::record_track(&cmd.arg_key, &points, cmd.arg_timestamp) {
+            return print_from_error(&e);
+        }
+    }
 
-    let v = bincode::serialize(&Data::GeoTree(geo_tree)).unwrap();
-    DB.merge(k, v);
-    print_integer(&items_count)
+    match geo_store::insert(&cmd.arg_key, points) {
+        Ok(_) => print_integer(&items_count),
+        Err(e) => print_from_error(&e)
+    }
 }
 
-pub fn geo_hash(context: Arc<RwLock<Context>>, cmd: &GeoHashCmd) -> String {
-    let data = match _get(&cmd.arg_key) {
-        Ok(d) => {
-            d
+pub fn geo_add_shape(context: Arc<RwLock<Context>>, cmd: &GeoAddShapeCmd) -> String {
+    let geometry = match serde_json::from_str::<Value>(&cmd.arg_value) {
+        Ok(v) => Geometry::from_geo_json(&v),
+        Err(_) => Err("ERR invalid json".to_owned())
+    };
+    let r = match geometry {
+        Ok(Geometry::Point(p)) => {
+            geo_store::insert(&cmd.arg_key, vec![GeoPoint2D::with_cord(cmd.arg_member.to_owned(), p[0], p[1])])
+        }
+        Ok(geometry) => {
+            geo_store::insert_shapes(&cmd.arg_key, vec![GeoShape { tag: cmd.arg_member.to_owned(), data: None, geometry }])
         }
         Err(e) => {
-            return e;
+            return print_err(&e);
         }
     };
+    match r {
+        Ok(_) => print_integer(&1),
+        Err(e) => print_from_error(&e)
+    }
+}
 
-    let geo_tree = match data {
-        Data::GeoTree(t) => {
-            t
-        }
-        _ => {
-            return print_err("ERR");
+pub fn geo_hash(context: Arc<RwLock<Context>>, cmd: &GeoHashCmd) -> String {
+    _with_geo_tree(&cmd.arg_key, |geo_tree| {
+        let mut geo_hashes: Vec<&String> = vec![];
+        let empty_string = String::new();
+
+        for tag in &cmd.items {
+            match geo_tree.get(tag, util::now_ms()) {
+                Some(point) => {
+                    geo_hashes.push(point.hash());
+                }
+                None => {
+                    geo_hashes.push(&empty_string)
+                }
+            };
         }
-    };
 
-    let mut geo_hashes: Vec<&String> = vec![];
-    let empty_string = String::new();
+        print_string_arr(geo_hashes)
+    })
+}
 
-    for tag in &cmd.items {
-        match geo_tree.get(tag) {
-            Some(point) => {
-                geo_hashes.push(point.hash());
-            }
+pub fn geo_dist(context: Arc<RwLock<Context>>, cmd: &GeoDistCmd) -> String {
+    _with_geo_tree(&cmd.arg_key, |geo_tree| {
+        let member_1 = match geo_tree.get(&cmd.arg_mem_1, util::now_ms()) {
             None => {
-                geo_hashes.push(&empty_string)
+                return print_err("ERR member 1 not found");
+            }
+            Some(p) => {
+                p
             }
         };
-    }
-
-    print_string_arr(geo_hashes)
-}
-
-pub fn geo_dist(context: Arc<RwLock<Context>>, cmd: &GeoDistCmd) -> String {
-    let data = match _get(&cmd.arg_key) {
-        Ok(d) => {
-            d
-        }
-        Err(e) => {
-            return e;
-        }
-    };
-
-    let geo_tree = match data {
-        Data::GeoTree(t) => {
-            t
-        }
-        _ => {
-            return print_err("ERR");
-        }
-    };
-
-    let member_1 = match geo_tree.get(&cmd.arg_mem_1) {
-        None => {
-            return print_err("ERR member 1 not found");
-        }
-        Some(p) => {
-            p
-        }
-    };
 
-    let member_2 = match geo_tree.get(&cmd.arg_mem_2) {
-        None => {
-            return print_err("ERR member 2 not found");
-        }
-        Some(p) => {
-            p
-        }
-    };
+        let member_2 = match geo_tree.get(&cmd.arg_mem_2, util::now_ms()) {
+            None => {
+                return print_err("ERR member 2 not found");
+            }
+            Some(p) => {
+                p
+            }
+        };
 
-    let distance = util::haversine_distance(Location { latitude: member_1.x_cord(), longitude: member_1.y_cord() },
-                                            Location { latitude: member_2.x_cord(), longitude: member_2.y_cord() },
-                                            cmd.arg_unit.clone());
-    print_string(&distance.to_string())
+        let start = Location { latitude: member_1.x_cord(), longitude: member_1.y_cord() };
+        let end = Location { latitude: member_2.x_cord(), longitude: member_2.y_cord() };
+        let distance = if cmd.arg_wgs84 {
+            // nearly antipodal points may not converge on the ellipsoid, the sphere still answers
+            util::vincenty_distance(start, end).unwrap_or_else(|| util::haversine_distance(start, end, Units::Meters))
+        } else {
+            util::haversine_distance(start, end, Units::Meters)
+        };
+        print_string(&unit_conv::from_m(distance, cmd.arg_unit).to_string())
+    })
 }
 
 pub fn geo_radius(context: Arc<RwLock<Context>>, cmd: &GeoRadiusCmd) -> String {
-    let data = match _get(&cmd.arg_key) {
-        Ok(d) => {
-            d
-        }
-        Err(e) => {
-            return e;
-        }
-    };
-
-    let geo_tree = match data {
-        Data::GeoTree(t) => {
-            t
-        }
-        _ => {
-            return print_err("ERR Invalid key for data type");
-        }
-    };
-
     let radius = match cmd.arg_unit {
         Units::Kilometers => unit_conv::km_m(cmd.arg_radius),
         Units::Miles => unit_conv::mi_m(cmd.arg_radius),
         Units::Meters => cmd.arg_radius,
     };
 
-    let circle = Circle {
-        origin: [cmd.arg_lat, cmd.arg_lng],
-        radius,
-    };
-
-    let nearest_in_radius_array = &mut geo_tree.rtree.nearest_neighbor_iter_with_distance_2(&circle.origin);
+    let origin = [cmd.arg_lat, cmd.arg_lng];
 
-    let mut item_string_arr: Vec<Vec<String>> = vec![];
-
-    while let Some((point, dist)) = nearest_in_radius_array.next() {
-        if dist <= circle.radius {
-            let dist = match cmd.arg_unit {
-                Units::Kilometers => unit_conv::m_km(dist),
-                Units::Miles => unit_conv::m_mi(dist),
-                Units::Meters => dist,
-            };
+    _with_geo_tree(&cmd.arg_key, |geo_tree| {
+        let mut hits: Vec<(&GeoPoint2D, f64)> = vec![];
 
-            let string_arr: Vec<String> = vec![point.tag.to_owned(), point.hash().to_owned(), dist.to_string()];
-            &item_string_arr.push(string_arr);
+        for (point, dist) in geo_tree.locate_within(&origin, radius) {
+            if let Some(filter) = &cmd.arg_filter {
+                if !filter.matches(point) {
+                    continue;
+                }
+            }
+            hits.push((point, unit_conv::from_m(dist, cmd.arg_unit)));
         }
-    }
-    match cmd.arg_order {
-        ArgOrder::UNSPECIFIED => (),
-        ArgOrder::ASC => item_string_arr.sort_by(|a, b| a[2].cmp(&b[2])),
-        ArgOrder::DESC => item_string_arr.sort_by(|a, b| b[2].cmp(&a[2]))
-    };
+        match cmd.arg_order {
+            ArgOrder::UNSPECIFIED => (),
+            ArgOrder::ASC => hits.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal)),
+            ArgOrder::DESC => hits.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal))
+        };
 
-    print_nested_arr(item_string_arr)
+        _print_geo_hits(&hits, &cmd.arg_geo_json)
+    })
 }
 
 pub fn geo_radius_by_member(context: Arc<RwLock<Context>>, cmd: &GeoRadiusByMemberCmd) -> String {
-    let data = match _get(&cmd.arg_key) {
-        Ok(d) => {
-            d
-        }
-        Err(e) => {
-            return e;
-        }
-    };
-
-    let geo_tree = match data {
-        Data::GeoTree(t) => {
-            t
-        }
-        _ => {
-            return print_err("ERR Invalid key for data type");
-        }
-    };
-
-    let member = match geo_tree.get(&cmd.member) {
-        Some(t) => {
-            t
+    let member = geo_store::read(&cmd.arg_key, |geo_tree| {
+        geo_tree.get(&cmd.member, util::now_ms()).map(|t| (t.x_cord(), t.y_cord()))
+    });
+    let (lat, lng) = match member {
+        Some(Some(m)) => m,
+        Some(None) => {
+            return print_err("ERR member 1 not found");
         }
         None => {
-            return print_err("ERR member 1 not found");
+            return _geo_key_error(&cmd.arg_key);
         }
     };
 
     let cmd = GeoRadiusCmd {
         arg_key: cmd.arg_key.to_owned(),
-        arg_lng: member.y_cord(),
-        arg_lat: member.x_cord(),
+        arg_lng: lng,
+        arg_lat: lat,
         arg_radius: cmd.arg_radius,
         arg_unit: cmd.arg_unit,
         arg_order: cmd.arg_order,
+        arg_filter: cmd.arg_filter.clone(),
+        arg_geo_json: cmd.arg_geo_json,
     };
 
     geo_radius(context.clone(), &cmd)
 }
 
+pub fn geo_nearest(context: Arc<RwLock<Context>>, cmd: &GeoNearestCmd) -> String {
+    let max_dist = cmd.arg_max_dist.map(|d| unit_conv::to_m(d, cmd.arg_unit));
+    let origin = [cmd.arg_lat, cmd.arg_lng];
 
-pub fn geo_pos(context: Arc<RwLock<Context>>, cmd: &GeoPosCmd) -> String {
-    let data = match _get(&cmd.arg_key) {
-        Ok(d) => {
-            d
-        }
+    _with_geo_tree(&cmd.arg_key, |geo_tree| {
+        let hits: Vec<(&GeoPoint2D, f64)> = geo_tree.nearest(&origin)
+            .take_while(|(_, dist)| max_dist.map_or(true, |m| *dist <= m))
+            .filter(|(point, _)| cmd.arg_filter.as_ref().map_or(true, |f| f.matches(point)))
+            .take(cmd.arg_count)
+            .map(|(point, dist)| (point, unit_conv::from_m(dist, cmd.arg_unit)))
+            .collect();
+
+        _print_geo_hits(&hits, &cmd.arg_geo_json)
+    })
+}
+
+pub fn geo_box(context: Arc<RwLock<Context>>, cmd: &GeoBoxCmd) -> String {
+    let min = [cmd.arg_min_lat.min(cmd.arg_max_lat), cmd.arg_min_lng.min(cmd.arg_max_lng)];
+    let max = [cmd.arg_min_lat.max(cmd.arg_max_lat), cmd.arg_min_lng.max(cmd.arg_max_lng)];
+
+    _with_geo_tree(&cmd.arg_key, |geo_tree| {
+        let points: Vec<&GeoPoint2D> = geo_tree.locate_in_box(min, max)
+            .filter(|point| cmd.arg_filter.as_ref().map_or(true, |f| f.matches(point)))
+            .collect();
+
+        _print_geo_points(&points, &cmd.arg_geo_json)
+    })
+}
+
+pub fn geo_cluster(context: Arc<RwLock<Context>>, cmd: &GeoClusterCmd) -> String {
+    let min = [cmd.arg_min_lat.min(cmd.arg_max_lat), cmd.arg_min_lng.min(cmd.arg_max_lng)];
+    let max = [cmd.arg_min_lat.max(cmd.arg_max_lat), cmd.arg_min_lng.max(cmd.arg_max_lng)];
+    let precision = geo::zoom_precision(cmd.arg_zoom);
+
+    let clusters = _with_geo_tree_result(&cmd.arg_key, |geo_tree| {
+        let points = geo_tree.locate_in_box(min, max)
+            .filter(|point| cmd.arg_filter.as_ref().map_or(true, |f| f.matches(point)));
+        geo::cluster_points(points, precision, cmd.arg_bounds)
+    });
+    let clusters = match clusters {
+        Ok(c) => c,
         Err(e) => {
             return e;
         }
     };
 
-    let geo_tree = match data {
-        Data::GeoTree(t) => {
-            t
-        }
-        _ => {
-            return print_err("ERR Invalid key for data type");
+    if let Some(options) = &cmd.arg_geo_json {
+        return print_string(&build_geo_json(&clusters, options).to_string());
+    }
+
+    // each row is cell, count, centroid lng, centroid lat and with BOUNDS min lng, min lat, max lng, max lat
+    let rows: Vec<Vec<String>> = clusters.iter().map(|c| {
+        let mut row = vec![c.cell.to_owned(), c.count.to_string(), c.lng.to_string(), c.lat.to_string()];
+        if cmd.arg_bounds {
+            row.extend(c.bounds.iter().map(|b| b.to_string()));
         }
-    };
+        row
+    }).collect();
+    print_nested_arr(rows)
+}
 
-    let mut points_array: Vec<Vec<String>> = vec![];
+pub fn geo_polygon(context: Arc<RwLock<Context>>, cmd: &GeoPolygonCmd) -> String {
+    let polygon = Polygon {
+        vertices: cmd.arg_vertices.iter().map(|(lng, lat)| [*lat, *lng]).collect()
+    };
 
-    for s in &cmd.items {
-        match geo_tree.get(s) {
-            Some(t) => {
-                let point_array: Vec<String> = vec![t.x_cord().to_string(), t.y_cord().to_string()];
-                points_array.push(point_array)
-            }
-            None => {
-                points_array.push(vec![])
-            }
-        };
-    }
+    _with_geo_tree(&cmd.arg_key, |geo_tree| {
+        let points: Vec<&GeoPoint2D> = geo_tree.locate_in_polygon(&polygon)
+            .filter(|point| cmd.arg_filter.as_ref().map_or(true, |f| f.matches(point)))
+            .collect();
 
-    print_nested_arr(points_array)
+        _print_geo_points(&points, &cmd.arg_geo_json)
+    })
 }
 
-pub fn geo_del(context: Arc<RwLock<Context>>, cmd: &GeoDelCmd) -> String {
-    let data = match _get(&cmd.arg_key) {
-        Ok(d) => {
-            d
-        }
+pub fn geo_query(context: Arc<RwLock<Context>>, cmd: &GeoQueryCmd) -> String {
+    let query = match &cmd.arg_target {
+        GeoQueryTarget::Geometry(value) => match serde_json::from_str::<Value>(value) {
+            Ok(v) => Geometry::from_geo_json(&v),
+            Err(_) => Err("ERR invalid json".to_owned())
+        },
+        GeoQueryTarget::Member(_) => Ok(Geometry::Point([0.0, 0.0]))
+    };
+    let query = match query {
+        Ok(q) => q,
         Err(e) => {
-            return e;
+            return print_err(&e);
         }
     };
 
-    return match data {
-        Data::GeoTree(t) => {
-            let k = cmd.arg_key.as_bytes();
-            let mut rem_keys_count = 0;
-            match DB.remove(k) {
-                Ok(k) => {
-                    rem_keys_count += 1;
+    _with_geo_tree(&cmd.arg_key, |geo_tree| {
+        let (query, exclude) = match &cmd.arg_target {
+            GeoQueryTarget::Member(tag) => match geo_tree.get_member(tag, util::now_ms()) {
+                Some(GeoMember::Point(p)) => (Geometry::Point([p.x_cord(), p.y_cord()]), Some(tag)),
+                Some(GeoMember::Shape(s)) => (s.geometry.to_owned(), Some(tag)),
+                None => {
+                    return print_err("ERR member not found");
                 }
-                Err(_) => {}
-            };
+            },
+            GeoQueryTarget::Geometry(_) => (query, None)
+        };
 
-            print_integer(&rem_keys_count)
-        }
-        _ => {
-            print_err("ERR Invalid key for data type")
+        let members: Vec<GeoMember> = geo_tree.query(cmd.arg_op, &query).into_iter()
+            .filter(|m| exclude.map_or(true, |tag| m.tag() != tag))
+            .filter(|m| cmd.arg_filter.as_ref().map_or(true, |f| f.matches_data(m.data())))
+            .collect();
+
+        if let Some(options) = &cmd.arg_geo_json {
+            return print_string(&build_geo_json(&members, options).to_string());
         }
-    };
+        let rows: Vec<Vec<String>> = members.iter()
+            .map(|m| vec![m.tag().to_owned(), m.type_name().to_owned()])
+            .collect();
+        print_nested_arr(rows)
+    })
 }
 
-pub fn geo_remove(context: Arc<RwLock<Context>>, cmd: &GeoRemoveCmd) -> String {
+pub fn geo_pos(context: Arc<RwLock<Context>>, cmd: &GeoPosCmd) -> String {
+    _with_geo_tree(&cmd.arg_key, |geo_tree| {
+        let mut points_array: Vec<Vec<String>> = vec![];
 
-    let k = cmd.arg_key.as_bytes();
-    let mut rm_count: i64 = 0;
-    DB.update_and_fetch(k, |old| -> Option<Vec<u8>> {
-        let data = match old {
-            None => {
-                Data::Null
-            }
-            Some(bytes) => {
-                match Data::from_vec(bytes) {
-                    Ok(d) => {
-                        d
-                    }
-                    Err(_) => {
-                        return None;
-                    }
+        for s in &cmd.items {
+            match geo_tree.get(s, util::now_ms()) {
+                Some(t) => {
+                    let point_array: Vec<String> = vec![t.y_cord().to_string(), t.x_cord().to_string()];
+                    points_array.push(point_array)
                 }
-            }
-        };
-
-        match data {
-            Data::GeoTree(mut t) => {
-                for s in &cmd.items {
-                    if t.delete(s) {
-                        rm_count += 1;
-                    }
+                None => {
+                    points_array.push(vec![])
                 }
-
-                let v = bincode::serialize(&Data::GeoTree(t)).unwrap();
-                Some(v)
-            }
-            _ => {
-                None
-            }
+            };
         }
-    });
 
-    print_integer(&rm_count)
+        print_nested_arr(points_array)
+    })
 }
 
-pub fn geo_json(context: Arc<RwLock<Context>>, cmd: &GeoJsonCmd) -> String {
-    let data = match _get(&cmd.arg_key) {
-        Ok(d) => {
-            d
+pub fn geo_del(context: Arc<RwLock<Context>>, cmd: &GeoDelCmd) -> String {
+    match _get(&cmd.arg_key) {
+        Ok(Data::GeoSet) => {}
+        Ok(_) => {
+            return print_err("ERR Invalid key for data type");
         }
         Err(e) => {
             return e;
         }
     };
 
-    let mut geo_arr: Vec<GeoPoint2D> = vec![];
-
-    match data {
-        Data::GeoTree(t) => {
-            for s in &cmd.items {
-                match t.get(s) {
-                    Some(t) => {
-                        geo_arr.push(t.to_owned())
-                    }
-                    None => {}
-                };
-            }
-        }
-        _ => {
-            return print_err("ERR Invalid key for data type");
-        }
+    match geo_store::drop_key(&cmd.arg_key) {
+        Ok(_) => print_integer(&1),
+        Err(e) => print_from_error(&e)
     }
-    print_string(&build_geo_json(&geo_arr).to_string())
 }
 
-// JSET, JGET, JDEL, JPATH, JMERGE
-pub fn jset_raw(context: Arc<RwLock<Context>>, cmd: &JSetRawCmd) -> String {
-    let k = cmd.arg_key.as_bytes();
+pub fn geo_track(context: Arc<RwLock<Context>>, cmd: &GeoTrackCmd) -> String {
+    if geo_store::read(&cmd.arg_key, |_| ()).is_none() && !geo_store::has_track(&cmd.arg_key) {
+        return _geo_key_error(&cmd.arg_key);
+    }
 
-    let json_value: Value = match serde_json::from_str(&cmd.arg_value) {
-        Ok(t) => t,
-        Err(_) => { return print_err("ERR invalid json"); }
+    let points = match geo_store::track(&cmd.arg_key, &cmd.arg_member, cmd.arg_from, cmd.arg_to) {
+        Ok(p) => p,
+        Err(e) => {
+            return print_from_error(&e);
+        }
+    };
+    let trajectory = Trajectory {
+        tag: cmd.arg_member.to_owned(),
+        points,
     };
 
+    if let Some(options) = &cmd.arg_geo_json {
+        return print_string(&build_geo_json(&vec![&trajectory], options).to_string());
+    }
 
-    let json_b = serde_json::to_vec(&json_value).unwrap();
-
-    let v = bincode::serialize(&Data::Json(json_b)).unwrap();
-    DB.insert(k, v);
-    print_ok()
+    // each row is timestamp, lng, lat, then the speed in m/s and heading in degrees since the previous row
+    let mut rows: Vec<Vec<String>> = vec![];
+    let segments = trajectory.segments();
+    for (i, point) in trajectory.points.iter().enumerate() {
+        let (speed, heading) = match i.checked_sub(1).and_then(|s| segments.get(s)) {
+            Some(s) => (s.speed, s.heading),
+            None => (0.0, 0.0)
+        };
+        rows.push(vec![point.timestamp.to_string(), point.lng.to_string(), point.lat.to_string(),
+                       speed.to_string(), heading.to_string()]);
+    }
+    print_nested_arr(rows)
 }
 
-pub fn jset(context: Arc<RwLock<Context>>, cmd: &JSetCmd) -> String {
-    let k = cmd.arg_key.as_bytes();
-    DB.fetch_and_update(k, |old| -> Option<Vec<u8>> {
-        let data = match old {
-            None => {
-                let json_b = serde_json::to_vec(&Value::Null).unwrap();
-                Data::Json(json_b)
-            }
-            Some(bytes) => {
-                match Data::from_vec(bytes) {
-                    Ok(d) => {
-                        d
-                    }
-                    Err(_) => {
-                        return None;
-                    }
-                }
+pub fn geo_join(context: Arc<RwLock<Context>>, cmd: &GeoJoinCmd) -> String {
+    // a stored join is a write, so it must see the same members on every replica
+    let now = storage::entry_time();
+    let pairs = geo_store::read_pair(&cmd.arg_left_key, &cmd.arg_right_key, |left, right| {
+        let mut pairs = match cmd.arg_mode {
+            GeoJoinMode::Within(radius) => left.join_within(right, unit_conv::to_m(radius, cmd.arg_unit), now),
+            GeoJoinMode::Nearest(k, max_dist) => {
+                left.join_nearest(right, k, max_dist.map(|d| unit_conv::to_m(d, cmd.arg_unit)), now)
             }
         };
+        pairs.sort_by(|a, b| a.0.tag.cmp(&b.0.tag).then(a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal)));
+        pairs.iter()
+            .map(|(l, r, dist)| (l.tag.to_owned(), r.tag.to_owned(), unit_conv::from_m(*dist, cmd.arg_unit)))
+            .collect::<Vec<(String, String, f64)>>()
+    });
+    let pairs = match pairs {
+        Some(p) => p,
+        None => {
+            let missing = if geo_store::read(&cmd.arg_left_key, |_| ()).is_none() { &cmd.arg_left_key } else { &cmd.arg_right_key };
+            return _geo_key_error(missing);
+        }
+    };
 
-        match data {
-            Data::Json(mut json_b) => {
-                let mut json: Value = serde_json::from_slice(&json_b).unwrap_or(Value::Null);
-                let mut ers: Vec<json_dotpath::Error> = vec![];
-                for (path, value) in &cmd.arg_set_items {
-                    match json.dot_set(path, value.to_owned()) {
-                        Ok(_t) => {}
-                        Err(e) => {
-                            ers.push(e)
-                        }
-                    };
-                }
-                if !ers.is_empty() {
-                    return None;
+    if let Some(dest) = &cmd.arg_store {
+        // stored as {"left member": [{"member": "right member", "distance": d}, ...]}
+        let mut joined = serde_json::Map::new();
+        for (left, right, dist) in &pairs {
+            let matches = joined.entry(left.to_owned()).or_insert_with(|| json!([]));
+            if let Value::Array(m) = matches {
+                m.push(json!({"member": right, "distance": dist}));
+            }
+        }
+        let joined = Value::Object(joined);
+        if let Err(e) = json_schema::validate_key(dest, &joined) {
+            return print_err(&e);
+        }
+        let v = bincode::serialize(&Data::Json(json_pack::pack(&joined))).unwrap();
+        _drop_geo_members(dest);
+        if DB.insert(dest.as_bytes(), v).is_err() {
+            return print_err("ERR");
+        }
+        _track_json(dest);
+        return print_integer(&(pairs.len() as i64));
+    }
+
+    let rows: Vec<Vec<String>> = pairs.into_iter()
+        .map(|(left, right, dist)| vec![left, right, dist.to_string()])
+        .collect();
+    print_nested_arr(rows)
+}
+
+pub fn geo_sweep(context: Arc<RwLock<Context>>, cmd: &GeoSweepCmd) -> String {
+    match geo_store::sweep(cmd.arg_timestamp) {
+        Ok(rm_count) => print_integer(&rm_count),
+        Err(e) => print_from_error(&e)
+    }
+}
+
+pub fn geo_remove(context: Arc<RwLock<Context>>, cmd: &GeoRemoveCmd) -> String {
+    match geo_store::remove(&cmd.arg_key, &cmd.items) {
+        Ok(rm_count) => print_integer(&rm_count),
+        Err(e) => print_from_error(&e)
+    }
+}
+
+pub fn geo_json(context: Arc<RwLock<Context>>, cmd: &GeoJsonCmd) -> String {
+    _with_geo_tree(&cmd.arg_key, |t| {
+        let mut geo_arr: Vec<GeoMember> = vec![];
+
+        if cmd.items.is_empty() {
+            geo_arr.extend(t.members());
+        }
+        for s in &cmd.items {
+            match t.get_member(s, util::now_ms()) {
+                Some(t) => {
+                    geo_arr.push(t)
                 }
-                let v = bincode::serialize(&Data::Json(serde_json::to_vec(&json).unwrap())).unwrap();
-                Some(v)
+                None => {}
+            };
+        }
+        print_string(&build_geo_json(&geo_arr, &cmd.arg_options).to_string())
+    })
+}
+
+pub fn geo_import(context: Arc<RwLock<Context>>, cmd: &GeoImportCmd) -> String {
+    let imported = match cmd.arg_format {
+        GeoImportFormat::GeoJson => {
+            match serde_json::from_str::<Value>(&cmd.arg_value) {
+                Ok(v) => geo::points_from_geo_json(&v)
+                    .and_then(|points| Ok((points, geo::shapes_from_geo_json(&v)?))),
+                Err(_) => Err("ERR invalid json".to_owned())
             }
-            _ => {
-                None
+        }
+        GeoImportFormat::Csv => geo::points_from_csv(&cmd.arg_value).map(|points| (points, vec![]))
+    };
+    let (points, shapes) = match imported {
+        Ok(p) => p,
+        Err(e) => {
+            return print_err(&e);
+        }
+    };
+    let items_count = (points.len() + shapes.len()) as i64;
+
+    match geo_store::bulk_insert(&cmd.arg_key, points, shapes) {
+        Ok(_) => print_integer(&items_count),
+        Err(e) => print_from_error(&e)
+    }
+}
+
+pub fn geo_set_attr(context: Arc<RwLock<Context>>, cmd: &GeoSetAttrCmd) -> String {
+    let json: Value = match serde_json::from_str(&cmd.arg_value) {
+        Ok(t) => t,
+        Err(_) => { return print_err("ERR invalid json"); }
+    };
+    _update_geo_attr(&cmd.arg_key, &cmd.arg_member, |_| json)
+}
+
+pub fn geo_merge_attr(context: Arc<RwLock<Context>>, cmd: &GeoMergeAttrCmd) -> String {
+    let json: Value = match serde_json::from_str(&cmd.arg_value) {
+        Ok(t) => t,
+        Err(_) => { return print_err("ERR invalid json"); }
+    };
+    _update_geo_attr(&cmd.arg_key, &cmd.arg_member, |old| {
+        let mut data = old.unwrap_or(Value::Null);
+        util::merge(&mut data, &json);
+        data
+    })
+}
+
+pub fn geo_get_attr(context: Arc<RwLock<Context>>, cmd: &GeoGetAttrCmd) -> String {
+    _with_geo_tree(&cmd.arg_key, |geo_tree| {
+        let data = match geo_tree.get_member(&cmd.arg_member, util::now_ms()) {
+            None => {
+                return print_err("ERR member not found");
+            }
+            Some(m) => {
+                m.data().to_owned().unwrap_or(Value::Null)
             }
+        };
+
+        if let Some(t) = &cmd.arg_dot_path {
+            let dot_path_value = data.dot_get::<Value>(t).unwrap_or(Some(Value::Null)).unwrap_or(Value::Null);
+            return print_string(&dot_path_value.to_string());
         }
+        print_string(&data.to_string())
+    })
+}
+
+fn _print_geo_hits(hits: &Vec<(&GeoPoint2D, f64)>, geo_json: &Option<GeoJsonOptions>) -> String {
+    if let Some(options) = geo_json {
+        return print_string(&build_geo_json(hits, options).to_string());
+    }
+    let item_string_arr: Vec<Vec<String>> = hits.iter()
+        .map(|(point, dist)| vec![point.tag.to_owned(), point.hash().to_owned(), dist.to_string()])
+        .collect();
+    print_nested_arr(item_string_arr)
+}
+
+fn _print_geo_points(points: &Vec<&GeoPoint2D>, geo_json: &Option<GeoJsonOptions>) -> String {
+    if let Some(options) = geo_json {
+        return print_string(&build_geo_json(points, options).to_string());
+    }
+    let item_string_arr: Vec<Vec<String>> = points.iter()
+        .map(|point| vec![point.tag.to_owned(), point.hash().to_owned()])
+        .collect();
+    print_nested_arr(item_string_arr)
+}
+
+fn _print_points(geo_tree: &GeoTree) -> String {
+    let mut points_array: Vec<Vec<String>> = vec![];
+    geo_tree.iter().for_each(|t| {
+        let point_array: Vec<String> = vec![t.y_cord().to_string(), t.x_cord().to_string()];
+        points_array.push(point_array)
     });
-    print_ok()
+    print_nested_arr(points_array)
 }
 
-pub fn jmerge(context: Arc<RwLock<Context>>, cmd: &JMergeCmd) -> String {
+/// Runs `f` against the in-memory index of a geo key, or returns the error for a missing or non-geo key
+fn _with_geo_tree<F>(key: &str, f: F) -> String
+    where F: FnOnce(&GeoTree) -> String {
+    match geo_store::read(key, f) {
+        Some(r) => r,
+        None => _geo_key_error(key)
+    }
+}
+
+/// Like `_with_geo_tree` for callers that keep working with the result after the index is released
+fn _with_geo_tree_result<R, F>(key: &str, f: F) -> Result<R, String>
+    where F: FnOnce(&GeoTree) -> R {
+    match geo_store::read(key, f) {
+        Some(r) => Ok(r),
+        None => Err(_geo_key_error(key))
+    }
+}
+
+fn _geo_key_error(key: &str) -> String {
+    match _get(key) {
+        Ok(_) => print_err("ERR Invalid key for data type"),
+        Err(e) => e
+    }
+}
+
+fn _update_geo_attr<F>(key: &str, member: &str, f: F) -> String
+    where F: FnOnce(Option<Value>) -> Value {
+    match _get(key) {
+        Ok(Data::GeoSet) => {}
+        Ok(_) => {
+            return print_err("ERR Invalid key for data type");
+        }
+        Err(_) => {
+            return print_err("ERR member not found");
+        }
+    };
+
+    match geo_store::update_data(key, member, f) {
+        Ok(true) => print_ok(),
+        Ok(false) => print_err("ERR member not found"),
+        Err(e) => print_from_error(&e)
+    }
+}
+
+/// Drops the member records of a geo key that is being deleted or overwritten
+fn _drop_geo_members(key: &str) {
+    if let Err(e) = geo_store::drop_members(key) {
+        error!("Failed to drop geo members of {}: {}", key, e);
+    }
+}
+
+/// Updates the json indexes and the revision history of a key that was just written or deleted
+fn _track_json(key: &str) {
+    if let Err(e) = json_index::reindex(key) {
+        error!("Failed to update json indexes of {}: {}", key, e);
+    }
+    if let Err(e) = json_search::reindex(key) {
+        error!("Failed to update full-text indexes of {}: {}", key, e);
+    }
+    if let Err(e) = json_history::record(key, storage::entry_time()) {
+        error!("Failed to record a revision of {}: {}", key, e);
+    }
+}
+
+// JSET, JGET, JDEL, JPATH, JMERGE
+pub fn jset_raw(context: Arc<RwLock<Context>>, cmd: &JSetRawCmd) -> String {
     let k = cmd.arg_key.as_bytes();
-    let mut json: Value = match serde_json::from_str(&cmd.arg_value) {
+
+    let json_value: Value = match serde_json::from_str(&cmd.arg_value) {
         Ok(t) => t,
         Err(_) => { return print_err("ERR invalid json"); }
     };
-    let v = bincode::serialize(&Data::Json(serde_json::to_vec(&json).unwrap())).unwrap();
-    DB.merge(k, v);
+    if let Err(e) = json_schema::validate_key(&cmd.arg_key, &json_value) {
+        return print_err(&e);
+    }
+
+    let json_b = json_pack::pack(&json_value);
+
+    let v = bincode::serialize(&Data::Json(json_b)).unwrap();
+    _drop_geo_members(&cmd.arg_key);
+    DB.insert(k, v);
+    _track_json(&cmd.arg_key);
     print_ok()
 }
 
+pub fn jset(context: Arc<RwLock<Context>>, cmd: &JSetCmd) -> String {
+    match _update_json(&cmd.arg_key, |json| {
+        for (path, value) in &cmd.arg_set_items {
+            json.dot_set(path, value.to_owned()).map_err(|_| "ERR invalid path".to_owned())?;
+        }
+        Ok(())
+    }) {
+        Ok(_) => print_ok(),
+        Err(e) => e
+    }
+}
+
+pub fn jmerge(context: Arc<RwLock<Context>>, cmd: &JMergeCmd) -> String {
+    let patch: Value = match serde_json::from_str(&cmd.arg_value) {
+        Ok(t) => t,
+        Err(_) => { return print_err("ERR invalid json"); }
+    };
+    match _update_json(&cmd.arg_key, |json| {
+        json::merge_patch(json, &patch);
+        Ok(())
+    }) {
+        Ok(_) => print_ok(),
+        Err(e) => e
+    }
+}
+
+pub fn jpatch(context: Arc<RwLock<Context>>, cmd: &JPatchCmd) -> String {
+    let patch: Value = match serde_json::from_str(&cmd.arg_value) {
+        Ok(t) => t,
+        Err(_) => { return print_err("ERR invalid json"); }
+    };
+    match _update_json(&cmd.arg_key, |json| json::apply_patch(json, &patch)) {
+        Ok(_) => print_ok(),
+        Err(e) => e
+    }
+}
+
 pub fn jget(context: Arc<RwLock<Context>>, cmd: &JGetCmd) -> String {
     let data = match _get(&cmd.arg_key) {
         Ok(d) => {
@@ -1012,31 +1201,43 @@ pub fn jget(context: Arc<RwLock<Context>>, cmd: &JGetCmd) -> String {
             return e;
         }
     };
-    let value = match data {
-        Data::Json(json) => {
-            let json: Value = serde_json::from_slice(&json).unwrap_or(Value::Null);
-            json
+    let packed = match data {
+        Data::Json(b) => {
+            b
         }
         _ => {
             return print_err("ERR Invalid key for data type");
         }
     };
 
-    if let Some(t) = &cmd.arg_dot_path {
-        let dot_path_value = value.dot_get::<Value>(t).unwrap_or(Some(Value::Null)).unwrap();
-        return match dot_path_value {
-            Value::String(s) => {
-                print_string(&s)
-            }
-            Value::Number(n) => {
-                print_string(&n.to_string())
-            }
-            v => {
-                print_string(&v.to_string())
+    if cmd.arg_project {
+        let projection = json::project(&json_pack::unpack(&packed), &cmd.arg_paths);
+        return print_string(&json::format(&projection, &cmd.arg_format));
+    }
+    match cmd.arg_paths.as_slice() {
+        [] => {
+            print_string(&json::format(&json_pack::unpack(&packed), &cmd.arg_format))
+        }
+        [path] => {
+            match json_pack::read_path(&packed, path).unwrap_or(Value::Null) {
+                Value::String(s) => {
+                    print_string(&s)
+                }
+                Value::Number(n) => {
+                    print_string(&n.to_string())
+                }
+                v => {
+                    print_string(&json::format(&v, &cmd.arg_format))
+                }
             }
-        };
+        }
+        paths => {
+            let values: serde_json::Map<String, Value> = paths.iter()
+                .map(|p| (p.to_owned(), json_pack::read_path(&packed, p).unwrap_or(Value::Null)))
+                .collect();
+            print_string(&json::format(&Value::Object(values), &cmd.arg_format))
+        }
     }
-    print_string(&value.to_string())
 }
 
 pub fn jpath(context: Arc<RwLock<Context>>, cmd: &JPathCmd) -> String {
@@ -1050,8 +1251,7 @@ pub fn jpath(context: Arc<RwLock<Context>>, cmd: &JPathCmd) -> String {
     };
     let value = match data {
         Data::Json(b) => {
-            let json: Value = serde_json::from_slice(&b).unwrap_or(Value::Null);
-            json
+            json_pack::unpack(&b)
         }
         _ => {
             return print_err("ERR Invalid key for data type");
@@ -1074,6 +1274,365 @@ pub fn jpath(context: Arc<RwLock<Context>>, cmd: &JPathCmd) -> String {
     print_arr(j_strings)
 }
 
+pub fn jpath_set(context: Arc<RwLock<Context>>, cmd: &JPathSetCmd) -> String {
+    match _update_json(&cmd.arg_key, |json| json::path_set(json, &cmd.arg_selector, &cmd.arg_value)) {
+        Ok(modified) => print_integer(&(modified as i64)),
+        Err(e) => e
+    }
+}
+
+pub fn jpath_del(context: Arc<RwLock<Context>>, cmd: &JPathDelCmd) -> String {
+    match _update_json(&cmd.arg_key, |json| json::path_delete(json, &cmd.arg_selector)) {
+        Ok(modified) => print_integer(&(modified as i64)),
+        Err(e) => e
+    }
+}
+
+pub fn jpath_incr(context: Arc<RwLock<Context>>, cmd: &JPathIncrCmd) -> String {
+    match _update_json(&cmd.arg_key, |json| json::path_incr(json, &cmd.arg_selector, cmd.arg_increment_value)) {
+        Ok(modified) => print_integer(&(modified as i64)),
+        Err(e) => e
+    }
+}
+
+/// Runs a JSONPath selector over every json key matching a glob, keys without matches are left out
+pub fn jpath_scan(context: Arc<RwLock<Context>>, cmd: &JPathScanCmd) -> String {
+    let pattern_matcher = match glob::Pattern::new(&cmd.arg_pattern) {
+        Ok(p) => p,
+        Err(_e) => {
+            return print_err("ERR invalid pattern");
+        }
+    };
+    let mut selector = jsonpath::compile(&cmd.arg_selector);
+    if selector(&Value::Null).is_err() {
+        return print_err("ERR invalid JSONPath selector");
+    }
+
+    let mut rows: Vec<Vec<String>> = vec![];
+    for r in DB.scan_prefix(util::glob_prefix(&cmd.arg_pattern)) {
+        let (k, v) = match r {
+            Ok(kv) => kv,
+            Err(_) => continue
+        };
+        let key = String::from_utf8_lossy(&k).to_string();
+        if !pattern_matcher.matches(&key) {
+            continue;
+        }
+        let json = match Data::from_vec(&v) {
+            Ok(Data::Json(b)) => json_pack::unpack(&b),
+            _ => continue
+        };
+        let matches: Vec<Value> = match selector(&json) {
+            Ok(m) => m.into_iter().cloned().collect(),
+            Err(_) => continue
+        };
+        if !matches.is_empty() {
+            rows.push(vec![key, Value::Array(matches).to_string()]);
+        }
+    }
+    print_nested_arr(rows)
+}
+
+/// Reads the same dot path from several json keys, missing keys, paths and other types give nil
+pub fn jmget(context: Arc<RwLock<Context>>, cmd: &JMGetCmd) -> String {
+    let replies: Vec<String> = cmd.arg_keys.iter()
+        .map(|key| match _get_json_path(key, &cmd.arg_path) {
+            Ok(Some(v)) => print_string(&v.to_string()),
+            _ => print_str("nil")
+        })
+        .collect();
+    print_reply_arr(replies)
+}
+
+pub fn jarr_append(context: Arc<RwLock<Context>>, cmd: &JArrAppendCmd) -> String {
+    match _update_json(&cmd.arg_key, |json| json::array_append(json, &cmd.arg_path, &cmd.arg_values)) {
+        Ok(len) => print_integer(&(len as i64)),
+        Err(e) => e
+    }
+}
+
+pub fn jarr_insert(context: Arc<RwLock<Context>>, cmd: &JArrInsertCmd) -> String {
+    match _update_json(&cmd.arg_key, |json| json::array_insert(json, &cmd.arg_path, cmd.arg_index, &cmd.arg_values)) {
+        Ok(len) => print_integer(&(len as i64)),
+        Err(e) => e
+    }
+}
+
+pub fn jarr_pop(context: Arc<RwLock<Context>>, cmd: &JArrPopCmd) -> String {
+    match _update_json(&cmd.arg_key, |json| json::array_pop(json, &cmd.arg_path, cmd.arg_index)) {
+        Ok(Some(v)) => print_string(&v.to_string()),
+        Ok(None) => print_str("nil"),
+        Err(e) => e
+    }
+}
+
+pub fn jarr_len(context: Arc<RwLock<Context>>, cmd: &JArrLenCmd) -> String {
+    match _get_json_path(&cmd.arg_key, &cmd.arg_path).and_then(|v| json::array_len(&v.unwrap_or(Value::Null), ".").map_err(|e| print_err(&e))) {
+        Ok(len) => print_integer(&(len as i64)),
+        Err(e) => e
+    }
+}
+
+pub fn jarr_index(context: Arc<RwLock<Context>>, cmd: &JArrIndexCmd) -> String {
+    match _get_json_path(&cmd.arg_key, &cmd.arg_path).and_then(|v| {
+        json::array_index(&v.unwrap_or(Value::Null), ".", &cmd.arg_value, cmd.arg_start, cmd.arg_stop).map_err(|e| print_err(&e))
+    }) {
+        Ok(index) => print_integer(&index),
+        Err(e) => e
+    }
+}
+
+pub fn jarr_trim(context: Arc<RwLock<Context>>, cmd: &JArrTrimCmd) -> String {
+    match _update_json(&cmd.arg_key, |json| json::array_trim(json, &cmd.arg_path, cmd.arg_start, cmd.arg_stop)) {
+        Ok(len) => print_integer(&(len as i64)),
+        Err(e) => e
+    }
+}
+
+pub fn jtype(context: Arc<RwLock<Context>>, cmd: &JTypeCmd) -> String {
+    match _get_json_path(&cmd.arg_key, &cmd.arg_path) {
+        Ok(Some(v)) => print_str(json::type_name(&v)),
+        Ok(None) => print_str("nil"),
+        Err(e) => e
+    }
+}
+
+pub fn jobj_keys(context: Arc<RwLock<Context>>, cmd: &JObjKeysCmd) -> String {
+    match _get_json_path(&cmd.arg_key, &cmd.arg_path).and_then(|v| json::object_keys(&v.unwrap_or(Value::Null), ".").map_err(|e| print_err(&e))) {
+        Ok(keys) => print_arr(keys),
+        Err(e) => e
+    }
+}
+
+pub fn jobj_len(context: Arc<RwLock<Context>>, cmd: &JObjLenCmd) -> String {
+    match _get_json_path(&cmd.arg_key, &cmd.arg_path).and_then(|v| json::object_keys(&v.unwrap_or(Value::Null), ".").map_err(|e| print_err(&e))) {
+        Ok(keys) => print_integer(&(keys.len() as i64)),
+        Err(e) => e
+    }
+}
+
+pub fn jstr_len(context: Arc<RwLock<Context>>, cmd: &JStrLenCmd) -> String {
+    match _get_json_path(&cmd.arg_key, &cmd.arg_path).and_then(|v| json::string_len(&v.unwrap_or(Value::Null), ".").map_err(|e| print_err(&e))) {
+        Ok(len) => print_integer(&(len as i64)),
+        Err(e) => e
+    }
+}
+
+pub fn jstr_append(context: Arc<RwLock<Context>>, cmd: &JStrAppendCmd) -> String {
+    match _update_json(&cmd.arg_key, |json| json::string_append(json, &cmd.arg_path, &cmd.arg_value)) {
+        Ok(len) => print_integer(&(len as i64)),
+        Err(e) => e
+    }
+}
+
+pub fn jtoggle(context: Arc<RwLock<Context>>, cmd: &JToggleCmd) -> String {
+    match _update_json(&cmd.arg_key, |json| json::toggle(json, &cmd.arg_path)) {
+        Ok(b) => print_integer(&(b as i64)),
+        Err(e) => e
+    }
+}
+
+pub fn jindex(context: Arc<RwLock<Context>>, cmd: &JIndexCmd) -> String {
+    let def = IndexDef {
+        name: cmd.arg_name.to_owned(),
+        prefix: cmd.arg_prefix.to_owned(),
+        path: cmd.arg_path.to_owned(),
+    };
+    match json_index::create(def) {
+        Ok(true) => print_ok(),
+        Ok(false) => print_err("ERR index already exists"),
+        Err(e) => print_from_error(&e)
+    }
+}
+
+pub fn jindex_drop(context: Arc<RwLock<Context>>, cmd: &JIndexDropCmd) -> String {
+    match json_index::remove(&cmd.arg_name) {
+        Ok(removed) => print_integer(&(removed as i64)),
+        Err(e) => print_from_error(&e)
+    }
+}
+
+pub fn jindex_list(context: Arc<RwLock<Context>>, _cmd: &JIndexListCmd) -> String {
+    let defs: Vec<Vec<String>> = json_index::list().into_iter()
+        .map(|d| vec![d.name, d.prefix, d.path])
+        .collect();
+    print_nested_arr(defs)
+}
+
+pub fn jfind(context: Arc<RwLock<Context>>, cmd: &JFindCmd) -> String {
+    match json_index::find(&cmd.arg_query) {
+        Ok(hits) => {
+            let rows: Vec<Vec<String>> = hits.into_iter()
+                .map(|(key, doc)| vec![key, doc.to_string()])
+                .collect();
+            print_nested_arr(rows)
+        }
+        Err(e) => print_from_error(&e)
+    }
+}
+
+pub fn jft_index(context: Arc<RwLock<Context>>, cmd: &JFtIndexCmd) -> String {
+    let def = FtIndexDef {
+        name: cmd.arg_name.to_owned(),
+        prefix: cmd.arg_prefix.to_owned(),
+        paths: cmd.arg_paths.to_owned(),
+    };
+    match json_search::create(def) {
+        Ok(true) => print_ok(),
+        Ok(false) => print_err("ERR index already exists"),
+        Err(e) => print_from_error(&e)
+    }
+}
+
+pub fn jft_drop(context: Arc<RwLock<Context>>, cmd: &JFtDropCmd) -> String {
+    match json_search::remove(&cmd.arg_name) {
+        Ok(removed) => print_integer(&(removed as i64)),
+        Err(e) => print_from_error(&e)
+    }
+}
+
+pub fn jft_list(context: Arc<RwLock<Context>>, _cmd: &JFtListCmd) -> String {
+    let defs: Vec<Vec<String>> = json_search::list().into_iter()
+        .map(|d| vec![d.name, d.prefix, d.paths.join(" ")])
+        .collect();
+    print_nested_arr(defs)
+}
+
+/// Matching keys with their BM25 score, best match first
+pub fn jft_search(context: Arc<RwLock<Context>>, cmd: &JFtSearchCmd) -> String {
+    match json_search::search(&cmd.arg_query) {
+        Ok(hits) => {
+            let rows: Vec<Vec<String>> = hits.into_iter()
+                .map(|(key, score)| vec![key, format!("{:.6}", score)])
+                .collect();
+            print_nested_arr(rows)
+        }
+        Err(e) => print_from_error(&e)
+    }
+}
+
+pub fn jhistory(context: Arc<RwLock<Context>>, cmd: &JHistoryCmd) -> String {
+    match &cmd.arg_policy {
+        Some(policy) => match json_history::enable(&cmd.arg_key, policy, storage::entry_time()) {
+            Ok(_) => print_ok(),
+            Err(e) => print_from_error(&e)
+        },
+        None => match json_history::disable(&cmd.arg_key) {
+            Ok(removed) => print_integer(&(removed as i64)),
+            Err(e) => print_from_error(&e)
+        }
+    }
+}
+
+pub fn jrev_list(context: Arc<RwLock<Context>>, cmd: &JRevListCmd) -> String {
+    match json_history::list(&cmd.arg_key) {
+        Ok(revisions) => {
+            let rows: Vec<Vec<String>> = revisions.into_iter()
+                .map(|(n, timestamp)| vec![n.to_string(), timestamp.to_string()])
+                .collect();
+            print_nested_arr(rows)
+        }
+        Err(e) => print_from_error(&e)
+    }
+}
+
+pub fn jrev_get(context: Arc<RwLock<Context>>, cmd: &JRevGetCmd) -> String {
+    match json_history::get(&cmd.arg_key, cmd.arg_revision) {
+        Ok(Some(doc)) => print_string(&doc.to_string()),
+        Ok(None) => print_str("nil"),
+        Err(e) => print_from_error(&e)
+    }
+}
+
+/// RFC 6902 patch from one revision to another
+pub fn jrev_diff(context: Arc<RwLock<Context>>, cmd: &JRevDiffCmd) -> String {
+    let from = json_history::get(&cmd.arg_key, cmd.arg_from);
+    let to = json_history::get(&cmd.arg_key, cmd.arg_to);
+    match (from, to) {
+        (Ok(Some(from)), Ok(Some(to))) => print_string(&json::diff(&from, &to).to_string()),
+        (Err(e), _) | (_, Err(e)) => print_from_error(&e),
+        _ => print_err("ERR no such revision")
+    }
+}
+
+/// Writes an old revision back as the current document, which is recorded as the newest revision
+pub fn jrevert(context: Arc<RwLock<Context>>, cmd: &JRevertCmd) -> String {
+    let doc = match json_history::get(&cmd.arg_key, cmd.arg_revision) {
+        Ok(Some(doc)) => doc,
+        Ok(None) => return print_err("ERR no such revision"),
+        Err(e) => return print_from_error(&e)
+    };
+    match _update_json(&cmd.arg_key, |json| {
+        *json = doc.clone();
+        Ok(())
+    }) {
+        Ok(_) => print_ok(),
+        Err(e) => e
+    }
+}
+
+/// Differences from the document at `arg_key` to another key or a literal, as an RFC 6902 patch
+/// or as one readable line per changed path
+pub fn jdiff(context: Arc<RwLock<Context>>, cmd: &JDiffCmd) -> String {
+    let from = match _get_json(&cmd.arg_key) {
+        Ok(doc) => doc,
+        Err(e) => return e
+    };
+    let to = match &cmd.arg_other_key {
+        Some(key) => match _get_json(key) {
+            Ok(doc) => doc,
+            Err(e) => return e
+        },
+        None => cmd.arg_value.to_owned().unwrap_or(Value::Null)
+    };
+    if cmd.arg_paths {
+        print_arr(json::diff_paths(&from, &to))
+    } else {
+        print_string(&json::diff(&from, &to).to_string())
+    }
+}
+
+/// One row per group, the group value first when the query has a GROUPBY, strings are returned unquoted
+pub fn jagg(context: Arc<RwLock<Context>>, cmd: &JAggCmd) -> String {
+    let rows = match json_agg::run(&cmd.arg_query) {
+        Ok(rows) => rows,
+        Err(e) => return print_err(&e)
+    };
+    let reply = |v: &Value| match v {
+        Value::Null => print_str("nil"),
+        Value::String(s) => print_string(s),
+        v => print_string(&v.to_string())
+    };
+    let rows: Vec<String> = rows.iter()
+        .map(|row| {
+            let replies: Vec<String> = row.group.iter().chain(row.values.iter()).map(reply).collect();
+            print_reply_arr(replies)
+        })
+        .collect();
+    print_reply_arr(rows)
+}
+
+pub fn jschema(context: Arc<RwLock<Context>>, cmd: &JSchemaCmd) -> String {
+    match json_schema::register(&cmd.arg_pattern, cmd.arg_schema.to_owned()) {
+        Ok(_) => print_ok(),
+        Err(e) => print_from_error(&e)
+    }
+}
+
+pub fn jschema_del(context: Arc<RwLock<Context>>, cmd: &JSchemaDelCmd) -> String {
+    match json_schema::remove(&cmd.arg_pattern) {
+        Ok(removed) => print_integer(&(removed as i64)),
+        Err(e) => print_from_error(&e)
+    }
+}
+
+pub fn jschema_list(context: Arc<RwLock<Context>>, _cmd: &JSchemaListCmd) -> String {
+    let schemas: Vec<Vec<String>> = json_schema::list().into_iter()
+        .map(|(pattern, schema)| vec![pattern, schema.to_string()])
+        .collect();
+    print_nested_arr(schemas)
+}
+
 pub fn jdel(context: Arc<RwLock<Context>>, cmd: &JDelCmd) -> String {
     let data = match _get(&cmd.arg_key) {
         Ok(d) => {
@@ -1097,6 +1656,7 @@ pub fn jdel(context: Arc<RwLock<Context>>, cmd: &JDelCmd) -> String {
                 }
                 Err(_) => {}
             };
+            _track_json(&cmd.arg_key);
 
             print_integer(&rem_keys_count)
         }
@@ -1107,182 +1667,111 @@ pub fn jdel(context: Arc<RwLock<Context>>, cmd: &JDelCmd) -> String {
 }
 
 pub fn jrem(context: Arc<RwLock<Context>>, cmd: &JRemCmd) -> String {
-    let k = cmd.arg_key.as_bytes();
-    let mut removal_count = 0;
-    DB.fetch_and_update(k, |old| -> Option<Vec<u8>> {
-        let data = match old {
-            None => {
-                let json_b = serde_json::to_vec(&Value::Null).unwrap();
-                Data::Json(json_b)
-            }
-            Some(bytes) => {
-                match Data::from_vec(bytes) {
-                    Ok(d) => {
-                        d
-                    }
-                    Err(_) => {
-                        return None;
-                    }
-                }
-            }
-        };
-
-        match data {
-            Data::Json(mut json_b) => {
-                let mut json: Value = serde_json::from_slice(&json_b).unwrap_or(Value::Null);
-                &cmd.arg_paths.iter().for_each(|s| {
-                    match json.dot_remove(s) {
-                        Ok(_) => {
-                            removal_count += 1;
-                        }
-                        Err(_) => {}
-                    };
-                });
-                let v = bincode::serialize(&Data::Json(serde_json::to_vec(&json).unwrap())).unwrap();
-                Some(v)
-            }
-            _ => {
-                None
-            }
-        }
-    });
-    print_integer(&removal_count)
+    match _update_json(&cmd.arg_key, |json| {
+        Ok(cmd.arg_paths.iter().filter(|s| json.dot_remove(s).is_ok()).count() as i64)
+    }) {
+        Ok(removal_count) => print_integer(&removal_count),
+        Err(e) => e
+    }
 }
 
 
 pub fn jincr_by(context: Arc<RwLock<Context>>, cmd: &JIncrByCmd) -> String {
-    let k = cmd.arg_key.as_bytes();
-    let mut _value: i64 = 0;
-    DB.fetch_and_update(k, |old| -> Option<Vec<u8>> {
-        let data = match old {
-            None => {
-                let json_b = serde_json::to_vec(&Value::Null).unwrap();
-                Data::Json(json_b)
-            }
-            Some(bytes) => {
-                match Data::from_vec(bytes) {
-                    Ok(d) => {
-                        d
-                    }
-                    Err(_) => {
-                        return None;
-                    }
-                }
-            }
+    match _update_json(&cmd.arg_key, |json| {
+        let path_to_incr = json.dot_get(&cmd.arg_path).unwrap_or(Some(Value::Null)).unwrap_or(Value::Null);
+
+        let new_value = if path_to_incr.is_null() {
+            json!(cmd.arg_increment_value)
+        } else if path_to_incr.is_i64() {
+            json!(path_to_incr.as_i64().unwrap() + cmd.arg_increment_value)
+        } else if path_to_incr.is_f64() {
+            json!(path_to_incr.as_f64().unwrap() + (cmd.arg_increment_value as f64))
+        } else if path_to_incr.is_u64() {
+            json!(path_to_incr.as_u64().unwrap() + (cmd.arg_increment_value as u64))
+        } else {
+            // not a number, the document is left as it is
+            return Ok(0);
         };
-
-        match data {
-            Data::Json(mut json_b) => {
-                let mut json: Value = serde_json::from_slice(&json_b).unwrap_or(Value::Null);
-                let path_to_incr = json.dot_get(&cmd.arg_path).unwrap_or(Some(Value::Null)).unwrap_or(Value::Null);
-
-                if path_to_incr.is_null() {
-                    let new_value = json!(cmd.arg_increment_value);
-                    json.dot_set(&cmd.arg_path.to_owned(), new_value.clone());
-                    let v = bincode::serialize(&Data::Json(serde_json::to_vec(&json).unwrap())).unwrap();
-                    _value = new_value.as_i64().unwrap();
-                    return Some(v);
-                }
-                let new_value = if path_to_incr.is_number() {
-                    if path_to_incr.is_i64() {
-                        let inc = path_to_incr.as_i64().unwrap() + cmd.arg_increment_value;
-                        json!(inc)
-                    } else if path_to_incr.is_f64() {
-                        let inc = path_to_incr.as_f64().unwrap() + (cmd.arg_increment_value as f64);
-                        json!(inc)
-                    } else if path_to_incr.is_u64() {
-                        let inc = path_to_incr.as_u64().unwrap() + (cmd.arg_increment_value as u64);
-                        json!(inc)
-                    } else {
-                        Value::Null
-                    }
-                } else {
-                    Value::Null
-                };
-
-                if new_value.is_null() {
-                    let v = bincode::serialize(&Data::Json(serde_json::to_vec(&json).unwrap())).unwrap();
-                    return Some(v);
-                }
-                json.dot_set(&cmd.arg_path, new_value.clone());
-                let v = bincode::serialize(&Data::Json(serde_json::to_vec(&json).unwrap())).unwrap();
-                _value = new_value.as_i64().unwrap();
-                Some(v)
-            }
-            _ => {
-                None
-            }
-        }
-    });
-
-    print_integer(&_value)
+        json.dot_set(&cmd.arg_path, new_value.clone()).map_err(|_| "ERR invalid path".to_owned())?;
+        Ok(new_value.as_i64().unwrap_or_default())
+    }) {
+        Ok(value) => print_integer(&value),
+        Err(e) => e
+    }
 }
 
 pub fn jincr_by_float(context: Arc<RwLock<Context>>, cmd: &JIncrByFloatCmd) -> String {
-    let k = cmd.arg_key.as_bytes();
-    let mut _value: f64 = 0.0;
-    DB.fetch_and_update(k, |old| -> Option<Vec<u8>> {
-        let data = match old {
-            None => {
-                let json_b = serde_json::to_vec(&Value::Null).unwrap();
-                Data::Json(json_b)
-            }
-            Some(bytes) => {
-                match Data::from_vec(bytes) {
-                    Ok(d) => {
-                        d
-                    }
-                    Err(_) => {
-                        return None;
-                    }
-                }
-            }
+    match _update_json(&cmd.arg_key, |json| {
+        let path_to_incr = json.dot_get(&cmd.arg_path).unwrap_or(Some(Value::Null)).unwrap_or(Value::Null);
+
+        let new_value = if path_to_incr.is_null() {
+            json!(cmd.arg_increment_value)
+        } else if path_to_incr.is_f64() {
+            json!(path_to_incr.as_f64().unwrap() + (cmd.arg_increment_value as f64))
+        } else {
+            // integers and other types are left as they are
+            return Ok(0.0);
         };
+        json.dot_set(&cmd.arg_path, new_value.clone()).map_err(|_| "ERR invalid path".to_owned())?;
+        Ok(new_value.as_f64().unwrap_or_default())
+    }) {
+        Ok(value) => print_string(&value.to_string()),
+        Err(e) => e
+    }
+}
 
-        match data {
-            Data::Json(mut json_b) => {
-                let mut json: Value = serde_json::from_slice(&json_b).unwrap_or(Value::Null);
-                let path_to_incr = json.dot_get(&cmd.arg_path).unwrap_or(Some(Value::Null)).unwrap_or(Value::Null);
-
-                if path_to_incr.is_null() {
-                    let new_value = json!(cmd.arg_increment_value);
-                    json.dot_set(&cmd.arg_path.to_owned(), new_value.clone());
-                    let v = bincode::serialize(&Data::Json(serde_json::to_vec(&json).unwrap())).unwrap();
-                    _value = new_value.as_f64().unwrap();
-                    return Some(v);
+/// Runs `f` on the json value of `key` inside a single `fetch_and_update`, a missing key starts out as `null`
+/// and is only created when `f` leaves something else. The value is only written back when `f` succeeds,
+/// on error the key is left untouched and the error printed.
+fn _update_json<T, F>(key: &str, mut f: F) -> Result<T, String>
+    where F: FnMut(&mut Value) -> Result<T, String> {
+    let mut outcome: Option<Result<T, String>> = None;
+    let update = DB.fetch_and_update(key.as_bytes(), |old| -> Option<Vec<u8>> {
+        let mut json = match old.map(Data::from_vec) {
+            None => Value::Null,
+            Some(Ok(Data::Json(b))) => json_pack::unpack(&b),
+            Some(_) => {
+                outcome = Some(Err(print_err("ERR Invalid key for data type")));
+                return old.map(|b| b.to_vec());
+            }
+        };
+        match f(&mut json) {
+            Ok(t) => {
+                outcome = Some(Ok(t));
+                if old.is_none() && json.is_null() {
+                    return None;
                 }
-                let new_value = if path_to_incr.is_number() {
-                    if path_to_incr.is_i64() {
-                        let v = bincode::serialize(&Data::Json(serde_json::to_vec(&json).unwrap())).unwrap();
-                        return Some(v);
-                    } else if path_to_incr.is_f64() {
-                        let inc = path_to_incr.as_f64().unwrap() + (cmd.arg_increment_value as f64);
-                        json!(inc)
-                    } else if path_to_incr.is_u64() {
-                        let v = bincode::serialize(&Data::Json(serde_json::to_vec(&json).unwrap())).unwrap();
-
-                        return Some(v);
-                    } else {
-                        Value::Null
-                    }
-                } else {
-                    Value::Null
-                };
-
-                if new_value.is_null() {
-                    let v = bincode::serialize(&Data::Json(serde_json::to_vec(&json).unwrap())).unwrap();
-                    return Some(v);
+                if let Err(e) = json_schema::validate_key(key, &json) {
+                    outcome = Some(Err(print_err(&e)));
+                    return old.map(|b| b.to_vec());
                 }
-                json.dot_set(&cmd.arg_path, new_value.clone());
-                let v = bincode::serialize(&Data::Json(serde_json::to_vec(&json).unwrap())).unwrap();
-                _value = new_value.as_f64().unwrap();
-                Some(v)
+                Some(bincode::serialize(&Data::Json(json_pack::pack(&json))).unwrap())
             }
-            _ => {
-                None
+            Err(e) => {
+                outcome = Some(Err(print_err(&e)));
+                old.map(|b| b.to_vec())
             }
         }
     });
+    if let Err(e) = update {
+        return Err(print_from_error(&e));
+    }
+    _track_json(key);
+    outcome.unwrap_or_else(|| Err(print_err("ERR internal error")))
+}
 
-    print_string(&_value.to_string())
+/// Reads the value at a dot path of a JSON key without decoding the rest of the document
+fn _get_json_path(key: &str, path: &str) -> Result<Option<Value>, String> {
+    match _get(key)? {
+        Data::Json(json) => Ok(json_pack::read_path(&json, path)),
+        _ => Err(print_err("ERR Invalid key for data type"))
+    }
+}
+
+/// Reads the document stored at a JSON key
+fn _get_json(key: &str) -> Result<Value, String> {
+    match _get(key)? {
+        Data::Json(json) => Ok(json_pack::unpack(&json)),
+        _ => Err(print_err("ERR Invalid key for data type"))
+    }
 }
@@ -126,3 +126,21 @@ impl DatabaseError {
         }
     }
 }
+
+impl From<sled::Error> for DatabaseError {
+    fn from(e: sled::Error) -> DatabaseError {
+        DatabaseError::new(&format!("ERR {}", e))
+    }
+}
+
+impl From<rmp_serde::encode::Error> for DatabaseError {
+    fn from(e: rmp_serde::encode::Error) -> DatabaseError {
+        DatabaseError::new(&format!("ERR {}", e))
+    }
+}
+
+impl From<rmp_serde::decode::Error> for DatabaseError {
+    fn from(e: rmp_serde::decode::Error) -> DatabaseError {
+        DatabaseError::new(&format!("ERR {}", e))
+    }
+}
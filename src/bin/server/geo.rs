@@ -1,63 +1,225 @@
-use rstar::{RTreeObject, AABB, PointDistance};
+use rstar::{RTreeObject, AABB, PointDistance, Envelope};
 use std::hash::{Hash, Hasher};
+use std::collections::HashMap;
 use crate::util;
 
 use serde::{Serialize, Deserialize};
 use crate::printer::{JsonPrint, GeoJsonFeature};
 use serde_json::Value;
 use geohash::Coordinate;
+use json_dotpath::DotPaths;
+use std::cmp::Ordering;
 
 pub type Scalar = f64;
 
 
 #[derive(Clone, Debug)]
-pub struct Circle
+pub struct Polygon
 {
-    pub origin: [Scalar; 2],
-    pub radius: Scalar,
+    pub vertices: Vec<[Scalar; 2]>,
 }
 
-impl RTreeObject for Circle {
-    type Envelope = AABB<[Scalar; 2]>;
+impl Polygon {
+    pub fn envelope(&self) -> AABB<[Scalar; 2]> {
+        AABB::from_points(self.vertices.iter())
+    }
+
+    /// Even-odd ray casting test, points on the boundary may fall on either side
+    pub fn contains_point(&self, point: &[Scalar; 2]) -> bool {
+        let mut inside = false;
+        let n = self.vertices.len();
+        let mut j = n - 1;
+        for i in 0..n {
+            let a = self.vertices[i];
+            let b = self.vertices[j];
+            if (a[1] > point[1]) != (b[1] > point[1])
+                && point[0] < (b[0] - a[0]) * (point[1] - a[1]) / (b[1] - a[1]) + a[0] {
+                inside = !inside;
+            }
+            j = i;
+        }
+        inside
+    }
+}
+
+/// Mean earth radius in meters, the sphere every indexed distance is measured on
+pub const EARTH_RADIUS: Scalar = 6_371_000.0;
+
+/// Position of a `[lat, lng]` point on the unit sphere. The straight line (chord) between two
+/// positions grows with their great circle distance, so the index can order and prune points
+/// with plain squared euclidean distances.
+pub fn unit_vector(point: [Scalar; 2]) -> [Scalar; 3] {
+    let (lat, lng) = (point[0].to_radians(), point[1].to_radians());
+    [lat.cos() * lng.cos(), lat.cos() * lng.sin(), lat.sin()]
+}
+
+/// Squared chord on the unit sphere spanned by a great circle arc of `meters`
+pub fn chord_2(meters: Scalar) -> Scalar {
+    let angle = (meters / EARTH_RADIUS).max(0.0).min(std::f64::consts::PI);
+    let chord = 2.0 * (angle / 2.0).sin();
+    chord * chord
+}
+
+/// Great circle distance in meters of a squared chord on the unit sphere
+pub fn arc_meters(chord_2: Scalar) -> Scalar {
+    2.0 * EARTH_RADIUS * (chord_2.sqrt() / 2.0).min(1.0).asin()
+}
+
+/// Smallest box around the part of the unit sphere covered by the `[lat, lng]` box from `min` to `max`
+pub fn sphere_envelope(min: [Scalar; 2], max: [Scalar; 2]) -> AABB<[Scalar; 3]> {
+    let (min_lat, max_lat) = (min[0].max(-90.0), max[0].min(90.0));
+    // the position is a product of a term of the latitude and a term of the longitude, so its
+    // extremes lie on the corners or where a term peaks inside the box
+    let mut lats = vec![min_lat, max_lat];
+    if min_lat < 0.0 && max_lat > 0.0 {
+        lats.push(0.0);
+    }
+    let mut lngs = vec![min[1], max[1]];
+    lngs.extend([-180.0, -90.0, 0.0, 90.0, 180.0].iter().filter(|l| **l > min[1] && **l < max[1]));
+
+    let corners: Vec<[Scalar; 3]> = lats.iter()
+        .flat_map(|lat| lngs.iter().map(move |lng| unit_vector([*lat, *lng])))
+        .collect();
+    AABB::from_points(corners.iter())
+}
+
+/// A member together with the box it searches in during a distance join
+pub struct SearchArea<'a> {
+    pub point: &'a GeoPoint2D,
+    envelope: AABB<[Scalar; 3]>,
+}
+
+impl<'a> SearchArea<'a> {
+    pub fn new(point: &'a GeoPoint2D, radius: Scalar) -> Self {
+        let center = point.position();
+        let chord = chord_2(radius).sqrt();
+        SearchArea {
+            point,
+            envelope: AABB::from_corners([center[0] - chord, center[1] - chord, center[2] - chord],
+                                         [center[0] + chord, center[1] + chord, center[2] + chord]),
+        }
+    }
+}
+
+impl<'a> RTreeObject for SearchArea<'a> {
+    type Envelope = AABB<[Scalar; 3]>;
 
     fn envelope(&self) -> Self::Envelope {
-        let corner_1 = [self.origin[0] - self.radius, self.origin[1] - self.radius];
-        let corner_2 = [self.origin[0] + self.radius, self.origin[1] + self.radius];
-        AABB::from_corners(corner_1, corner_2)
+        self.envelope
     }
 }
 
-impl PointDistance for Circle
-{
-    fn distance_2(&self, point: &[Scalar; 2]) -> f64
-    {
-        let distance_to_origin = util::get_distance((self.origin[0], self.origin[1]), (point[0], point[1]));
-        let distance_to_ring = distance_to_origin - self.radius;
-        let distance_to_circle = Scalar::max(0.0, distance_to_ring);
-        // We must return the squared distance!
-        distance_to_circle * distance_to_circle
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl FilterOp {
+    pub fn parse(s: &str) -> Option<FilterOp> {
+        match s {
+            "==" | "=" => Some(FilterOp::Eq),
+            "!=" => Some(FilterOp::Ne),
+            ">" => Some(FilterOp::Gt),
+            ">=" => Some(FilterOp::Gte),
+            "<" => Some(FilterOp::Lt),
+            "<=" => Some(FilterOp::Lte),
+            _ => None
+        }
     }
+}
 
-    // This implementation is not required but more efficient since it
-    // omits the calculation of a square root
-    fn contains_point(&self, point: &[Scalar; 2]) -> bool
-    {
-        let distance_to_origin_2 = util::get_distance((self.origin[0], self.origin[1]), (point[0], point[1]));
-        let radius_2 = self.radius * self.radius;
-        distance_to_origin_2 <= radius_2
+#[derive(Clone, Debug)]
+pub struct FilterCondition {
+    pub path: String,
+    pub op: FilterOp,
+    pub value: Value,
+}
+
+/// Conditions of a `WHERE` clause, all of which must hold for a member to match
+#[derive(Clone, Debug, Default)]
+pub struct GeoFilter {
+    pub conditions: Vec<FilterCondition>,
+}
+
+impl GeoFilter {
+    pub fn matches(&self, point: &GeoPoint2D) -> bool {
+        self.matches_data(&point.data)
+    }
+
+    pub fn matches_data(&self, data: &Option<Value>) -> bool {
+        let data = match data {
+            None => Value::Null,
+            Some(d) => d.to_owned()
+        };
+        self.conditions.iter().all(|c| {
+            let field = data.dot_get::<Value>(&c.path).unwrap_or(None).unwrap_or(Value::Null);
+            let ordering = compare_values(&field, &c.value);
+            match c.op {
+                FilterOp::Eq => ordering == Some(Ordering::Equal),
+                FilterOp::Ne => ordering != Some(Ordering::Equal),
+                FilterOp::Gt => ordering == Some(Ordering::Greater),
+                FilterOp::Gte => ordering == Some(Ordering::Greater) || ordering == Some(Ordering::Equal),
+                FilterOp::Lt => ordering == Some(Ordering::Less),
+                FilterOp::Lte => ordering == Some(Ordering::Less) || ordering == Some(Ordering::Equal),
+            }
+        })
+    }
+}
+
+/// Orders json scalars of the same kind, values of different kinds are not comparable
+pub fn compare_values(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => {
+            a.as_f64().unwrap_or(0.0).partial_cmp(&b.as_f64().unwrap_or(0.0))
+        }
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        (a, b) => {
+            if a == b { Some(Ordering::Equal) } else { None }
+        }
     }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GeoPoint2D {
     pub tag: String,
+    #[serde(with = "json_string")]
     pub data: Option<Value>,
+    /// Latitude in degrees
     x_cord: f64,
+    /// Longitude in degrees
     y_cord: f64,
     hash: String,
+    /// Unix time in milliseconds after which the member is expired
+    #[serde(default)]
+    pub expires_at: Option<u64>,
 }
 
 
+/// bincode can not deserialize a `Value` directly, so member data is stored as a json string
+mod json_string {
+    use serde::{Serialize, Deserialize, Serializer, Deserializer};
+    use serde::de::Error;
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(data: &Option<Value>, serializer: S) -> Result<S::Ok, S::Error> {
+        data.as_ref().map(|v| v.to_string()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            None => Ok(None),
+            Some(s) => serde_json::from_str(&s).map(Some).map_err(D::Error::custom)
+        }
+    }
+}
+
 impl GeoPoint2D {
     pub fn new(tag: String) -> Self {
         GeoPoint2D {
@@ -66,6 +228,7 @@ impl GeoPoint2D {
             x_cord: 0.0,
             y_cord: 0.0,
             hash: String::new(),
+            expires_at: None,
         }
     }
     pub fn with_cord(tag: String, x_cord: f64, y_cord: f64) -> Self {
@@ -75,37 +238,31 @@ impl GeoPoint2D {
             x_cord,
             y_cord,
             hash: String::new(),
+            expires_at: None,
         };
-        match geohash::encode(Coordinate { x: geo_point.x_cord, y: geo_point.y_cord }, 10) {
-            Ok(t) => geo_point.hash = t,
-            Err(e) => {}
-        };
-
+        geo_point.update_hash();
         geo_point
     }
     pub fn set_cord(&mut self, x_cord: f64, y_cord: f64) {
         self.x_cord = x_cord;
         self.y_cord = y_cord;
-
-        match geohash::encode(Coordinate { x: self.x_cord, y: self.y_cord }, 10) {
-            Ok(t) => self.hash = t,
-            Err(e) => {}
-        };
+        self.update_hash();
     }
 
     pub fn set_x_cord(&mut self, x_cord: f64) {
         self.x_cord = x_cord;
-        match geohash::encode(Coordinate { x: self.x_cord, y: self.y_cord }, 10) {
-            Ok(t) => self.hash = t,
-            Err(e) => {}
-        };
+        self.update_hash();
     }
     pub fn set_y_cord(&mut self, y_cord: f64) {
         self.y_cord = y_cord;
-        match geohash::encode(Coordinate { x: self.x_cord, y: self.y_cord }, 10) {
-            Ok(t) => self.hash = t,
-            Err(e) => {}
-        };
+        self.update_hash();
+    }
+
+    /// geohash coordinates are `x` longitude and `y` latitude
+    pub fn update_hash(&mut self) {
+        if let Ok(t) = geohash::encode(Coordinate { x: self.y_cord, y: self.x_cord }, 10) {
+            self.hash = t
+        }
     }
 
     pub fn x_cord(&self) -> f64 {
@@ -123,23 +280,33 @@ impl GeoPoint2D {
     pub fn get_cord(&self) -> (f64, f64) {
         (self.x_cord, self.y_cord)
     }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map_or(false, |t| t <= now)
+    }
+
+    /// Position on the unit sphere the point is indexed by
+    pub fn position(&self) -> [Scalar; 3] {
+        unit_vector([self.x_cord, self.y_cord])
+    }
 }
 
 impl RTreeObject for GeoPoint2D
 {
-    type Envelope = AABB<[Scalar; 2]>;
+    type Envelope = AABB<[Scalar; 3]>;
 
     fn envelope(&self) -> Self::Envelope
     {
-        AABB::from_point([self.x_cord, self.y_cord])
+        AABB::from_point(self.position())
     }
 }
 
 impl PointDistance for GeoPoint2D {
-    fn distance_2(&self, point: &[Scalar; 2]) -> Scalar
+    /// Squared chord to a position on the unit sphere, see `arc_meters` for the distance in meters
+    fn distance_2(&self, point: &[Scalar; 3]) -> Scalar
     {
-        let distance_to_origin = util::get_distance((self.x_cord, self.y_cord), (point[0], point[1]));
-        distance_to_origin
+        let p = self.position();
+        (p[0] - point[0]).powi(2) + (p[1] - point[1]).powi(2) + (p[2] - point[2]).powi(2)
     }
 }
 
@@ -169,8 +336,8 @@ impl JsonPrint for GeoPoint2D {
               "geometry": {
                 "type": "Point",
                 "coordinates": [
-                  self.x_cord,
-                  self.y_cord
+                  self.y_cord,
+                  self.x_cord
                 ]
               }
             }
@@ -182,4 +349,869 @@ impl GeoJsonFeature for GeoPoint2D {
     fn geo_json_feature(&self) -> Value {
         self.print_json()
     }
-}
\ No newline at end of file
+}
+
+/// A search hit, exported with its distance from the search origin
+impl GeoJsonFeature for (&GeoPoint2D, f64) {
+    fn geo_json_feature(&self) -> Value {
+        let mut feature = self.0.print_json();
+        feature["properties"]["distance"] = json!(self.1);
+        feature
+    }
+}
+/// A timestamped position of a tracked member
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrackPoint {
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+    pub lat: f64,
+    pub lng: f64,
+}
+
+/// Movement between two consecutive track points, speed in meters per second and heading in degrees
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackSegment {
+    pub distance: f64,
+    pub speed: f64,
+    pub heading: f64,
+}
+
+/// The recorded path of a member, oldest position first
+#[derive(Clone, Debug)]
+pub struct Trajectory {
+    pub tag: String,
+    pub points: Vec<TrackPoint>,
+}
+
+impl Trajectory {
+    pub fn segments(&self) -> Vec<TrackSegment> {
+        self.points.windows(2).map(|w| {
+            let start = util::Location { latitude: w[0].lat, longitude: w[0].lng };
+            let end = util::Location { latitude: w[1].lat, longitude: w[1].lng };
+            let distance = util::get_distance((w[0].lat, w[0].lng), (w[1].lat, w[1].lng));
+            let seconds = w[1].timestamp.saturating_sub(w[0].timestamp) as f64 / 1000.0;
+            TrackSegment {
+                distance,
+                speed: if seconds > 0.0 { distance / seconds } else { 0.0 },
+                heading: util::bearing(start, end),
+            }
+        }).collect()
+    }
+
+    /// Milliseconds between the first and the last position
+    pub fn duration(&self) -> u64 {
+        match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => last.timestamp.saturating_sub(first.timestamp),
+            _ => 0
+        }
+    }
+}
+
+impl JsonPrint for Trajectory {
+    fn print_json(&self) -> Value {
+        let distance: f64 = self.segments().iter().map(|s| s.distance).sum();
+        let duration = self.duration();
+        let speed = if duration > 0 { distance / (duration as f64 / 1000.0) } else { 0.0 };
+        let coordinates: Vec<[f64; 2]> = self.points.iter().map(|p| [p.lng, p.lat]).collect();
+        let timestamps: Vec<u64> = self.points.iter().map(|p| p.timestamp).collect();
+        json!(
+        {
+              "type": "Feature",
+              "properties": {
+                "name" : self.tag,
+                "timestamps" : timestamps,
+                "distance" : distance,
+                "duration" : duration,
+                "speed" : speed
+              },
+              "geometry": {
+                "type": "LineString",
+                "coordinates": coordinates
+              }
+            }
+        )
+    }
+}
+
+impl GeoJsonFeature for Trajectory {
+    fn geo_json_feature(&self) -> Value {
+        self.print_json()
+    }
+}
+
+/// Members sharing a geohash cell, aggregated for display at a map zoom level
+#[derive(Clone, Debug)]
+pub struct GeoCluster {
+    pub cell: String,
+    pub count: usize,
+    pub lat: f64,
+    pub lng: f64,
+    /// min lng, min lat, max lng, max lat of the members in the cluster
+    pub bounds: [f64; 4],
+    /// Name of the only member of a single member cluster
+    pub member: Option<String>,
+    pub with_bounds: bool,
+}
+
+/// Geohash length whose cells are roughly the size of a map tile at `zoom`
+pub fn zoom_precision(zoom: u8) -> usize {
+    match zoom {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=7 => 3,
+        8..=9 => 4,
+        10..=12 => 5,
+        13..=14 => 6,
+        15..=17 => 7,
+        18..=19 => 8,
+        _ => 9
+    }
+}
+
+/// Groups points by the geohash cell of the given length, the biggest clusters first
+pub fn cluster_points<'a, I>(points: I, precision: usize, with_bounds: bool) -> Vec<GeoCluster>
+    where I: Iterator<Item=&'a GeoPoint2D> {
+    let mut cells: HashMap<String, GeoCluster> = HashMap::new();
+    for point in points {
+        let (lat, lng) = (point.x_cord, point.y_cord);
+        // member hashes are longer than any cell, so the cell is a prefix of the hash
+        let cell = match point.hash().get(..precision) {
+            Some(c) => c.to_owned(),
+            None => continue
+        };
+        let cluster = cells.entry(cell.to_owned()).or_insert_with(|| GeoCluster {
+            cell,
+            count: 0,
+            lat: 0.0,
+            lng: 0.0,
+            bounds: [lng, lat, lng, lat],
+            member: Some(point.tag.to_owned()),
+            with_bounds,
+        });
+        cluster.count += 1;
+        // running mean keeps the centroid without a second pass
+        cluster.lat += (lat - cluster.lat) / cluster.count as f64;
+        cluster.lng += (lng - cluster.lng) / cluster.count as f64;
+        cluster.bounds = [cluster.bounds[0].min(lng), cluster.bounds[1].min(lat),
+            cluster.bounds[2].max(lng), cluster.bounds[3].max(lat)];
+        if cluster.count > 1 {
+            cluster.member = None;
+        }
+    }
+    let mut clusters: Vec<GeoCluster> = cells.into_iter().map(|(_, c)| c).collect();
+    clusters.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.cell.cmp(&b.cell)));
+    clusters
+}
+
+impl JsonPrint for GeoCluster {
+    fn print_json(&self) -> Value {
+        let mut feature = json!(
+        {
+              "type": "Feature",
+              "properties": {
+                "cell" : self.cell,
+                "count" : self.count
+              },
+              "geometry": {
+                "type": "Point",
+                "coordinates": [
+                  self.lng,
+                  self.lat
+                ]
+              }
+            }
+        );
+        if let Some(member) = &self.member {
+            feature["properties"]["name"] = json!(member);
+        }
+        if self.with_bounds {
+            feature["bbox"] = json!(self.bounds);
+        }
+        feature
+    }
+}
+
+impl GeoJsonFeature for GeoCluster {
+    fn geo_json_feature(&self) -> Value {
+        self.print_json()
+    }
+}
+
+/// Spatial relation asked of the members of a geo key by `GEOQUERY`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpatialOp {
+    /// The member and the query geometry share at least one point
+    Intersects,
+    /// The member covers the query geometry
+    Contains,
+    /// The member lies inside the query geometry
+    Within,
+}
+
+/// Geometry of a geo key member, coordinates are `[lat, lng]` like `GeoPoint2D`.
+/// Predicates are planar in degrees, which is accurate enough for shapes that do not span
+/// large parts of the globe or cross the antimeridian.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Geometry {
+    Point([Scalar; 2]),
+    LineString(Vec<[Scalar; 2]>),
+    /// Exterior ring followed by holes, rings are implicitly closed
+    Polygon(Vec<Vec<[Scalar; 2]>>),
+}
+
+const EPSILON: Scalar = 1e-12;
+
+impl Geometry {
+    /// Reads a GeoJSON `Point`, `LineString` or `Polygon` geometry
+    pub fn from_geo_json(geometry: &Value) -> Result<Geometry, String> {
+        fn position(v: &Value) -> Result<[Scalar; 2], String> {
+            match (v[0].as_f64(), v[1].as_f64()) {
+                (Some(lng), Some(lat)) => Ok([lat, lng]),
+                _ => Err("ERR invalid coordinates".to_owned())
+            }
+        }
+        fn positions(v: &Value) -> Result<Vec<[Scalar; 2]>, String> {
+            v.as_array().ok_or("ERR invalid coordinates".to_owned())?.iter().map(position).collect()
+        }
+
+        let coordinates = &geometry["coordinates"];
+        match geometry["type"].as_str() {
+            Some("Point") => Ok(Geometry::Point(position(coordinates)?)),
+            Some("LineString") => {
+                let line = positions(coordinates)?;
+                if line.len() < 2 {
+                    return Err("ERR a LineString needs at least two positions".to_owned());
+                }
+                Ok(Geometry::LineString(line))
+            }
+            Some("Polygon") => {
+                let mut rings: Vec<Vec<[Scalar; 2]>> = vec![];
+                for ring in coordinates.as_array().ok_or("ERR invalid coordinates".to_owned())? {
+                    let mut ring = positions(ring)?;
+                    if ring.len() > 1 && ring.first() == ring.last() {
+                        ring.pop();
+                    }
+                    if ring.len() < 3 {
+                        return Err("ERR a Polygon ring needs at least three positions".to_owned());
+                    }
+                    rings.push(ring);
+                }
+                if rings.is_empty() {
+                    return Err("ERR a Polygon needs an exterior ring".to_owned());
+                }
+                Ok(Geometry::Polygon(rings))
+            }
+            _ => Err("ERR unsupported geometry type".to_owned())
+        }
+    }
+
+    pub fn to_geo_json(&self) -> Value {
+        fn position(p: &[Scalar; 2]) -> Value {
+            json!([p[1], p[0]])
+        }
+        match self {
+            Geometry::Point(p) => json!({"type": "Point", "coordinates": position(p)}),
+            Geometry::LineString(line) => {
+                json!({"type": "LineString", "coordinates": line.iter().map(position).collect::<Vec<Value>>()})
+            }
+            Geometry::Polygon(rings) => {
+                // GeoJSON rings repeat their first position at the end
+                let rings: Vec<Vec<Value>> = rings.iter()
+                    .map(|r| r.iter().chain(r.first()).map(position).collect())
+                    .collect();
+                json!({"type": "Polygon", "coordinates": rings})
+            }
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Geometry::Point(_) => "Point",
+            Geometry::LineString(_) => "LineString",
+            Geometry::Polygon(_) => "Polygon",
+        }
+    }
+
+    pub fn envelope(&self) -> AABB<[Scalar; 2]> {
+        match self {
+            Geometry::Point(p) => AABB::from_point(*p),
+            Geometry::LineString(line) => AABB::from_points(line.iter()),
+            Geometry::Polygon(rings) => AABB::from_points(rings[0].iter()),
+        }
+    }
+
+    fn vertices(&self) -> Vec<[Scalar; 2]> {
+        match self {
+            Geometry::Point(p) => vec![*p],
+            Geometry::LineString(line) => line.to_owned(),
+            Geometry::Polygon(rings) => rings.iter().flatten().cloned().collect(),
+        }
+    }
+
+    fn segments(&self) -> Vec<([Scalar; 2], [Scalar; 2])> {
+        match self {
+            Geometry::Point(_) => vec![],
+            Geometry::LineString(line) => line.windows(2).map(|w| (w[0], w[1])).collect(),
+            Geometry::Polygon(rings) => rings.iter().flat_map(|r| ring_segments(r)).collect(),
+        }
+    }
+
+    /// Whether `p` lies on or inside the geometry
+    pub fn covers_point(&self, p: &[Scalar; 2]) -> bool {
+        match self {
+            Geometry::Point(q) => (p[0] - q[0]).abs() <= EPSILON && (p[1] - q[1]).abs() <= EPSILON,
+            Geometry::LineString(_) => self.segments().iter().any(|(a, b)| on_segment(a, b, p)),
+            Geometry::Polygon(rings) => {
+                if self.segments().iter().any(|(a, b)| on_segment(a, b, p)) {
+                    return true;
+                }
+                ring_contains(&rings[0], p) && !rings[1..].iter().any(|hole| ring_contains(hole, p))
+            }
+        }
+    }
+
+    pub fn intersects(&self, other: &Geometry) -> bool {
+        if !self.envelope().intersects(&other.envelope()) {
+            return false;
+        }
+        let segments = self.segments();
+        let other_segments = other.segments();
+        let crossing = segments.iter().any(|(a, b)| {
+            other_segments.iter().any(|(c, d)| segments_intersect(a, b, c, d))
+        });
+        crossing
+            || other.vertices().iter().any(|p| self.covers_point(p))
+            || self.vertices().iter().any(|p| other.covers_point(p))
+    }
+
+    pub fn contains(&self, other: &Geometry) -> bool {
+        if !self.envelope().contains_envelope(&other.envelope()) {
+            return false;
+        }
+        if !other.vertices().iter().all(|p| self.covers_point(p)) {
+            return false;
+        }
+        let other_segments = other.segments();
+        match self {
+            Geometry::Point(_) => true,
+            // every vertex is on the line, so each segment is covered when its midpoint is
+            Geometry::LineString(_) => other_segments.iter().all(|(a, b)| self.covers_point(&midpoint(a, b))),
+            Geometry::Polygon(rings) => {
+                let segments = self.segments();
+                // without crossings a hole is either inside `other` or apart from it, and one
+                // inside would be covered by `other` while its edges all lie on this polygon
+                other_segments.iter().all(|(a, b)| {
+                    self.covers_point(&midpoint(a, b))
+                        && !segments.iter().any(|(c, d)| segments_cross(a, b, c, d))
+                }) && !rings[1..].iter().any(|hole| ring_interior_point(hole).map_or(false, |p| other.covers_point(&p)))
+            }
+        }
+    }
+
+    pub fn relates(&self, op: SpatialOp, query: &Geometry) -> bool {
+        match op {
+            SpatialOp::Intersects => self.intersects(query),
+            SpatialOp::Contains => self.contains(query),
+            SpatialOp::Within => query.contains(self),
+        }
+    }
+}
+
+fn ring_segments(ring: &[[Scalar; 2]]) -> Vec<([Scalar; 2], [Scalar; 2])> {
+    (0..ring.len()).map(|i| (ring[i], ring[(i + 1) % ring.len()])).collect()
+}
+
+fn ring_contains(ring: &[[Scalar; 2]], p: &[Scalar; 2]) -> bool {
+    Polygon { vertices: ring.to_vec() }.contains_point(p)
+}
+
+/// A point strictly inside `ring`, taken halfway between the first two edges met by a line
+/// that passes between vertices
+fn ring_interior_point(ring: &[[Scalar; 2]]) -> Option<[Scalar; 2]> {
+    let mut ys: Vec<Scalar> = ring.iter().map(|p| p[1]).collect();
+    ys.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    ys.dedup();
+    if ys.len() < 2 {
+        return None;
+    }
+    let k = ys.len() / 2;
+    let y = (ys[k - 1] + ys[k]) / 2.0;
+    let mut xs: Vec<Scalar> = ring_segments(ring).iter()
+        .filter(|(a, b)| (a[1] > y) != (b[1] > y))
+        .map(|(a, b)| a[0] + (y - a[1]) * (b[0] - a[0]) / (b[1] - a[1]))
+        .collect();
+    xs.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    match (xs.get(0), xs.get(1)) {
+        (Some(x0), Some(x1)) => Some([(x0 + x1) / 2.0, y]),
+        _ => None
+    }
+}
+
+fn midpoint(a: &[Scalar; 2], b: &[Scalar; 2]) -> [Scalar; 2] {
+    [(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0]
+}
+
+fn orientation(a: &[Scalar; 2], b: &[Scalar; 2], c: &[Scalar; 2]) -> Scalar {
+    (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])
+}
+
+fn on_segment(a: &[Scalar; 2], b: &[Scalar; 2], p: &[Scalar; 2]) -> bool {
+    orientation(a, b, p).abs() <= EPSILON
+        && p[0] >= a[0].min(b[0]) - EPSILON && p[0] <= a[0].max(b[0]) + EPSILON
+        && p[1] >= a[1].min(b[1]) - EPSILON && p[1] <= a[1].max(b[1]) + EPSILON
+}
+
+/// Segments share at least one point
+fn segments_intersect(a: &[Scalar; 2], b: &[Scalar; 2], c: &[Scalar; 2], d: &[Scalar; 2]) -> bool {
+    segments_cross(a, b, c, d)
+        || on_segment(a, b, c) || on_segment(a, b, d)
+        || on_segment(c, d, a) || on_segment(c, d, b)
+}
+
+/// Segments cross at a single point interior to both
+fn segments_cross(a: &[Scalar; 2], b: &[Scalar; 2], c: &[Scalar; 2], d: &[Scalar; 2]) -> bool {
+    let o1 = orientation(a, b, c);
+    let o2 = orientation(a, b, d);
+    let o3 = orientation(c, d, a);
+    let o4 = orientation(c, d, b);
+    o1 * o2 < -EPSILON && o3 * o4 < -EPSILON
+}
+
+/// A `LineString` or `Polygon` member of a geo key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GeoShape {
+    pub tag: String,
+    #[serde(with = "json_string")]
+    pub data: Option<Value>,
+    pub geometry: Geometry,
+}
+
+impl RTreeObject for GeoShape {
+    type Envelope = AABB<[Scalar; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        self.geometry.envelope()
+    }
+}
+
+impl PartialEq for GeoShape {
+    fn eq(&self, other: &Self) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl Eq for GeoShape {}
+
+impl Hash for GeoShape {
+    fn hash<H: Hasher>(&self, hasher: &mut H) {
+        self.tag.hash(hasher)
+    }
+}
+
+impl JsonPrint for GeoShape {
+    fn print_json(&self) -> Value {
+        json!(
+        {
+              "type": "Feature",
+              "properties": {
+                "name" : self.tag,
+                "data" : self.data
+              },
+              "geometry": self.geometry.to_geo_json()
+            }
+        )
+    }
+}
+
+impl GeoJsonFeature for GeoShape {
+    fn geo_json_feature(&self) -> Value {
+        self.print_json()
+    }
+}
+
+/// Any member of a geo key, as returned by queries that consider points and shapes together
+#[derive(Clone, Copy, Debug)]
+pub enum GeoMember<'a> {
+    Point(&'a GeoPoint2D),
+    Shape(&'a GeoShape),
+}
+
+impl<'a> GeoMember<'a> {
+    pub fn tag(&self) -> &'a str {
+        match self {
+            GeoMember::Point(p) => &p.tag,
+            GeoMember::Shape(s) => &s.tag,
+        }
+    }
+
+    pub fn data(&self) -> &'a Option<Value> {
+        match self {
+            GeoMember::Point(p) => &p.data,
+            GeoMember::Shape(s) => &s.data,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            GeoMember::Point(_) => "Point",
+            GeoMember::Shape(s) => s.geometry.type_name(),
+        }
+    }
+}
+
+impl<'a> GeoJsonFeature for GeoMember<'a> {
+    fn geo_json_feature(&self) -> Value {
+        match self {
+            GeoMember::Point(p) => p.geo_json_feature(),
+            GeoMember::Shape(s) => s.geo_json_feature(),
+        }
+    }
+}
+
+/// Reads the `Point` features of a GeoJSON FeatureCollection, the member name is taken from the
+/// feature `id` or the `name` property and the remaining properties are kept as member data
+pub fn points_from_geo_json(collection: &Value) -> Result<Vec<GeoPoint2D>, String> {
+    let features = match collection.get("features").and_then(|f| f.as_array()) {
+        Some(f) => f,
+        None => {
+            return Err("ERR expected a GeoJSON FeatureCollection".to_owned());
+        }
+    };
+
+    let mut points: Vec<GeoPoint2D> = vec![];
+
+    for (i, feature) in features.iter().enumerate() {
+        let geometry = &feature["geometry"];
+        if geometry["type"] != "Point" {
+            continue;
+        }
+        let (lng, lat) = match (geometry["coordinates"][0].as_f64(), geometry["coordinates"][1].as_f64()) {
+            (Some(lng), Some(lat)) => (lng, lat),
+            _ => {
+                return Err(format!("ERR invalid coordinates in feature {}", i));
+            }
+        };
+
+        let (tag, properties) = feature_tag(feature, i)?;
+
+        let mut point = GeoPoint2D::with_cord(tag, lat, lng);
+        point.data = properties;
+        points.push(point);
+    }
+    Ok(points)
+}
+
+/// Reads the `LineString` and `Polygon` features of a GeoJSON FeatureCollection, named the same
+/// way as `points_from_geo_json`
+pub fn shapes_from_geo_json(collection: &Value) -> Result<Vec<GeoShape>, String> {
+    let features = match collection.get("features").and_then(|f| f.as_array()) {
+        Some(f) => f,
+        None => {
+            return Err("ERR expected a GeoJSON FeatureCollection".to_owned());
+        }
+    };
+
+    let mut shapes: Vec<GeoShape> = vec![];
+
+    for (i, feature) in features.iter().enumerate() {
+        let geometry = match Geometry::from_geo_json(&feature["geometry"]) {
+            Ok(Geometry::Point(_)) => continue,
+            Ok(g) => g,
+            Err(e) => {
+                return Err(format!("{} in feature {}", e, i));
+            }
+        };
+        let (tag, properties) = feature_tag(feature, i)?;
+        shapes.push(GeoShape { tag, data: properties, geometry });
+    }
+    Ok(shapes)
+}
+
+/// Member name of a feature and its remaining properties, if any
+fn feature_tag(feature: &Value, i: usize) -> Result<(String, Option<Value>), String> {
+    let mut properties = feature["properties"].clone();
+    let tag = match &feature["id"] {
+        Value::String(s) => s.to_owned(),
+        Value::Number(n) => n.to_string(),
+        _ => {
+            match properties.as_object_mut().and_then(|p| p.remove("name")) {
+                Some(Value::String(s)) => s,
+                Some(Value::Number(n)) => n.to_string(),
+                _ => {
+                    return Err(format!("ERR feature {} has no id or name", i));
+                }
+            }
+        }
+    };
+    let has_properties = properties.as_object().map_or(false, |p| !p.is_empty());
+    Ok((tag, if has_properties { Some(properties) } else { None }))
+}
+
+/// Reads `lat,lng,name` rows, a header row may name the columns in any order and columns other
+/// than the coordinates and name are kept as member data
+pub fn points_from_csv(csv: &str) -> Result<Vec<GeoPoint2D>, String> {
+    let mut rows = csv.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .map(split_csv_row)
+        .peekable();
+
+    let first_row = match rows.peek() {
+        None => {
+            return Ok(vec![]);
+        }
+        Some(r) => r.to_owned()
+    };
+
+    let has_header = first_row.len() < 2 || first_row[0].parse::<f64>().is_err() || first_row[1].parse::<f64>().is_err();
+    let header: Vec<String> = if has_header {
+        rows.next();
+        first_row.iter().map(|h| h.to_lowercase()).collect()
+    } else {
+        vec!["lat".to_owned(), "lng".to_owned(), "name".to_owned()]
+    };
+
+    let column = |names: &[&str]| header.iter().position(|h| names.contains(&h.as_str()));
+    let (lat_col, lng_col, name_col) = match (column(&["lat", "latitude"]), column(&["lng", "lon", "long", "longitude"]), column(&["name", "id", "member"])) {
+        (Some(lat), Some(lng), Some(name)) => (lat, lng, name),
+        _ => {
+            return Err("ERR csv header must name lat, lng and name columns".to_owned());
+        }
+    };
+
+    let mut points: Vec<GeoPoint2D> = vec![];
+    for (i, row) in rows.enumerate() {
+        let field = |col: usize| row.get(col).map(|f| f.as_str()).unwrap_or("");
+        let (lat, lng) = match (field(lat_col).parse::<f64>(), field(lng_col).parse::<f64>()) {
+            (Ok(lat), Ok(lng)) => (lat, lng),
+            _ => {
+                return Err(format!("ERR invalid coordinates in row {}", i + 1));
+            }
+        };
+        let tag = field(name_col);
+        if tag.is_empty() {
+            return Err(format!("ERR missing name in row {}", i + 1));
+        }
+
+        let mut point = GeoPoint2D::with_cord(tag.to_owned(), lat, lng);
+        let mut data = serde_json::Map::new();
+        for (col, name) in header.iter().enumerate() {
+            if col == lat_col || col == lng_col || col == name_col || col >= row.len() {
+                continue;
+            }
+            let value = match row[col].parse::<f64>() {
+                Ok(n) => json!(n),
+                Err(_) => Value::String(row[col].to_owned())
+            };
+            data.insert(name.to_owned(), value);
+        }
+        if !data.is_empty() {
+            point.data = Some(Value::Object(data));
+        }
+        points.push(point);
+    }
+    Ok(points)
+}
+
+fn split_csv_row(line: &str) -> Vec<String> {
+    let mut fields: Vec<String> = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                fields.push(field.trim().to_owned());
+                field.clear();
+            }
+            c => field.push(c)
+        }
+    }
+    fields.push(field.trim().to_owned());
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_matches_member_data() {
+        let mut point = GeoPoint2D::with_cord("driver:1".to_owned(), 5.6037, -0.1870);
+        point.data = Some(json!({"status" : "available", "rating" : 4.5}));
+
+        let filter = GeoFilter {
+            conditions: vec![
+                FilterCondition { path: "status".to_owned(), op: FilterOp::Eq, value: json!("available") },
+                FilterCondition { path: "rating".to_owned(), op: FilterOp::Gte, value: json!(4) },
+            ]
+        };
+        assert!(filter.matches(&point));
+
+        point.data = Some(json!({"status" : "busy", "rating" : 4.5}));
+        assert!(!filter.matches(&point));
+
+        point.data = None;
+        assert!(!filter.matches(&point));
+    }
+
+    #[test]
+    fn test_polygon_contains_point() {
+        let polygon = Polygon {
+            vertices: vec![[0.0, 0.0], [0.0, 10.0], [10.0, 10.0], [10.0, 0.0]]
+        };
+        assert!(polygon.contains_point(&[5.0, 5.0]));
+        assert!(!polygon.contains_point(&[15.0, 5.0]));
+        assert!(!polygon.contains_point(&[-1.0, -1.0]));
+    }
+
+    #[test]
+    fn test_points_from_geo_json() {
+        let collection = json!({
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "properties": {"name": "kumasi-store1", "open": true},
+                    "geometry": {"type": "Point", "coordinates": [-1.596427, 5.506981]}
+                },
+                {
+                    "type": "Feature",
+                    "id": "tema",
+                    "properties": {},
+                    "geometry": {"type": "Point", "coordinates": [-1.372422, 5.194894]}
+                }
+            ]
+        });
+        let points = points_from_geo_json(&collection).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].tag, "kumasi-store1");
+        assert_eq!(points[0].data, Some(json!({"open": true})));
+        assert_eq!(points[1].tag, "tema");
+        assert_eq!(points[1].data, None);
+        // exported as [lng, lat] like the import
+        assert_eq!(points[0].print_json()["geometry"]["coordinates"], json!([-1.596427, 5.506981]));
+    }
+
+    #[test]
+    fn test_points_from_csv() {
+        let csv = "name,lat,lng,city\n\"store, 1\",5.506981,-1.596427,Kumasi\ntema,5.194894,-1.372422,Tema";
+        let points = points_from_csv(csv).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].tag, "store, 1");
+        assert_eq!(points[0].data, Some(json!({"city": "Kumasi"})));
+
+        let points = points_from_csv("5.506981,-1.596427,kumasi\n").unwrap();
+        assert_eq!(points[0].tag, "kumasi");
+        assert!(points_from_csv("5.5,abc,kumasi").is_err());
+    }
+
+    #[test]
+    fn test_trajectory_segments() {
+        let trajectory = Trajectory {
+            tag: "courier".to_owned(),
+            points: vec![
+                TrackPoint { timestamp: 0, lat: 0.0, lng: 0.0 },
+                TrackPoint { timestamp: 10_000, lat: 0.0, lng: 0.001 },
+                TrackPoint { timestamp: 20_000, lat: 0.001, lng: 0.001 },
+            ],
+        };
+        let segments = trajectory.segments();
+        assert_eq!(segments.len(), 2);
+        assert!((segments[0].speed - 11.12).abs() < 0.01);
+        assert!((segments[0].heading - 90.0).abs() < 1e-6);
+        assert!(segments[1].heading.abs() < 1e-6);
+
+        let feature = trajectory.geo_json_feature();
+        assert_eq!(feature["geometry"]["type"], "LineString");
+        assert_eq!(feature["geometry"]["coordinates"][1], json!([0.001, 0.0]));
+        assert_eq!(feature["properties"]["duration"], 20_000);
+    }
+
+    #[test]
+    fn test_cluster_points() {
+        let points = vec![
+            GeoPoint2D::with_cord("a".to_owned(), 5.6037, -0.1870),
+            GeoPoint2D::with_cord("b".to_owned(), 5.6137, -0.1970),
+            GeoPoint2D::with_cord("c".to_owned(), 6.6885, -1.6244),
+        ];
+        let clusters = cluster_points(points.iter(), zoom_precision(6), true);
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].count, 2);
+        assert!((clusters[0].lat - 5.6087).abs() < 1e-9);
+        assert_eq!(clusters[0].bounds, [-0.1970, 5.6037, -0.1870, 5.6137]);
+        assert_eq!(clusters[0].member, None);
+        assert_eq!(clusters[1].member, Some("c".to_owned()));
+
+        let clusters = cluster_points(points.iter(), zoom_precision(0), false);
+        assert_eq!(clusters.len(), 1);
+        assert!(clusters[0].geo_json_feature().get("bbox").is_none());
+    }
+
+    #[test]
+    fn test_sphere_metric() {
+        let origin = GeoPoint2D::with_cord("a".to_owned(), 60.0, 10.0);
+        let east = GeoPoint2D::with_cord("b".to_owned(), 60.0, 10.03);
+        let haversine = util::get_distance(origin.get_cord(), east.get_cord());
+
+        let distance = arc_meters(origin.distance_2(&east.position()));
+        assert!((distance - haversine).abs() < 1e-6);
+        assert!(origin.distance_2(&east.position()) <= chord_2(haversine) + 1e-15);
+        assert!(origin.distance_2(&east.position()) > chord_2(haversine * 0.999));
+
+        let envelope = sphere_envelope([-10.0, 170.0], [10.0, 179.0]);
+        assert!(envelope.contains_point(&unit_vector([0.0, 175.0])));
+        assert!(!envelope.contains_point(&unit_vector([0.0, -175.0])));
+    }
+
+    #[test]
+    fn test_geohash_order() {
+        // Accra lies just north of the equator and west of the prime meridian
+        let accra = GeoPoint2D::with_cord("accra".to_owned(), 5.6037, -0.1870);
+        assert!(accra.hash().starts_with("ebz"));
+        assert_eq!(accra.print_json()["geometry"]["coordinates"], json!([-0.1870, 5.6037]));
+    }
+
+    #[test]
+    fn test_geometry_relations() {
+        let area = Geometry::from_geo_json(&json!({
+            "type": "Polygon",
+            "coordinates": [[[0.0, 0.0], [4.0, 0.0], [4.0, 4.0], [0.0, 4.0], [0.0, 0.0]],
+                            [[1.0, 1.0], [2.0, 1.0], [2.0, 2.0], [1.0, 2.0], [1.0, 1.0]]]
+        })).unwrap();
+        let inside = Geometry::from_geo_json(&json!({"type": "LineString", "coordinates": [[2.5, 2.5], [3.5, 3.5]]})).unwrap();
+        let crossing = Geometry::from_geo_json(&json!({"type": "LineString", "coordinates": [[3.0, 3.0], [5.0, 3.0]]})).unwrap();
+        let in_hole = Geometry::Point([1.5, 1.5]);
+
+        assert!(area.relates(SpatialOp::Contains, &inside));
+        assert!(inside.relates(SpatialOp::Within, &area));
+        assert!(area.relates(SpatialOp::Intersects, &crossing));
+        assert!(!area.relates(SpatialOp::Contains, &crossing));
+        assert!(!area.relates(SpatialOp::Intersects, &in_hole));
+
+        // every vertex and edge of the ring is inside the area, but the hole is in it too
+        let around_hole = Geometry::from_geo_json(&json!({
+            "type": "Polygon",
+            "coordinates": [[[0.5, 0.5], [2.5, 0.5], [2.5, 2.5], [0.5, 2.5], [0.5, 0.5]]]
+        })).unwrap();
+        let beside_hole = Geometry::from_geo_json(&json!({
+            "type": "Polygon",
+            "coordinates": [[[2.5, 0.5], [3.5, 0.5], [3.5, 3.5], [2.5, 3.5], [2.5, 0.5]]]
+        })).unwrap();
+        let hole = Geometry::from_geo_json(&json!({
+            "type": "Polygon",
+            "coordinates": [[[1.0, 1.0], [2.0, 1.0], [2.0, 2.0], [1.0, 2.0], [1.0, 1.0]]]
+        })).unwrap();
+        assert!(!area.relates(SpatialOp::Contains, &around_hole));
+        assert!(!around_hole.relates(SpatialOp::Within, &area));
+        assert!(!area.relates(SpatialOp::Contains, &hole));
+        assert!(area.relates(SpatialOp::Contains, &beside_hole));
+        assert!(area.relates(SpatialOp::Intersects, &around_hole));
+        assert_eq!(area.to_geo_json()["coordinates"][0][4], json!([0.0, 0.0]));
+    }
+}
@@ -0,0 +1,873 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use dashmap::DashMap;
+use rstar::{RTree, RTreeObject, PointDistance, Envelope, AABB};
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use sled::Tree;
+use crate::db::{self, Data};
+use crate::error::DatabaseError;
+use crate::geo::{self, GeoPoint2D, GeoShape, GeoMember, Geometry, SpatialOp, Polygon, Scalar, SearchArea, TrackPoint};
+use crate::codec::ClientRequest;
+use crate::network::Context;
+use crate::{storage, util};
+use crate::RAFT;
+use async_raft::raft::ClientWriteRequest;
+use async_raft::State;
+use redis_protocol::prelude::*;
+use tracing::{debug, info, warn};
+
+/// Members of a geo key live in their own sled tree, named with this prefix followed by the key
+const GEO_TREE_PREFIX: &str = "__geo__:";
+/// Position history of tracked members, keyed by member, a zero byte and the big endian timestamp
+const TRACK_TREE_PREFIX: &str = "__geotrack__:";
+/// LineString and Polygon members, keyed by member like the point records
+const SHAPE_TREE_PREFIX: &str = "__geoshape__:";
+const META_TREE: &str = "__meta__";
+const GEO_LAYOUT_KEY: &str = "geo_layout";
+const GEO_LAYOUT_VERSION: u8 = 2;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref GEO_INDEX: DashMap<String, GeoTree> = DashMap::new();
+    static ref EXPIRY_QUEUE: Mutex<ExpiryQueue> = Mutex::new(ExpiryQueue::default());
+}
+
+/// Members with a TTL ordered by expiry, so finding and sweeping expired members costs only as
+/// much as there are of them. Entries are not removed when a member is deleted or re-added,
+/// the sweep checks each due entry against the index instead.
+#[derive(Debug, Default)]
+struct ExpiryQueue {
+    entries: BTreeSet<(u64, String, String)>,
+}
+
+impl ExpiryQueue {
+    fn push(&mut self, key: &str, point: &GeoPoint2D) {
+        if let Some(expires_at) = point.expires_at {
+            self.entries.insert((expires_at, key.to_owned(), point.tag.to_owned()));
+        }
+    }
+    fn is_due(&self, now: u64) -> bool {
+        self.entries.iter().next().map_or(false, |(expires_at, _, _)| *expires_at <= now)
+    }
+    /// Takes the entries due at `now` as key and member
+    fn pop_due(&mut self, now: u64) -> Vec<(String, String)> {
+        let later = self.entries.split_off(&(now.saturating_add(1), String::new(), String::new()));
+        std::mem::replace(&mut self.entries, later).into_iter()
+            .map(|(_, key, tag)| (key, tag))
+            .collect()
+    }
+}
+
+fn expiry_queue() -> std::sync::MutexGuard<'static, ExpiryQueue> {
+    EXPIRY_QUEUE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// In-memory index of a geo key, rebuilt from the member records on startup.
+/// Expired members stay in the index until swept but are hidden from every query.
+#[derive(Debug, Clone)]
+pub struct GeoTree {
+    rtree: RTree<GeoPoint2D>,
+    hash: HashSet<GeoPoint2D>,
+    shapes: RTree<GeoShape>,
+    shape_hash: HashSet<GeoShape>,
+}
+
+impl GeoTree {
+    pub fn new() -> Self {
+        GeoTree {
+            rtree: RTree::new(),
+            hash: HashSet::new(),
+            shapes: RTree::new(),
+            shape_hash: HashSet::new(),
+        }
+    }
+
+    pub fn with_items(items: Vec<GeoPoint2D>) -> Self {
+        GeoTree::with_members(items, vec![])
+    }
+
+    pub fn with_members(items: Vec<GeoPoint2D>, shapes: Vec<GeoShape>) -> Self {
+        let mut btree = HashSet::new();
+        btree.extend(items.iter().map(|i| { i.to_owned() }));
+        let mut shape_hash = HashSet::new();
+        shape_hash.extend(shapes.iter().map(|s| { s.to_owned() }));
+        GeoTree {
+            rtree: RTree::bulk_load(items),
+            hash: btree,
+            shapes: RTree::bulk_load(shapes),
+            shape_hash,
+        }
+    }
+    /// Adds a point, replacing any point or shape with the same tag
+    pub fn insert(&mut self, p: GeoPoint2D) {
+        self.delete(&p.tag);
+        self.hash.insert(p.clone());
+        self.rtree.insert(p);
+    }
+    /// Adds a shape, replacing any point or shape with the same tag
+    pub fn insert_shape(&mut self, s: GeoShape) {
+        self.delete(&s.tag);
+        self.shape_hash.insert(s.clone());
+        self.shapes.insert(s);
+    }
+    /// Removes the point or shape with the given tag
+    pub fn delete(&mut self, tag: &str) -> bool {
+        let point_removed = self.delete_point(tag);
+        let shape_removed = self.delete_shape(tag);
+        point_removed || shape_removed
+    }
+    fn delete_shape(&mut self, tag: &str) -> bool {
+        let saved_shape = match self.get_shape(tag) {
+            None => {
+                return false;
+            }
+            Some(s) => {
+                s.to_owned()
+            }
+        };
+        let r = self.shapes.remove(&saved_shape).is_some();
+        let l = self.shape_hash.remove(&saved_shape);
+        r && l
+    }
+    fn delete_point(&mut self, tag: &str) -> bool {
+        let point = GeoPoint2D::new(tag.to_owned());
+        let saved_point = match self.hash.get(&point) {
+            None => {
+                return false;
+            }
+            Some(s) => {
+                s.to_owned()
+            }
+        };
+
+        let r = match self.rtree.remove(&saved_point) {
+            None => {
+                false
+            }
+            Some(_) => {
+                true
+            }
+        };
+        let l = self.hash.remove(&saved_point);
+        return r && l;
+    }
+    /// Point member `tag` unless it expired at `now`, writes pass the entry time so every replica
+    /// sees the same members
+    pub fn get(&self, tag: &str, now: u64) -> Option<&GeoPoint2D> {
+        let point = GeoPoint2D::new(tag.to_owned());
+        self.hash.get(&point).filter(|p| !p.is_expired(now))
+    }
+    fn has_point(&self, tag: &str) -> bool {
+        self.hash.contains(&GeoPoint2D::new(tag.to_owned()))
+    }
+    pub fn get_shape(&self, tag: &str) -> Option<&GeoShape> {
+        let shape = GeoShape { tag: tag.to_owned(), data: None, geometry: Geometry::Point([0.0, 0.0]) };
+        self.shape_hash.get(&shape)
+    }
+    pub fn get_member(&self, tag: &str, now: u64) -> Option<GeoMember<'_>> {
+        self.get(tag, now).map(GeoMember::Point).or_else(|| self.get_shape(tag).map(GeoMember::Shape))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.hash.is_empty() && self.shape_hash.is_empty()
+    }
+    /// Live points ordered by great circle distance in meters from `origin`
+    pub fn nearest<'a>(&'a self, origin: &[Scalar; 2]) -> impl Iterator<Item=(&'a GeoPoint2D, f64)> {
+        self.nearest_at(origin, util::now_ms())
+    }
+    /// Points not expired at `now` ordered by great circle distance in meters from `origin`
+    pub fn nearest_at<'a>(&'a self, origin: &[Scalar; 2], now: u64) -> impl Iterator<Item=(&'a GeoPoint2D, f64)> {
+        self.rtree.nearest_neighbor_iter_with_distance_2(&geo::unit_vector(*origin))
+            .filter(move |(p, _)| !p.is_expired(now))
+            .map(|(p, chord_2)| (p, geo::arc_meters(chord_2)))
+    }
+    /// Live points at most `radius` meters from `origin`, nearest first
+    pub fn locate_within<'a>(&'a self, origin: &[Scalar; 2], radius: Scalar) -> impl Iterator<Item=(&'a GeoPoint2D, f64)> {
+        self.nearest(origin).take_while(move |(_, dist)| *dist <= radius)
+    }
+    pub fn locate_in_box(&self, min: [f64; 2], max: [f64; 2]) -> impl Iterator<Item=&GeoPoint2D> {
+        let now = util::now_ms();
+        self.rtree.locate_in_envelope(&geo::sphere_envelope(min, max)).filter(move |p| {
+            !p.is_expired(now) && AABB::from_corners(min, max).contains_point(&[p.x_cord(), p.y_cord()])
+        })
+    }
+    pub fn locate_in_polygon<'a>(&'a self, polygon: &'a Polygon) -> impl Iterator<Item=&'a GeoPoint2D> {
+        let now = util::now_ms();
+        let envelope = polygon.envelope();
+        self.rtree.locate_in_envelope(&geo::sphere_envelope(envelope.lower(), envelope.upper())).filter(move |p| {
+            !p.is_expired(now) && polygon.contains_point(&[p.x_cord(), p.y_cord()])
+        })
+    }
+    pub fn iter(self: &Self) -> impl Iterator<Item=&GeoPoint2D>
+    {
+        self.iter_at(util::now_ms())
+    }
+    pub fn iter_at(&self, now: u64) -> impl Iterator<Item=&GeoPoint2D> {
+        self.hash.iter().filter(move |p| !p.is_expired(now))
+    }
+    /// Pairs of a member of this tree and a member of `other` at most `radius` meters apart,
+    /// a member is never paired with itself when a tree is joined with itself.
+    /// The search boxes of this tree's members are bulk loaded into a tree of their own and
+    /// traversed together with `other`, so only overlapping nodes are visited. Members expired at `now` are left out.
+    pub fn join_within<'a>(&'a self, other: &'a GeoTree, radius: Scalar, now: u64) -> Vec<(&'a GeoPoint2D, &'a GeoPoint2D, f64)> {
+        let self_join = std::ptr::eq(self, other);
+        let areas: RTree<SearchArea<'a>> = RTree::bulk_load(self.iter_at(now).map(|p| SearchArea::new(p, radius)).collect());
+        areas.intersection_candidates_with_other_tree(&other.rtree)
+            .filter(|(_, p)| !p.is_expired(now))
+            // the iterator borrows both trees only as long as `areas` lives, so the right member
+            // is looked up again to hand out a reference that lives as long as `other`
+            .filter_map(|(a, p)| other.hash.get(p).map(|p| (a.point, p)))
+            .filter(|(l, r)| !(self_join && l.tag == r.tag))
+            .map(|(l, r)| (l, r, geo::arc_meters(l.distance_2(&r.position()))))
+            .filter(|(_, _, dist)| *dist <= radius)
+            .collect()
+    }
+    /// The `k` members of `other` nearest to each member of this tree, optionally no further than `max_dist` meters.
+    /// Members expired at `now` are left out.
+    pub fn join_nearest<'a>(&'a self, other: &'a GeoTree, k: usize, max_dist: Option<Scalar>, now: u64) -> Vec<(&'a GeoPoint2D, &'a GeoPoint2D, f64)> {
+        let self_join = std::ptr::eq(self, other);
+        let mut pairs = vec![];
+        for point in self.iter_at(now) {
+            pairs.extend(other.nearest_at(&[point.x_cord(), point.y_cord()], now)
+                .filter(|(p, _)| !(self_join && p.tag == point.tag))
+                .take_while(|(_, dist)| max_dist.map_or(true, |m| *dist <= m))
+                .take(k)
+                .map(|(p, dist)| (point, p, dist)));
+        }
+        pairs
+    }
+    /// Points and shapes in the given spatial relation to `geometry`
+    pub fn query(&self, op: SpatialOp, geometry: &Geometry) -> Vec<GeoMember<'_>> {
+        let now = util::now_ms();
+        let envelope = geometry.envelope();
+        let points = self.rtree.locate_in_envelope(&geo::sphere_envelope(envelope.lower(), envelope.upper()))
+            .filter(|p| !p.is_expired(now))
+            .filter(|p| Geometry::Point([p.x_cord(), p.y_cord()]).relates(op, geometry))
+            .map(GeoMember::Point);
+        let shapes = self.shapes.locate_in_envelope_intersecting(&envelope)
+            .filter(|s| s.geometry.relates(op, geometry))
+            .map(GeoMember::Shape);
+        points.chain(shapes).collect()
+    }
+    /// Every live point followed by every shape
+    pub fn members(&self) -> impl Iterator<Item=GeoMember<'_>> {
+        self.iter().map(GeoMember::Point).chain(self.shape_hash.iter().map(GeoMember::Shape))
+    }
+}
+
+/// Whole tree value geo keys held before members got their own records, only read to migrate
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LegacyGeoTree {
+    rtree: RTree<LegacyGeoPoint>,
+    hash: HashSet<LegacyGeoPoint>,
+}
+
+/// Member layout of `LegacyGeoTree`, frozen so `GeoPoint2D` can gain fields
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LegacyGeoPoint {
+    tag: String,
+    data: Option<String>,
+    x_cord: f64,
+    y_cord: f64,
+    hash: String,
+}
+
+impl LegacyGeoPoint {
+    fn upgrade(&self) -> GeoPoint2D {
+        let mut point = GeoPoint2D::with_cord(self.tag.to_owned(), self.x_cord, self.y_cord);
+        point.data = self.data.as_ref().and_then(|d| serde_json::from_str(d).ok());
+        point
+    }
+}
+
+impl RTreeObject for LegacyGeoPoint {
+    type Envelope = AABB<[Scalar; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_point([self.x_cord, self.y_cord])
+    }
+}
+
+impl PartialEq for LegacyGeoPoint {
+    fn eq(&self, other: &Self) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl Eq for LegacyGeoPoint {}
+
+impl Hash for LegacyGeoPoint {
+    fn hash<H: Hasher>(&self, hasher: &mut H) {
+        self.tag.hash(hasher)
+    }
+}
+
+fn tree_name(key: &str) -> String {
+    format!("{}{}", GEO_TREE_PREFIX, key)
+}
+
+fn track_tree_name(key: &str) -> String {
+    format!("{}{}", TRACK_TREE_PREFIX, key)
+}
+
+fn track_prefix(member: &str) -> Vec<u8> {
+    let mut prefix = member.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+fn track_key(member: &str, timestamp: u64) -> Vec<u8> {
+    let mut key = track_prefix(member);
+    key.extend_from_slice(&timestamp.to_be_bytes());
+    key
+}
+
+fn shape_tree_name(key: &str) -> String {
+    format!("{}{}", SHAPE_TREE_PREFIX, key)
+}
+
+fn encode<T: Serialize>(record: &T) -> Result<Vec<u8>, DatabaseError> {
+    rmp_serde::to_vec_named(record).map_err(DatabaseError::from)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Option<T> {
+    rmp_serde::from_slice(bytes).ok()
+}
+
+fn wrong_type() -> DatabaseError {
+    DatabaseError::new("WRONGTYPE Operation against a key holding the wrong kind of value")
+}
+
+fn open_tree(key: &str) -> Result<Tree, DatabaseError> {
+    db::get_db().open_tree(tree_name(key)).map_err(DatabaseError::from)
+}
+
+fn open_shape_tree(key: &str) -> Result<Tree, DatabaseError> {
+    db::get_db().open_tree(shape_tree_name(key)).map_err(DatabaseError::from)
+}
+
+/// Writes the `GeoSet` marker under the key if it is absent, fails if the key holds another type
+fn ensure_marker(key: &str) -> Result<(), DatabaseError> {
+    let db = db::get_db();
+    match db.get(key.as_bytes())? {
+        None => {
+            let v = bincode::serialize(&Data::GeoSet).unwrap();
+            db.insert(key.as_bytes(), v)?;
+            Ok(())
+        }
+        Some(bytes) => {
+            match Data::from_vec(&bytes) {
+                Ok(Data::GeoSet) => Ok(()),
+                _ => Err(wrong_type())
+            }
+        }
+    }
+}
+
+/// Runs `f` against the index of a geo key, `None` if the key holds no geo members
+pub fn read<R, F>(key: &str, f: F) -> Option<R>
+    where F: FnOnce(&GeoTree) -> R {
+    GEO_INDEX.get(key).map(|t| f(&t))
+}
+
+/// Runs `f` against the indexes of two geo keys, which may be the same key
+pub fn read_pair<R, F>(left: &str, right: &str, f: F) -> Option<R>
+    where F: FnOnce(&GeoTree, &GeoTree) -> R {
+    let left_tree = GEO_INDEX.get(left)?;
+    if left == right {
+        return Some(f(&left_tree, &left_tree));
+    }
+    let right_tree = GEO_INDEX.get(right)?;
+    Some(f(&left_tree, &right_tree))
+}
+
+/// Adds or moves members, a member added without data keeps its current attributes
+pub fn insert(key: &str, points: Vec<GeoPoint2D>) -> Result<(), DatabaseError> {
+    ensure_marker(key)?;
+    let tree = open_tree(key)?;
+    let mut index = GEO_INDEX.entry(key.to_owned()).or_insert_with(GeoTree::new);
+
+    let mut batch = sled::Batch::default();
+    let mut shape_batch = sled::Batch::default();
+    let mut replaces_shape = false;
+    let mut points = points;
+    for point in points.iter_mut() {
+        if point.data.is_none() {
+            point.data = index.get_member(&point.tag, storage::entry_time()).and_then(|m| m.data().clone());
+        }
+        if index.get_shape(&point.tag).is_some() {
+            shape_batch.remove(point.tag.as_bytes());
+            replaces_shape = true;
+        }
+        batch.insert(point.tag.as_bytes(), encode(point)?);
+    }
+    tree.apply_batch(batch)?;
+    if replaces_shape {
+        open_shape_tree(key)?.apply_batch(shape_batch)?;
+    }
+    let mut queue = expiry_queue();
+    points.into_iter().for_each(|p| {
+        queue.push(key, &p);
+        index.insert(p)
+    });
+    Ok(())
+}
+
+/// Adds or replaces LineString and Polygon members, a shape added without data keeps the current attributes
+pub fn insert_shapes(key: &str, shapes: Vec<GeoShape>) -> Result<(), DatabaseError> {
+    ensure_marker(key)?;
+    let shape_tree = open_shape_tree(key)?;
+    let mut index = GEO_INDEX.entry(key.to_owned()).or_insert_with(GeoTree::new);
+
+    let mut batch = sled::Batch::default();
+    let mut shape_batch = sled::Batch::default();
+    let mut shapes = shapes;
+    for shape in shapes.iter_mut() {
+        if shape.data.is_none() {
+            shape.data = index.get_member(&shape.tag, storage::entry_time()).and_then(|m| m.data().clone());
+        }
+        if index.has_point(&shape.tag) {
+            batch.remove(shape.tag.as_bytes());
+        }
+        shape_batch.insert(shape.tag.as_bytes(), encode(shape)?);
+    }
+    shape_tree.apply_batch(shape_batch)?;
+    open_tree(key)?.apply_batch(batch)?;
+    shapes.into_iter().for_each(|s| index.insert_shape(s));
+    Ok(())
+}
+
+/// Adds many members at once, the index is bulk loaded instead of growing one insert at a time
+pub fn bulk_insert(key: &str, points: Vec<GeoPoint2D>, shapes: Vec<GeoShape>) -> Result<(), DatabaseError> {
+    ensure_marker(key)?;
+    let tree = open_tree(key)?;
+    let shape_tree = open_shape_tree(key)?;
+
+    // a tag names either a point or a shape, so each record clears its counterpart in the other tree
+    let mut batch = sled::Batch::default();
+    let mut shape_batch = sled::Batch::default();
+    for point in &points {
+        batch.insert(point.tag.as_bytes(), encode(point)?);
+        shape_batch.remove(point.tag.as_bytes());
+    }
+    for shape in &shapes {
+        shape_batch.insert(shape.tag.as_bytes(), encode(shape)?);
+        batch.remove(shape.tag.as_bytes());
+    }
+    tree.apply_batch(batch)?;
+    shape_tree.apply_batch(shape_batch)?;
+
+    let mut point_members: HashMap<String, GeoPoint2D> = HashMap::new();
+    let mut shape_members: HashMap<String, GeoShape> = HashMap::new();
+    if let Some(t) = GEO_INDEX.get(key) {
+        point_members.extend(t.hash.iter().map(|p| (p.tag.to_owned(), p.to_owned())));
+        shape_members.extend(t.shape_hash.iter().map(|s| (s.tag.to_owned(), s.to_owned())));
+    }
+    for point in points {
+        expiry_queue().push(key, &point);
+        shape_members.remove(&point.tag);
+        point_members.insert(point.tag.to_owned(), point);
+    }
+    for shape in shapes {
+        point_members.remove(&shape.tag);
+        shape_members.insert(shape.tag.to_owned(), shape);
+    }
+    let geo_tree = GeoTree::with_members(point_members.into_iter().map(|(_, p)| p).collect(),
+                                         shape_members.into_iter().map(|(_, s)| s).collect());
+    GEO_INDEX.insert(key.to_owned(), geo_tree);
+    Ok(())
+}
+
+/// Replaces the attributes of a member with `f(old)`, returns false if the member does not exist
+pub fn update_data<F>(key: &str, member: &str, f: F) -> Result<bool, DatabaseError>
+    where F: FnOnce(Option<Value>) -> Value {
+    let mut index = match GEO_INDEX.get_mut(key) {
+        None => {
+            return Ok(false);
+        }
+        Some(t) => t
+    };
+    if let Some(p) = index.get(member, storage::entry_time()) {
+        let mut point = p.to_owned();
+        point.data = Some(f(point.data.take()));
+        open_tree(key)?.insert(member.as_bytes(), encode(&point)?)?;
+        index.insert(point);
+        return Ok(true);
+    }
+    if let Some(s) = index.get_shape(member) {
+        let mut shape = s.to_owned();
+        shape.data = Some(f(shape.data.take()));
+        open_shape_tree(key)?.insert(member.as_bytes(), encode(&shape)?)?;
+        index.insert_shape(shape);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Appends the current positions of `points` to their history
+pub fn record_track(key: &str, points: &[GeoPoint2D], timestamp: u64) -> Result<(), DatabaseError> {
+    let tree = db::get_db().open_tree(track_tree_name(key))?;
+    let mut batch = sled::Batch::default();
+    for point in points {
+        let track_point = TrackPoint { timestamp, lat: point.x_cord(), lng: point.y_cord() };
+        batch.insert(track_key(&point.tag, timestamp), encode(&track_point)?);
+    }
+    tree.apply_batch(batch).map_err(DatabaseError::from)
+}
+
+/// Recorded positions of a member between `from` and `to` inclusive, oldest first
+pub fn track(key: &str, member: &str, from: u64, to: u64) -> Result<Vec<TrackPoint>, DatabaseError> {
+    let tree = db::get_db().open_tree(track_tree_name(key))?;
+    let mut points: Vec<TrackPoint> = vec![];
+    for r in tree.range(track_key(member, from)..=track_key(member, to)) {
+        let (_, v) = r?;
+        if let Some(p) = decode(&v) {
+            points.push(p);
+        }
+    }
+    Ok(points)
+}
+
+/// Removes members and their history, returns how many existed. The key goes away with its last member
+pub fn remove(key: &str, members: &[String]) -> Result<i64, DatabaseError> {
+    remove_members(key, members, true)
+}
+
+/// Removes members, their history goes too when `with_history` is set. An expired member keeps its
+/// history, which stays readable with `GEOTRACK` after the key lost its last member.
+fn remove_members(key: &str, members: &[String], with_history: bool) -> Result<i64, DatabaseError> {
+    let mut rm_count: i64 = 0;
+    let is_empty = {
+        let mut index = match GEO_INDEX.get_mut(key) {
+            None => {
+                return Ok(0);
+            }
+            Some(t) => t
+        };
+        let tree = open_tree(key)?;
+        let track_tree = db::get_db().open_tree(track_tree_name(key))?;
+        let mut batch = sled::Batch::default();
+        let mut shape_batch = sled::Batch::default();
+        let mut track_batch = sled::Batch::default();
+        let has_shapes = !index.shape_hash.is_empty();
+        for member in members {
+            if index.delete(member) {
+                batch.remove(member.as_bytes());
+                shape_batch.remove(member.as_bytes());
+                if with_history {
+                    for k in track_tree.scan_prefix(track_prefix(member)).keys() {
+                        track_batch.remove(k?);
+                    }
+                }
+                rm_count += 1;
+            }
+        }
+        tree.apply_batch(batch)?;
+        if has_shapes {
+            open_shape_tree(key)?.apply_batch(shape_batch)?;
+        }
+        track_tree.apply_batch(track_batch)?;
+        index.is_empty()
+    };
+    // history of members that expired earlier stays until the key is deleted
+    if is_empty && drop_trees(key, false)? {
+        db::get_db().remove(key.as_bytes())?;
+        let track_tree = db::get_db().open_tree(track_tree_name(key))?;
+        if track_tree.is_empty() {
+            db::get_db().drop_tree(track_tree_name(key).as_bytes())?;
+        }
+    }
+    Ok(rm_count)
+}
+
+/// Deletes the member records, history and index of a geo key, returns false if the key was not a geo key.
+/// The marker under the key itself is left to the caller, which may be overwriting it.
+pub fn drop_members(key: &str) -> Result<bool, DatabaseError> {
+    drop_trees(key, true)
+}
+
+fn drop_trees(key: &str, with_history: bool) -> Result<bool, DatabaseError> {
+    if GEO_INDEX.remove(key).is_none() {
+        return Ok(false);
+    }
+    db::get_db().drop_tree(tree_name(key).as_bytes())?;
+    db::get_db().drop_tree(shape_tree_name(key).as_bytes())?;
+    if with_history {
+        db::get_db().drop_tree(track_tree_name(key).as_bytes())?;
+    }
+    Ok(true)
+}
+
+/// Whether positions of any member of `key` were recorded, they outlive the members that expired
+pub fn has_track(key: &str) -> bool {
+    let name = track_tree_name(key);
+    db::get_db().tree_names().iter().any(|n| n.as_ref() == name.as_bytes())
+}
+
+/// Deletes a geo key entirely, returns false if the key was not a geo key
+pub fn drop_key(key: &str) -> Result<bool, DatabaseError> {
+    if !drop_members(key)? {
+        return Ok(false);
+    }
+    db::get_db().remove(key.as_bytes())?;
+    Ok(true)
+}
+
+/// Removes the members that expired at or before `now` from every geo key, returns how many went away.
+/// Their recorded positions are kept.
+pub fn sweep(now: u64) -> Result<i64, DatabaseError> {
+    let mut expired: HashMap<String, Vec<String>> = HashMap::new();
+    for (key, tag) in expiry_queue().pop_due(now) {
+        expired.entry(key).or_insert_with(Vec::new).push(tag);
+    }
+    // members deleted or given a new expiry since they were queued are left alone
+    let expired: Vec<(String, Vec<String>)> = expired.into_iter()
+        .filter_map(|(key, tags)| {
+            let t = GEO_INDEX.get(&key)?;
+            let tags: Vec<String> = tags.into_iter()
+                .filter(|tag| t.hash.get(&GeoPoint2D::new(tag.to_owned())).map_or(false, |p| p.is_expired(now)))
+                .collect();
+            Some((key, tags))
+        })
+        .filter(|(_, tags)| !tags.is_empty())
+        .collect();
+    let mut rm_count: i64 = 0;
+    for (key, tags) in expired {
+        rm_count += remove_members(&key, &tags, false)?;
+    }
+    Ok(rm_count)
+}
+
+fn has_expired(now: u64) -> bool {
+    expiry_queue().is_due(now)
+}
+
+/// Submits `GEOSWEEP` through raft while this node is the leader, so every replica
+/// drops the same expired members
+pub async fn start_sweeper() {
+    tokio::task::spawn(async {
+        let context = Arc::new(RwLock::new(Context {
+            client_addr: "geo-sweeper".to_owned(),
+            auth_is_required: false,
+            auth_key: None,
+            client_authenticated: true,
+            client_auth_key: None,
+        }));
+        loop {
+            tokio::time::delay_for(SWEEP_INTERVAL).await;
+            if RAFT.metrics().borrow().state != State::Leader {
+                continue;
+            }
+            let now = util::now_ms();
+            if !has_expired(now) {
+                continue;
+            }
+            // replicas sweep with the entry's timestamp, which is `now`
+            let frame = Frame::Array(vec![Frame::BulkString(b"GEOSWEEP".to_vec())]);
+            if let Err(e) = RAFT.client_write(ClientWriteRequest::new(ClientRequest { context: context.clone(), frame, timestamp: now })).await {
+                debug!("Geo sweep failed: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Brings stored geo keys up to the current layout, runs once per database
+fn migrate() -> Result<(), DatabaseError> {
+    let meta = db::get_db().open_tree(META_TREE)?;
+    let layout = meta.get(GEO_LAYOUT_KEY)?
+        .and_then(|v| v.first().cloned())
+        .unwrap_or(0);
+    if layout >= GEO_LAYOUT_VERSION {
+        return Ok(());
+    }
+    if layout < 1 {
+        migrate_legacy()?;
+    }
+    if layout < 2 {
+        rehash_members()?;
+    }
+    meta.insert(GEO_LAYOUT_KEY, vec![GEO_LAYOUT_VERSION])?;
+    Ok(())
+}
+
+/// Converts keys holding a whole serialized `GeoTree` into member records
+fn migrate_legacy() -> Result<(), DatabaseError> {
+    let db = db::get_db();
+    let mut migrated = 0;
+    for r in db.iter() {
+        let (k, v) = r?;
+        let legacy = match Data::from_vec(&v) {
+            Ok(Data::GeoTree(t)) => t,
+            _ => continue
+        };
+        let key = String::from_utf8_lossy(&k).to_string();
+        let tree = open_tree(&key)?;
+        let mut batch = sled::Batch::default();
+        for point in legacy.hash.iter() {
+            batch.insert(point.tag.as_bytes(), encode(&point.upgrade())?);
+        }
+        tree.apply_batch(batch)?;
+        db.insert(k, bincode::serialize(&Data::GeoSet).unwrap())?;
+        migrated += 1;
+    }
+    if migrated > 0 {
+        info!("Migrated {} geo keys to member records", migrated);
+    }
+    Ok(())
+}
+
+/// Member records written before layout 2 hold geohashes encoded with latitude and longitude swapped
+fn rehash_members() -> Result<(), DatabaseError> {
+    let db = db::get_db();
+    for name in db.tree_names() {
+        if !name.starts_with(GEO_TREE_PREFIX.as_bytes()) {
+            continue;
+        }
+        let tree = db.open_tree(&name)?;
+        let mut batch = sled::Batch::default();
+        for r in tree.iter() {
+            let (k, v) = r?;
+            if let Some(mut point) = decode::<GeoPoint2D>(&v) {
+                point.update_hash();
+                batch.insert(k, encode(&point)?);
+            }
+        }
+        tree.apply_batch(batch)?;
+    }
+    Ok(())
+}
+
+/// Migrates legacy geo keys and rebuilds the in-memory index from the member records
+pub fn init() -> Result<(), DatabaseError> {
+    migrate()?;
+    let db = db::get_db();
+    GEO_INDEX.clear();
+    *expiry_queue() = ExpiryQueue::default();
+    let mut points: HashMap<String, Vec<GeoPoint2D>> = HashMap::new();
+    let mut shapes: HashMap<String, Vec<GeoShape>> = HashMap::new();
+    for name in db.tree_names() {
+        if name.starts_with(GEO_TREE_PREFIX.as_bytes()) {
+            let key = String::from_utf8_lossy(&name[GEO_TREE_PREFIX.len()..]).to_string();
+            let records = load_records(&db.open_tree(&name)?, &key)?;
+            points.entry(key).or_insert_with(Vec::new).extend(records);
+        } else if name.starts_with(SHAPE_TREE_PREFIX.as_bytes()) {
+            let key = String::from_utf8_lossy(&name[SHAPE_TREE_PREFIX.len()..]).to_string();
+            let records = load_records(&db.open_tree(&name)?, &key)?;
+            shapes.entry(key).or_insert_with(Vec::new).extend(records);
+        }
+    }
+    let keys: HashSet<String> = points.keys().chain(shapes.keys()).cloned().collect();
+    for key in keys {
+        if let Some(points) = points.get(&key) {
+            let mut queue = expiry_queue();
+            points.iter().for_each(|p| queue.push(&key, p));
+        }
+        let geo_tree = GeoTree::with_members(points.remove(&key).unwrap_or_default(),
+                                             shapes.remove(&key).unwrap_or_default());
+        if !geo_tree.is_empty() {
+            GEO_INDEX.insert(key, geo_tree);
+        }
+    }
+    info!("Loaded {} geo keys", GEO_INDEX.len());
+    Ok(())
+}
+
+fn load_records<T: DeserializeOwned>(tree: &Tree, key: &str) -> Result<Vec<T>, DatabaseError> {
+    let mut records = vec![];
+    for r in tree.iter().values() {
+        let bytes = r?;
+        match decode(&bytes) {
+            Some(record) => records.push(record),
+            None => warn!("Skipping corrupt member record in geo key {}", key)
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_member_record_round_trip() {
+        let mut point = GeoPoint2D::with_cord("Accra".to_owned(), 5.6037, -0.1870);
+        point.data = Some(json!({"population": 2270000}));
+        let bytes = encode(&point).unwrap();
+        let decoded: GeoPoint2D = decode(&bytes).unwrap();
+        assert_eq!(decoded.tag, "Accra");
+        assert_eq!(decoded.get_cord(), point.get_cord());
+        assert_eq!(decoded.hash(), point.hash());
+        assert_eq!(decoded.data, point.data);
+    }
+
+    #[test]
+    fn test_geo_tree_nearest() {
+        let tree = GeoTree::with_items(vec![
+            GeoPoint2D::with_cord("a".to_owned(), 0.0, 0.0),
+            GeoPoint2D::with_cord("b".to_owned(), 1.0, 1.0),
+            GeoPoint2D::with_cord("c".to_owned(), 2.0, 2.0),
+        ]);
+        let tags: Vec<&str> = tree.nearest(&[1.9, 1.9]).map(|(p, _)| p.tag.as_str()).collect();
+        assert_eq!(tags, vec!["c", "b", "a"]);
+    }
+
+    #[test]
+    fn test_expired_members_hidden() {
+        let mut gone = GeoPoint2D::with_cord("gone".to_owned(), 0.0, 0.0);
+        gone.expires_at = Some(1);
+        let mut alive = GeoPoint2D::with_cord("alive".to_owned(), 1.0, 1.0);
+        alive.expires_at = Some(util::now_ms() + 60_000);
+        let tree = GeoTree::with_items(vec![gone, alive]);
+
+        assert!(tree.get("gone", util::now_ms()).is_none());
+        assert!(tree.get("alive", util::now_ms()).is_some());
+        assert_eq!(tree.iter().count(), 1);
+        assert_eq!(tree.nearest(&[0.0, 0.0]).count(), 1);
+        assert_eq!(tree.locate_in_box([-1.0, -1.0], [2.0, 2.0]).count(), 1);
+    }
+
+    #[test]
+    fn test_expiry_queue() {
+        let mut queue = ExpiryQueue::default();
+        let point = |tag: &str, expires_at: Option<u64>| {
+            let mut p = GeoPoint2D::with_cord(tag.to_owned(), 0.0, 0.0);
+            p.expires_at = expires_at;
+            p
+        };
+        queue.push("k", &point("late", Some(300)));
+        queue.push("k", &point("early", Some(100)));
+        queue.push("j", &point("edge", Some(200)));
+        queue.push("k", &point("forever", None));
+
+        assert!(!queue.is_due(99));
+        assert!(queue.is_due(100));
+        assert_eq!(queue.pop_due(200), vec![("k".to_owned(), "early".to_owned()), ("j".to_owned(), "edge".to_owned())]);
+        assert!(!queue.is_due(299));
+        assert_eq!(queue.pop_due(u64::max_value()), vec![("k".to_owned(), "late".to_owned())]);
+        assert!(!queue.is_due(u64::max_value()));
+    }
+
+    #[test]
+    fn test_query_points_and_shapes() {
+        let route = GeoShape {
+            tag: "route".to_owned(),
+            data: None,
+            geometry: Geometry::LineString(vec![[0.0, 0.0], [2.0, 2.0]]),
+        };
+        let tree = GeoTree::with_members(vec![
+            GeoPoint2D::with_cord("in".to_owned(), 0.5, 0.5),
+            GeoPoint2D::with_cord("out".to_owned(), 3.0, 3.0),
+        ], vec![route]);
+        let area = Geometry::Polygon(vec![vec![[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]]]);
+
+        let mut tags: Vec<&str> = tree.query(SpatialOp::Intersects, &area).iter().map(|m| m.tag()).collect();
+        tags.sort();
+        assert_eq!(tags, vec!["in", "route"]);
+        let within: Vec<&str> = tree.query(SpatialOp::Within, &area).iter().map(|m| m.tag()).collect();
+        assert_eq!(within, vec!["in"]);
+        assert_eq!(tree.members().count(), 3);
+    }
+}
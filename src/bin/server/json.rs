@@ -0,0 +1,865 @@
+extern crate jsonpath_lib as jsonpath;
+
+use std::collections::HashSet;
+use serde_json::{Map, Value};
+use json_dotpath::DotPaths;
+use crate::json_pack;
+
+/// Applies an RFC 6902 JSON Patch to `doc`. Operations run in order against a copy of the
+/// document, which only replaces `doc` when every operation succeeds.
+pub fn apply_patch(doc: &mut Value, patch: &Value) -> Result<(), String> {
+    let operations = match patch.as_array() {
+        Some(o) => o,
+        None => {
+            return Err("ERR a JSON Patch must be an array of operations".to_owned());
+        }
+    };
+
+    let mut patched = doc.clone();
+    for (i, operation) in operations.iter().enumerate() {
+        let op = operation["op"].as_str().unwrap_or("");
+        if let Err(e) = apply_operation(&mut patched, operation) {
+            return Err(format!("ERR patch operation {} ({}) failed: {}", i, op, e));
+        }
+    }
+    *doc = patched;
+    Ok(())
+}
+
+fn apply_operation(doc: &mut Value, operation: &Value) -> Result<(), String> {
+    let path = member_str(operation, "path")?;
+    match operation["op"].as_str() {
+        Some("add") => add(doc, path, member(operation, "value")?.clone()),
+        Some("remove") => remove(doc, path).map(|_| ()),
+        Some("replace") => {
+            let value = member(operation, "value")?.clone();
+            *pointer_mut(doc, path)? = value;
+            Ok(())
+        }
+        Some("move") => {
+            let from = member_str(operation, "from")?;
+            if from == path {
+                return Ok(());
+            }
+            if path.starts_with(from) && path[from.len()..].starts_with('/') {
+                return Err(format!("can not move {} into one of its children", from));
+            }
+            let value = remove(doc, from)?;
+            add(doc, path, value)
+        }
+        Some("copy") => {
+            let value = pointer(doc, member_str(operation, "from")?)?.clone();
+            add(doc, path, value)
+        }
+        Some("test") => {
+            let value = member(operation, "value")?;
+            if json_eq(pointer(doc, path)?, value) {
+                Ok(())
+            } else {
+                Err(format!("value at {} is not {}", path, value))
+            }
+        }
+        Some(op) => Err(format!("unknown operation {}", op)),
+        None => Err("missing op".to_owned())
+    }
+}
+
+fn member<'a>(operation: &'a Value, name: &str) -> Result<&'a Value, String> {
+    operation.get(name).ok_or(format!("missing {}", name))
+}
+
+fn member_str<'a>(operation: &'a Value, name: &str) -> Result<&'a str, String> {
+    member(operation, name)?.as_str().ok_or(format!("{} must be a JSON Pointer string", name))
+}
+
+fn validate_pointer(path: &str) -> Result<(), String> {
+    if path.is_empty() || path.starts_with('/') {
+        Ok(())
+    } else {
+        Err(format!("invalid JSON Pointer {}", path))
+    }
+}
+
+fn pointer<'a>(doc: &'a Value, path: &str) -> Result<&'a Value, String> {
+    validate_pointer(path)?;
+    doc.pointer(path).ok_or(format!("path {} does not exist", path))
+}
+
+fn pointer_mut<'a>(doc: &'a mut Value, path: &str) -> Result<&'a mut Value, String> {
+    validate_pointer(path)?;
+    doc.pointer_mut(path).ok_or(format!("path {} does not exist", path))
+}
+
+/// Splits a pointer into its parent pointer and the unescaped last reference token
+fn split_pointer(path: &str) -> Result<(&str, String), String> {
+    validate_pointer(path)?;
+    match path.rfind('/') {
+        Some(i) => Ok((&path[..i], path[i + 1..].replace("~1", "/").replace("~0", "~"))),
+        None => Err("the document root has no parent".to_owned())
+    }
+}
+
+/// Array index of a reference token, leading zeros are not allowed
+fn pointer_index(token: &str) -> Option<usize> {
+    if token.is_empty() || (token.len() > 1 && token.starts_with('0')) || !token.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    token.parse().ok()
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    let (parent, token) = split_pointer(path)?;
+    match pointer_mut(doc, parent)? {
+        Value::Object(map) => {
+            map.insert(token, value);
+            Ok(())
+        }
+        Value::Array(items) => {
+            if token == "-" {
+                items.push(value);
+                return Ok(());
+            }
+            match pointer_index(&token) {
+                Some(i) if i <= items.len() => {
+                    items.insert(i, value);
+                    Ok(())
+                }
+                _ => Err(format!("index {} is out of bounds at {}", token, path))
+            }
+        }
+        _ => Err(format!("parent of {} is not a container", path))
+    }
+}
+
+fn remove(doc: &mut Value, path: &str) -> Result<Value, String> {
+    let (parent, token) = split_pointer(path)?;
+    match pointer_mut(doc, parent)? {
+        Value::Object(map) => map.remove(&token).ok_or(format!("path {} does not exist", path)),
+        Value::Array(items) => match pointer_index(&token) {
+            Some(i) if i < items.len() => Ok(items.remove(i)),
+            _ => Err(format!("index {} is out of bounds at {}", token, path))
+        },
+        _ => Err(format!("path {} does not exist", path))
+    }
+}
+
+/// Applies an RFC 7386 JSON Merge Patch to `target`. `null` members of the patch remove the
+/// member from the target, and a patch that is not an object, arrays included, replaces the target.
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(p) => p,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(map) = target {
+        for (k, v) in patch {
+            if v.is_null() {
+                map.remove(k);
+            } else {
+                merge_patch(map.entry(k.to_owned()).or_insert(Value::Null), v);
+            }
+        }
+    }
+}
+
+/// A step from a value to one of its children
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum PathToken {
+    Key(String),
+    Index(usize),
+}
+
+/// Locations of the nodes matched by a JSONPath selector, in document order
+fn select_paths(doc: &Value, selector: &str) -> Result<Vec<Vec<PathToken>>, String> {
+    let matched: HashSet<*const Value> = jsonpath::select(doc, selector)
+        .map_err(|_| "ERR invalid JSONPath selector".to_owned())?
+        .into_iter()
+        .map(|v| v as *const Value)
+        .collect();
+    let mut paths = vec![];
+    if !matched.is_empty() {
+        collect_paths(doc, &matched, &mut vec![], &mut paths);
+    }
+    Ok(paths)
+}
+
+fn collect_paths(node: &Value, matched: &HashSet<*const Value>, current: &mut Vec<PathToken>, paths: &mut Vec<Vec<PathToken>>) {
+    if matched.contains(&(node as *const Value)) {
+        paths.push(current.clone());
+    }
+    match node {
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                current.push(PathToken::Index(i));
+                collect_paths(item, matched, current, paths);
+                current.pop();
+            }
+        }
+        Value::Object(map) => {
+            for (k, v) in map {
+                current.push(PathToken::Key(k.to_owned()));
+                collect_paths(v, matched, current, paths);
+                current.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+fn node_mut<'a>(doc: &'a mut Value, path: &[PathToken]) -> Option<&'a mut Value> {
+    path.iter().try_fold(doc, |node, token| match (node, token) {
+        (Value::Object(map), PathToken::Key(k)) => map.get_mut(k),
+        (Value::Array(items), PathToken::Index(i)) => items.get_mut(*i),
+        _ => None
+    })
+}
+
+/// Replaces every node matched by `selector` with `value`, returns how many nodes were set
+pub fn path_set(doc: &mut Value, selector: &str, value: &Value) -> Result<usize, String> {
+    let paths = select_paths(doc, selector)?;
+    for path in &paths {
+        if let Some(node) = node_mut(doc, path) {
+            *node = value.clone();
+        }
+    }
+    Ok(paths.len())
+}
+
+/// Removes every node matched by `selector`, returns how many nodes were removed.
+/// Nodes inside another removed node are not counted, and removing the root leaves `null`.
+pub fn path_delete(doc: &mut Value, selector: &str) -> Result<usize, String> {
+    let mut paths = select_paths(doc, selector)?;
+    let removed: HashSet<Vec<PathToken>> = paths.iter().cloned().collect();
+    paths.retain(|p| !(0..p.len()).any(|n| removed.contains(&p[..n])));
+    // later siblings go first so removing an array element never shifts one still to be removed
+    paths.sort_by(|a, b| b.cmp(a));
+
+    for path in &paths {
+        let (last, parent) = match path.split_last() {
+            Some(p) => p,
+            None => {
+                *doc = Value::Null;
+                continue;
+            }
+        };
+        match (node_mut(doc, parent), last) {
+            (Some(Value::Object(map)), PathToken::Key(k)) => {
+                map.remove(k);
+            }
+            (Some(Value::Array(items)), PathToken::Index(i)) => {
+                items.remove(*i);
+            }
+            _ => {}
+        }
+    }
+    Ok(paths.len())
+}
+
+/// Adds `by` to every number matched by `selector`, returns how many numbers were changed.
+/// Integers stay integers when `by` is whole and the sum fits, other matches are left alone.
+pub fn path_incr(doc: &mut Value, selector: &str, by: f64) -> Result<usize, String> {
+    let paths = select_paths(doc, selector)?;
+    let mut modified = 0;
+    for path in &paths {
+        let node = match node_mut(doc, path) {
+            Some(n) => n,
+            None => continue
+        };
+        let sum = match node {
+            Value::Number(n) => {
+                let whole = n.as_i64().filter(|_| by.fract() == 0.0 && by.abs() < i64::MAX as f64)
+                    .and_then(|i| i.checked_add(by as i64));
+                match whole {
+                    Some(i) => json!(i),
+                    None => json!(n.as_f64().unwrap_or(0.0) + by)
+                }
+            }
+            _ => continue
+        };
+        if sum.is_null() {
+            return Err("ERR increment would produce NaN or Infinity".to_owned());
+        }
+        *node = sum;
+        modified += 1;
+    }
+    Ok(modified)
+}
+
+/// Dot path of a command argument, `.` addresses the whole document
+fn dot_path(path: &str) -> &str {
+    if path == "." { "" } else { path }
+}
+
+/// Copy of the value at a dot path, `None` when nothing is there
+pub fn get_path(doc: &Value, path: &str) -> Option<Value> {
+    if dot_path(path).is_empty() {
+        return Some(doc.clone());
+    }
+    if let Some(v) = doc.dot_get::<Value>(dot_path(path)).ok().flatten() {
+        return Some(v);
+    }
+    // dot_get reads an explicit null as missing, look it up in the parent instead
+    let (parent, last) = path.rsplit_once('.').unwrap_or((".", path));
+    match get_path(doc, parent)? {
+        Value::Object(map) => map.get(last).cloned(),
+        Value::Array(items) => items.get(last.parse::<usize>().ok()?).cloned(),
+        _ => None
+    }
+}
+
+/// Mutable reference to the value at a dot path, missing parents are created as `null`
+pub fn get_path_mut<'a>(doc: &'a mut Value, path: &str) -> Result<&'a mut Value, String> {
+    if dot_path(path).is_empty() {
+        return Ok(doc);
+    }
+    doc.dot_get_mut(dot_path(path)).map_err(|_| format!("ERR invalid path {}", path))
+}
+
+fn array_mut<'a>(doc: &'a mut Value, path: &str, create: bool) -> Result<&'a mut Vec<Value>, String> {
+    let node = get_path_mut(doc, path)?;
+    if create && node.is_null() {
+        *node = Value::Array(vec![]);
+    }
+    node.as_array_mut().ok_or("ERR path is not an array".to_owned())
+}
+
+fn array(doc: &Value, path: &str) -> Result<Vec<Value>, String> {
+    match get_path(doc, path) {
+        Some(Value::Array(items)) => Ok(items),
+        _ => Err("ERR path is not an array".to_owned())
+    }
+}
+
+/// Offset of a possibly negative index counted from the end, may lie outside the array
+fn offset(index: i64, len: usize) -> i64 {
+    if index < 0 { len as i64 + index } else { index }
+}
+
+/// Appends to the array at `path`, creating it when the path is empty, returns the new length
+pub fn array_append(doc: &mut Value, path: &str, values: &[Value]) -> Result<usize, String> {
+    let items = array_mut(doc, path, true)?;
+    items.extend_from_slice(values);
+    Ok(items.len())
+}
+
+/// Inserts before `index`, which may equal the length to append, returns the new length
+pub fn array_insert(doc: &mut Value, path: &str, index: i64, values: &[Value]) -> Result<usize, String> {
+    let items = array_mut(doc, path, false)?;
+    let at = offset(index, items.len());
+    if at < 0 || at > items.len() as i64 {
+        return Err("ERR index out of range".to_owned());
+    }
+    let tail = items.split_off(at as usize);
+    items.extend_from_slice(values);
+    items.extend(tail);
+    Ok(items.len())
+}
+
+/// Removes and returns the element at `index`, clamped to the array, `None` when the array is empty
+pub fn array_pop(doc: &mut Value, path: &str, index: i64) -> Result<Option<Value>, String> {
+    let items = array_mut(doc, path, false)?;
+    if items.is_empty() {
+        return Ok(None);
+    }
+    let at = offset(index, items.len()).max(0).min(items.len() as i64 - 1);
+    Ok(Some(items.remove(at as usize)))
+}
+
+/// Keeps the elements from `start` to `stop` inclusive, returns the new length
+pub fn array_trim(doc: &mut Value, path: &str, start: i64, stop: i64) -> Result<usize, String> {
+    let items = array_mut(doc, path, false)?;
+    let len = items.len() as i64;
+    let start = offset(start, items.len()).max(0);
+    let stop = offset(stop, items.len()).min(len - 1);
+    if start > stop {
+        items.clear();
+    } else {
+        items.truncate(stop as usize + 1);
+        items.drain(..start as usize);
+    }
+    Ok(items.len())
+}
+
+pub fn array_len(doc: &Value, path: &str) -> Result<usize, String> {
+    array(doc, path).map(|items| items.len())
+}
+
+/// Position of the first element equal to `value` between `start` and `stop` inclusive, or -1
+pub fn array_index(doc: &Value, path: &str, value: &Value, start: i64, stop: Option<i64>) -> Result<i64, String> {
+    let items = array(doc, path)?;
+    let start = offset(start, items.len()).max(0);
+    let stop = stop.map_or(items.len() as i64 - 1, |s| offset(s, items.len()).min(items.len() as i64 - 1));
+    Ok((start..=stop)
+        .find(|i| json_eq(&items[*i as usize], value))
+        .unwrap_or(-1))
+}
+
+/// Type name reported by `JTYPE`, whole numbers are `integer`
+pub fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object"
+    }
+}
+
+pub fn object_keys(doc: &Value, path: &str) -> Result<Vec<String>, String> {
+    match get_path(doc, path) {
+        Some(Value::Object(map)) => Ok(map.keys().cloned().collect()),
+        _ => Err("ERR path is not an object".to_owned())
+    }
+}
+
+pub fn string_len(doc: &Value, path: &str) -> Result<usize, String> {
+    match get_path(doc, path) {
+        Some(Value::String(s)) => Ok(s.chars().count()),
+        _ => Err("ERR path is not a string".to_owned())
+    }
+}
+
+/// Appends to the string at `path`, returns the new length in characters
+pub fn string_append(doc: &mut Value, path: &str, suffix: &str) -> Result<usize, String> {
+    match get_path_mut(doc, path)? {
+        Value::String(s) => {
+            s.push_str(suffix);
+            Ok(s.chars().count())
+        }
+        _ => Err("ERR path is not a string".to_owned())
+    }
+}
+
+/// Flips the boolean at `path`, returns the new value
+pub fn toggle(doc: &mut Value, path: &str) -> Result<bool, String> {
+    match get_path_mut(doc, path)? {
+        Value::Bool(b) => {
+            *b = !*b;
+            Ok(*b)
+        }
+        _ => Err("ERR path is not a boolean".to_owned())
+    }
+}
+/// Strings placed between the tokens of `JGET` output, all empty gives compact json
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JsonFormat {
+    /// Repeated once per nesting level at the start of each member
+    pub indent: String,
+    /// Written before each member and before the closing bracket of a non empty container
+    pub newline: String,
+    /// Written after the colon of an object member
+    pub space: String,
+}
+
+pub fn format(value: &Value, format: &JsonFormat) -> String {
+    if *format == JsonFormat::default() {
+        return value.to_string();
+    }
+    let mut out = String::new();
+    write_formatted(value, format, 0, &mut out);
+    out
+}
+
+fn write_formatted(value: &Value, format: &JsonFormat, depth: usize, out: &mut String) {
+    let (open, close, len) = match value {
+        Value::Array(items) => ('[', ']', items.len()),
+        Value::Object(map) => ('{', '}', map.len()),
+        v => {
+            out.push_str(&v.to_string());
+            return;
+        }
+    };
+    out.push(open);
+    let member = |i: usize, out: &mut String| {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(&format.newline);
+        out.push_str(&format.indent.repeat(depth + 1));
+    };
+    match value {
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                member(i, out);
+                write_formatted(item, format, depth + 1, out);
+            }
+        }
+        Value::Object(map) => {
+            for (i, (k, v)) in map.iter().enumerate() {
+                member(i, out);
+                out.push_str(&Value::String(k.to_owned()).to_string());
+                out.push(':');
+                out.push_str(&format.space);
+                write_formatted(v, format, depth + 1, out);
+            }
+        }
+        _ => {}
+    }
+    if len > 0 {
+        out.push_str(&format.newline);
+        out.push_str(&format.indent.repeat(depth));
+    }
+    out.push(close);
+}
+
+/// Pruned copy of `doc` holding only the members along the dot paths, selected array elements keep
+/// their order but are packed together. Paths that match nothing are left out.
+pub fn project(doc: &Value, paths: &[String]) -> Value {
+    let split: Vec<Vec<String>> = paths.iter().map(|p| split_dot_path(dot_path(p))).collect();
+    let selected: Vec<&[String]> = split.iter().map(|p| p.as_slice()).collect();
+    project_node(doc, &selected).unwrap_or_else(|| match doc {
+        Value::Array(_) => Value::Array(vec![]),
+        _ => Value::Object(Map::new())
+    })
+}
+
+fn split_dot_path(path: &str) -> Vec<String> {
+    let mut segments = vec![];
+    let mut rest = if path.is_empty() { None } else { Some(path) };
+    while let Some(p) = rest {
+        let (segment, sub) = json_pack::split_segment(p);
+        segments.push(segment);
+        rest = sub;
+    }
+    segments
+}
+
+fn project_node(node: &Value, paths: &[&[String]]) -> Option<Value> {
+    if paths.iter().any(|p| p.is_empty()) {
+        return Some(node.clone());
+    }
+    match node {
+        Value::Object(map) => {
+            let mut out = Map::new();
+            for (k, v) in map {
+                let rest: Vec<&[String]> = paths.iter().filter(|p| &p[0] == k).map(|p| &p[1..]).collect();
+                if rest.is_empty() {
+                    continue;
+                }
+                if let Some(child) = project_node(v, &rest) {
+                    out.insert(k.to_owned(), child);
+                }
+            }
+            if out.is_empty() { None } else { Some(Value::Object(out)) }
+        }
+        Value::Array(items) => {
+            let index = |segment: &str| match segment {
+                "<" if !items.is_empty() => Some(0),
+                ">" => items.len().checked_sub(1),
+                i => i.parse::<usize>().ok()
+            };
+            let mut out = vec![];
+            for (i, item) in items.iter().enumerate() {
+                let rest: Vec<&[String]> = paths.iter().filter(|p| index(&p[0]) == Some(i)).map(|p| &p[1..]).collect();
+                if rest.is_empty() {
+                    continue;
+                }
+                if let Some(child) = project_node(item, &rest) {
+                    out.push(child);
+                }
+            }
+            if out.is_empty() { None } else { Some(Value::Array(out)) }
+        }
+        _ => None
+    }
+}
+
+/// Equality for `test`, `JARRINDEX` and schema `enum`, numbers compare by value so `1` equals `1.0`
+pub fn json_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x == y || x.as_f64() == y.as_f64(),
+        (Value::Array(x), Value::Array(y)) => x.len() == y.len() && x.iter().zip(y).all(|(x, y)| json_eq(x, y)),
+        (Value::Object(x), Value::Object(y)) => {
+            x.len() == y.len() && x.iter().all(|(k, v)| y.get(k).map_or(false, |w| json_eq(v, w)))
+        }
+        (x, y) => x == y
+    }
+}
+
+/// One difference between two documents, `old` is missing for an addition and `new` for a removal
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub path: Vec<String>,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+/// Differences turning `from` into `to`, in an order they can be applied in. Members are compared one by
+/// one, arrays element by element with the extra elements added or removed at the end, and any other
+/// change replaces the value.
+pub fn changes(from: &Value, to: &Value) -> Vec<Change> {
+    let mut changes = vec![];
+    diff_node(from, to, &mut vec![], &mut changes);
+    changes
+}
+
+fn diff_node(from: &Value, to: &Value, path: &mut Vec<String>, changes: &mut Vec<Change>) {
+    let at = |path: &Vec<String>, key: String| {
+        let mut p = path.clone();
+        p.push(key);
+        p
+    };
+    match (from, to) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (k, v) in a {
+                match b.get(k) {
+                    Some(w) => {
+                        path.push(k.to_owned());
+                        diff_node(v, w, path, changes);
+                        path.pop();
+                    }
+                    None => changes.push(Change { path: at(path, k.to_owned()), old: Some(v.clone()), new: None })
+                }
+            }
+            for (k, w) in b {
+                if !a.contains_key(k) {
+                    changes.push(Change { path: at(path, k.to_owned()), old: None, new: Some(w.clone()) });
+                }
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            for (i, (v, w)) in a.iter().zip(b).enumerate() {
+                path.push(i.to_string());
+                diff_node(v, w, path, changes);
+                path.pop();
+            }
+            // removing from the back keeps the indexes of the elements still to remove
+            for i in (b.len()..a.len()).rev() {
+                changes.push(Change { path: at(path, i.to_string()), old: Some(a[i].clone()), new: None });
+            }
+            for (i, w) in b.iter().enumerate().skip(a.len()) {
+                changes.push(Change { path: at(path, i.to_string()), old: None, new: Some(w.clone()) });
+            }
+        }
+        (a, b) if a == b => {}
+        (a, b) => changes.push(Change { path: path.clone(), old: Some(a.clone()), new: Some(b.clone()) })
+    }
+}
+
+/// RFC 6902 patch that turns `from` into `to`
+pub fn diff(from: &Value, to: &Value) -> Value {
+    let operations = changes(from, to).into_iter()
+        .map(|c| {
+            let path: String = c.path.iter()
+                .map(|t| format!("/{}", t.replace('~', "~0").replace('/', "~1")))
+                .collect();
+            match (c.old, c.new) {
+                (Some(_), Some(value)) => json!({"op": "replace", "path": path, "value": value}),
+                (None, Some(value)) => json!({"op": "add", "path": path, "value": value}),
+                _ => json!({"op": "remove", "path": path})
+            }
+        })
+        .collect();
+    Value::Array(operations)
+}
+
+/// Readable form of the differences, one line per change naming its dot path: `+ path: new`,
+/// `- path: old` or `~ path: old -> new`
+pub fn diff_paths(from: &Value, to: &Value) -> Vec<String> {
+    changes(from, to).into_iter()
+        .map(|c| {
+            let path = if c.path.is_empty() {
+                ".".to_owned()
+            } else {
+                c.path.iter()
+                    .map(|t| t.replace('\\', "\\\\").replace('.', "\\."))
+                    .collect::<Vec<String>>()
+                    .join(".")
+            };
+            match (c.old, c.new) {
+                (Some(old), Some(new)) => format!("~ {}: {} -> {}", path, old, new),
+                (None, Some(new)) => format!("+ {}: {}", path, new),
+                (old, None) => format!("- {}: {}", path, old.unwrap_or_default())
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_patch() {
+        let mut doc = json!({"biscuits": [{"name": "Digestive"}, {"name": "Choco Leibniz"}], "a/b": 1});
+        let patch = json!([
+            {"op": "add", "path": "/biscuits/1", "value": {"name": "Ginger Nut"}},
+            {"op": "remove", "path": "/biscuits/0"},
+            {"op": "replace", "path": "/a~1b", "value": 2.0},
+            {"op": "test", "path": "/a~1b", "value": 2},
+            {"op": "copy", "from": "/biscuits/0", "path": "/best_biscuit"},
+            {"op": "move", "from": "/biscuits", "path": "/cookies"},
+            {"op": "add", "path": "/cookies/-", "value": {"name": "Oreo"}}
+        ]);
+        apply_patch(&mut doc, &patch).unwrap();
+        assert_eq!(doc, json!({
+            "cookies": [{"name": "Ginger Nut"}, {"name": "Choco Leibniz"}, {"name": "Oreo"}],
+            "best_biscuit": {"name": "Ginger Nut"},
+            "a/b": 2.0
+        }));
+    }
+
+    #[test]
+    fn test_diff() {
+        let from = json!({"name": "api", "replicas": 2, "env": {"a/b": 1, "old": true}, "ports": [80, 443, 8080], "tags": ["x"]});
+        let to = json!({"name": "api", "replicas": 3, "env": {"a/b": 2}, "ports": [80], "tags": ["x", "y", "z"], "new": null});
+        let patch = diff(&from, &to);
+        assert_eq!(patch, json!([
+            {"op": "replace", "path": "/replicas", "value": 3},
+            {"op": "replace", "path": "/env/a~1b", "value": 2},
+            {"op": "remove", "path": "/env/old"},
+            {"op": "remove", "path": "/ports/2"},
+            {"op": "remove", "path": "/ports/1"},
+            {"op": "add", "path": "/tags/1", "value": "y"},
+            {"op": "add", "path": "/tags/2", "value": "z"},
+            {"op": "add", "path": "/new", "value": null}
+        ]));
+        let mut doc = from.clone();
+        apply_patch(&mut doc, &patch).unwrap();
+        assert_eq!(doc, to);
+
+        assert_eq!(diff(&to, &to), json!([]));
+        assert_eq!(diff(&json!([1]), &json!({"a": 1})), json!([{"op": "replace", "path": "", "value": {"a": 1}}]));
+
+        assert_eq!(diff_paths(&from, &to), vec![
+            "~ replicas: 2 -> 3",
+            "~ env.a/b: 1 -> 2",
+            "- env.old: true",
+            "- ports.2: 8080",
+            "- ports.1: 443",
+            "+ tags.1: \"y\"",
+            "+ tags.2: \"z\"",
+            "+ new: null",
+        ]);
+        assert_eq!(diff_paths(&json!({"a.b": 1}), &json!({"a.b": 2})), vec!["~ a\\.b: 1 -> 2"]);
+        assert_eq!(diff_paths(&json!(1), &json!("1")), vec!["~ .: 1 -> \"1\""]);
+    }
+
+    #[test]
+    fn test_failed_patch_is_not_applied() {
+        let mut doc = json!({"a": [1, 2]});
+        let patch = json!([
+            {"op": "add", "path": "/b", "value": true},
+            {"op": "test", "path": "/a/0", "value": 5}
+        ]);
+        let e = apply_patch(&mut doc, &patch).unwrap_err();
+        assert!(e.starts_with("ERR patch operation 1 (test) failed"));
+        assert_eq!(doc, json!({"a": [1, 2]}));
+
+        assert!(apply_patch(&mut doc, &json!([{"op": "remove", "path": "/a/02"}])).is_err());
+        assert!(apply_patch(&mut doc, &json!([{"op": "move", "from": "/a", "path": "/a/0"}])).is_err());
+        assert!(apply_patch(&mut doc, &json!([{"op": "add", "path": "/a/3", "value": 1}])).is_err());
+        assert!(apply_patch(&mut doc, &json!({"op": "add"})).is_err());
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let mut doc = json!({"title": "Goodbye!", "author": {"givenName": "John", "familyName": "Doe"}, "tags": ["example", "sample"], "content": "This will be unchanged"});
+        let patch = json!({"title": "Hello!", "phoneNumber": "+01-123-456-7890", "author": {"familyName": null}, "tags": ["example"]});
+        merge_patch(&mut doc, &patch);
+        assert_eq!(doc, json!({"title": "Hello!", "author": {"givenName": "John"}, "tags": ["example"], "content": "This will be unchanged", "phoneNumber": "+01-123-456-7890"}));
+
+        let mut doc = json!({"a": ["b"]});
+        merge_patch(&mut doc, &json!({"a": {"b": "c", "d": null}}));
+        assert_eq!(doc, json!({"a": {"b": "c"}}));
+
+        let mut doc = Value::Null;
+        merge_patch(&mut doc, &json!({"a": {"bb": {"ccc": null}}}));
+        assert_eq!(doc, json!({"a": {"bb": {}}}));
+
+        let mut doc = json!({"a": "b"});
+        merge_patch(&mut doc, &json!(["c"]));
+        assert_eq!(doc, json!(["c"]));
+    }
+
+    #[test]
+    fn test_path_writes() {
+        let todos = json!({"todos": [
+            {"title": "a", "completed": false, "priority": 1},
+            {"title": "b", "completed": true, "priority": 2},
+            {"title": "c", "completed": false, "priority": 3}
+        ]});
+
+        let mut doc = todos.clone();
+        assert_eq!(path_incr(&mut doc, "$.todos[?(@.completed==false)].priority", 10.0), Ok(2));
+        assert_eq!(doc["todos"][0]["priority"], json!(11));
+        assert_eq!(doc["todos"][1]["priority"], json!(2));
+        assert_eq!(path_incr(&mut doc, "$.todos[*].title", 1.0), Ok(0));
+        assert_eq!(path_incr(&mut doc, "$.todos[0].priority", 0.5), Ok(1));
+        assert_eq!(doc["todos"][0]["priority"], json!(11.5));
+
+        let mut doc = todos.clone();
+        assert_eq!(path_set(&mut doc, "$.todos[?(@.completed==true)].completed", &json!(false)), Ok(1));
+        assert_eq!(doc["todos"][1]["completed"], json!(false));
+
+        let mut doc = todos.clone();
+        assert_eq!(path_delete(&mut doc, "$.todos[?(@.completed==false)]"), Ok(2));
+        assert_eq!(doc, json!({"todos": [{"title": "b", "completed": true, "priority": 2}]}));
+        assert_eq!(path_delete(&mut doc, "$..priority"), Ok(1));
+        assert_eq!(path_delete(&mut doc, "$.missing"), Ok(0));
+        assert!(path_delete(&mut doc, "$[").is_err());
+    }
+
+    #[test]
+    fn test_array_commands() {
+        let mut doc = json!({"list": [1, 2, 3]});
+        assert_eq!(array_append(&mut doc, "list", &[json!(4)]), Ok(4));
+        assert_eq!(array_append(&mut doc, "new", &[json!("a")]), Ok(1));
+        assert_eq!(array_insert(&mut doc, "list", -1, &[json!("x"), json!("y")]), Ok(6));
+        assert_eq!(doc["list"], json!([1, 2, 3, "x", "y", 4]));
+        assert!(array_insert(&mut doc, "list", 7, &[json!(0)]).is_err());
+        assert_eq!(array_index(&doc, "list", &json!(4.0), 0, None), Ok(5));
+        assert_eq!(array_index(&doc, "list", &json!(1), 1, Some(-1)), Ok(-1));
+        assert_eq!(array_pop(&mut doc, "list", -1), Ok(Some(json!(4))));
+        assert_eq!(array_pop(&mut doc, "list", 100), Ok(Some(json!("y"))));
+        assert_eq!(array_trim(&mut doc, "list", 1, -2), Ok(2));
+        assert_eq!(doc["list"], json!([2, 3]));
+        assert_eq!(array_trim(&mut doc, "list", 5, 10), Ok(0));
+        assert_eq!(array_pop(&mut doc, "list", 0), Ok(None));
+        assert_eq!(array_len(&doc, "new"), Ok(1));
+        assert!(array_len(&doc, "missing").is_err());
+        assert!(array_append(&mut json!({"a": 1}), "a", &[json!(1)]).is_err());
+        assert_eq!(array_len(&json!([1, 2]), "."), Ok(2));
+    }
+
+    #[test]
+    fn test_introspection() {
+        let mut doc = json!({"name": "kofi", "age": 30, "score": 1.5, "admin": false, "tags": [], "none": null});
+        let types: Vec<Option<&str>> = ["name", "age", "score", "admin", "tags", "none", ".", "missing"].iter()
+            .map(|p| get_path(&doc, p).as_ref().map(type_name))
+            .collect();
+        assert_eq!(types, vec![Some("string"), Some("integer"), Some("number"), Some("boolean"), Some("array"), Some("null"), Some("object"), None]);
+        assert_eq!(object_keys(&doc, ".").unwrap().len(), 6);
+        assert!(object_keys(&doc, "name").is_err());
+        assert_eq!(string_len(&doc, "name"), Ok(4));
+        assert_eq!(string_append(&mut doc, "name", "é"), Ok(5));
+        assert_eq!(doc["name"], json!("kofié"));
+        assert_eq!(toggle(&mut doc, "admin"), Ok(true));
+        assert!(toggle(&mut doc, "age").is_err());
+    }
+
+    #[test]
+    fn test_format_and_project() {
+        let doc = json!({"a": 1, "b": [true, {"c": "x"}], "d": {}});
+        assert_eq!(format(&doc, &JsonFormat::default()), r#"{"a":1,"b":[true,{"c":"x"}],"d":{}}"#);
+        let pretty = JsonFormat { indent: "\t".to_owned(), newline: "\n".to_owned(), space: " ".to_owned() };
+        assert_eq!(format(&doc, &pretty), "{\n\t\"a\": 1,\n\t\"b\": [\n\t\ttrue,\n\t\t{\n\t\t\t\"c\": \"x\"\n\t\t}\n\t],\n\t\"d\": {}\n}");
+
+        let paths = |p: &[&str]| p.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(project(&doc, &paths(&["a", "b.>.c", "missing"])), json!({"a": 1, "b": [{"c": "x"}]}));
+        assert_eq!(project(&doc, &paths(&["b", "b.0"])), json!({"b": [true, {"c": "x"}]}));
+        assert_eq!(project(&doc, &paths(&["."])), doc);
+        assert_eq!(project(&doc, &paths(&["x.y"])), json!({}));
+    }
+}
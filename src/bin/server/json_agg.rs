@@ -0,0 +1,216 @@
+extern crate jsonpath_lib as jsonpath;
+
+use std::collections::BTreeMap;
+use serde_json::{Number, Value};
+use jsonpath::JsonPathError;
+use crate::db::{self, Data};
+use crate::json_pack;
+use crate::util;
+
+/// Aggregate function of `JAGG`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggOp {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl AggOp {
+    pub fn from_name(name: &str) -> Option<AggOp> {
+        match name.to_lowercase().as_str() {
+            "count" => Some(AggOp::Count),
+            "sum" => Some(AggOp::Sum),
+            "avg" => Some(AggOp::Avg),
+            "min" => Some(AggOp::Min),
+            "max" => Some(AggOp::Max),
+            _ => None
+        }
+    }
+}
+
+/// Query of `JAGG`, a `COUNT` without a selector counts documents
+#[derive(Debug, Clone, Default)]
+pub struct AggQuery {
+    pub pattern: String,
+    pub group_by: Option<String>,
+    pub aggregates: Vec<(AggOp, Option<String>)>,
+}
+
+/// One row of an aggregation, `group` is `None` when the query has no `GROUPBY`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggRow {
+    pub group: Option<Value>,
+    pub values: Vec<Value>,
+}
+
+type Selector = Box<dyn for<'a> FnMut(&'a Value) -> Result<Vec<&'a Value>, JsonPathError>>;
+
+fn selector(path: &str) -> Result<Selector, String> {
+    let mut select = jsonpath::compile(path);
+    if select(&Value::Null).is_err() {
+        return Err("ERR invalid JSONPath selector".to_owned());
+    }
+    Ok(Box::new(select))
+}
+
+/// Running state of one aggregate, `matches` counts every non null value and `count` only the numbers.
+/// Integers are summed exactly until a float or an overflow shows up.
+#[derive(Debug, Clone)]
+struct Accumulator {
+    matches: u64,
+    count: u64,
+    sum: f64,
+    int_sum: Option<i64>,
+    min: Option<(f64, Value)>,
+    max: Option<(f64, Value)>,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Accumulator { matches: 0, count: 0, sum: 0.0, int_sum: Some(0), min: None, max: None }
+    }
+}
+
+impl Accumulator {
+    fn add(&mut self, value: &Value) {
+        if value.is_null() {
+            return;
+        }
+        self.matches += 1;
+        let n = match value.as_f64() {
+            Some(n) => n,
+            None => return
+        };
+        self.count += 1;
+        self.sum += n;
+        self.int_sum = match (self.int_sum, value.as_i64()) {
+            (Some(s), Some(i)) => s.checked_add(i),
+            _ => None
+        };
+        if self.min.as_ref().map_or(true, |(m, _)| n < *m) {
+            self.min = Some((n, value.clone()));
+        }
+        if self.max.as_ref().map_or(true, |(m, _)| n > *m) {
+            self.max = Some((n, value.clone()));
+        }
+    }
+
+    fn result(&self, op: AggOp) -> Value {
+        match op {
+            AggOp::Count => Value::from(self.matches),
+            AggOp::Sum => match self.int_sum {
+                Some(s) => Value::from(s),
+                None => float(self.sum)
+            },
+            AggOp::Avg if self.count == 0 => Value::Null,
+            AggOp::Avg => float(self.sum / self.count as f64),
+            AggOp::Min => self.min.as_ref().map_or(Value::Null, |(_, v)| v.clone()),
+            AggOp::Max => self.max.as_ref().map_or(Value::Null, |(_, v)| v.clone()),
+        }
+    }
+}
+
+fn float(n: f64) -> Value {
+    Number::from_f64(n).map_or(Value::Null, Value::Number)
+}
+
+/// Accumulators of one group, `docs` backs a `COUNT` without a selector
+#[derive(Debug, Clone)]
+struct Group {
+    key: Option<Value>,
+    docs: u64,
+    accumulators: Vec<Accumulator>,
+}
+
+/// Scans the json keys matching `query.pattern` and folds the selected values into grouped aggregates.
+/// Only numbers take part in `SUM`, `AVG`, `MIN` and `MAX`, a document without a group value falls in the null group.
+pub fn run(query: &AggQuery) -> Result<Vec<AggRow>, String> {
+    let pattern = glob::Pattern::new(&query.pattern).map_err(|_| "ERR invalid pattern".to_owned())?;
+    let mut group_by = match &query.group_by {
+        Some(path) => Some(selector(path)?),
+        None => None
+    };
+    let mut selectors = vec![];
+    for (_, path) in &query.aggregates {
+        selectors.push(match path {
+            Some(path) => Some(selector(path)?),
+            None => None
+        });
+    }
+
+    let mut groups: BTreeMap<String, Group> = BTreeMap::new();
+    if group_by.is_none() {
+        groups.insert(String::new(), Group { key: None, docs: 0, accumulators: vec![Accumulator::default(); selectors.len()] });
+    }
+    for r in db::get_db().scan_prefix(util::glob_prefix(&query.pattern)) {
+        let (k, v) = r.map_err(|e| format!("ERR {}", e))?;
+        if !pattern.matches(&String::from_utf8_lossy(&k)) {
+            continue;
+        }
+        let doc = match Data::from_vec(&v) {
+            Ok(Data::Json(b)) => json_pack::unpack(&b),
+            _ => continue
+        };
+        let (name, key) = match group_by.as_mut() {
+            Some(select) => {
+                let key = select(&doc).ok()
+                    .and_then(|m| m.first().cloned().cloned())
+                    .unwrap_or(Value::Null);
+                (key.to_string(), Some(key))
+            }
+            None => (String::new(), None)
+        };
+        let n = selectors.len();
+        let group = groups.entry(name).or_insert_with(|| Group { key, docs: 0, accumulators: vec![Accumulator::default(); n] });
+        group.docs += 1;
+        for (select, acc) in selectors.iter_mut().zip(group.accumulators.iter_mut()) {
+            if let Some(select) = select {
+                for value in select(&doc).unwrap_or_default() {
+                    acc.add(value);
+                }
+            }
+        }
+    }
+
+    Ok(groups.into_iter().map(|(_, group)| {
+        let values = query.aggregates.iter().zip(group.accumulators.iter())
+            .map(|((op, path), acc)| match (op, path) {
+                (AggOp::Count, None) => Value::from(group.docs),
+                (op, _) => acc.result(*op)
+            })
+            .collect();
+        AggRow { group: group.key, values }
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accumulator() {
+        let mut acc = Accumulator::default();
+        for v in &[json!(3), json!(-1), Value::Null, json!("x"), json!(5)] {
+            acc.add(v);
+        }
+        assert_eq!(acc.result(AggOp::Count), json!(4));
+        assert_eq!(acc.result(AggOp::Sum), json!(7));
+        assert_eq!(acc.result(AggOp::Min), json!(-1));
+        assert_eq!(acc.result(AggOp::Max), json!(5));
+
+        acc.add(&json!(0.5));
+        assert_eq!(acc.result(AggOp::Sum), json!(7.5));
+        assert_eq!(acc.result(AggOp::Avg), json!(1.875));
+        assert_eq!(acc.result(AggOp::Max), json!(5));
+
+        acc.add(&json!(i64::max_value()));
+        assert!(acc.result(AggOp::Sum).is_f64());
+
+        let empty = Accumulator::default();
+        assert_eq!(empty.result(AggOp::Sum), json!(0));
+        assert_eq!(empty.result(AggOp::Avg), Value::Null);
+        assert_eq!(empty.result(AggOp::Min), Value::Null);
+    }
+}
@@ -0,0 +1,187 @@
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use sled::Tree;
+use crate::db;
+use crate::error::DatabaseError;
+use crate::json_pack;
+
+/// History policies, keyed by the document key
+const POLICY_TREE: &str = "__jhistpolicy__";
+/// Revisions, keyed by the document key, a zero byte and the big endian revision number
+const REVISION_TREE: &str = "__jhist__";
+
+/// Revisions kept when `JHISTORY` gives neither a count nor a window
+pub const DEFAULT_MAX_REVISIONS: usize = 10;
+
+lazy_static! {
+    /// Serializes revision writes so revision numbers are never handed out twice
+    static ref WRITE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// How much history of a key to keep, both limits apply when both are set and the latest revision
+/// is always kept. Revisions are pruned when the key is written.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HistoryPolicy {
+    pub max_revisions: Option<usize>,
+    pub window_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Revision {
+    timestamp: u64,
+    doc: Vec<u8>,
+}
+
+fn policy_tree() -> Result<Tree, DatabaseError> {
+    db::get_db().open_tree(POLICY_TREE).map_err(DatabaseError::from)
+}
+
+fn revision_tree() -> Result<Tree, DatabaseError> {
+    db::get_db().open_tree(REVISION_TREE).map_err(DatabaseError::from)
+}
+
+fn revision_prefix(key: &str) -> Vec<u8> {
+    let mut prefix = key.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+fn revision_key(key: &str, revision: u64) -> Vec<u8> {
+    let mut k = revision_prefix(key);
+    k.extend_from_slice(&revision.to_be_bytes());
+    k
+}
+
+/// Revisions of `key` oldest first, the length check skips keys that only share the prefix
+fn revisions(tree: &Tree, key: &str) -> Result<Vec<(u64, Revision)>, DatabaseError> {
+    let prefix = revision_prefix(key);
+    let mut revisions = vec![];
+    for r in tree.scan_prefix(&prefix) {
+        let (k, v) = r?;
+        if k.len() != prefix.len() + 8 {
+            continue;
+        }
+        let mut number = [0; 8];
+        number.copy_from_slice(&k[prefix.len()..]);
+        if let Ok(revision) = rmp_serde::from_slice(&v) {
+            revisions.push((u64::from_be_bytes(number), revision));
+        }
+    }
+    Ok(revisions)
+}
+
+fn policy(key: &str) -> Result<Option<HistoryPolicy>, DatabaseError> {
+    match policy_tree()?.get(key.as_bytes())? {
+        Some(b) => Ok(rmp_serde::from_slice(&b).ok()),
+        None => Ok(None)
+    }
+}
+
+/// Revisions, given oldest first as number and timestamp, that the policy no longer covers
+fn outdated(revisions: &[(u64, u64)], policy: &HistoryPolicy, now: u64) -> Vec<u64> {
+    let keep_from = policy.max_revisions
+        .map_or(0, |max| revisions.len().saturating_sub(max.max(1)));
+    let latest = revisions.len().saturating_sub(1);
+    revisions.iter().enumerate()
+        .filter(|(i, (_, timestamp))| {
+            *i < latest && (*i < keep_from || policy.window_ms.map_or(false, |w| timestamp.checked_add(w).map_or(false, |end| end < now)))
+        })
+        .map(|(_, (number, _))| *number)
+        .collect()
+}
+
+fn prune(tree: &Tree, key: &str, policy: &HistoryPolicy, now: u64) -> Result<(), DatabaseError> {
+    let revisions: Vec<(u64, u64)> = revisions(tree, key)?.into_iter()
+        .map(|(n, r)| (n, r.timestamp))
+        .collect();
+    for number in outdated(&revisions, policy, now) {
+        tree.remove(revision_key(key, number))?;
+    }
+    Ok(())
+}
+
+/// Stores the current document of `key` as a new revision when the key has a history policy
+/// and the document differs from the latest revision, deletes and other types are not recorded.
+/// `now` is the time of the write, it has to come from the replicated entry so every node keeps the same revisions.
+pub fn record(key: &str, now: u64) -> Result<(), DatabaseError> {
+    let policy = match policy(key)? {
+        Some(p) => p,
+        None => return Ok(())
+    };
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let packed = match json_pack::read_stored(key)? {
+        Some(p) => p,
+        None => return Ok(())
+    };
+    let tree = revision_tree()?;
+    let latest = revisions(&tree, key)?.pop();
+    if latest.as_ref().map_or(false, |(_, r)| r.doc == packed) {
+        return Ok(());
+    }
+    let number = latest.map_or(1, |(n, _)| n + 1);
+    let revision = Revision { timestamp: now, doc: packed };
+    tree.insert(revision_key(key, number), rmp_serde::to_vec(&revision)?)?;
+    prune(&tree, key, &policy, now)
+}
+
+/// Turns on or changes the history of `key`, the current document becomes a revision
+pub fn enable(key: &str, policy: &HistoryPolicy, now: u64) -> Result<(), DatabaseError> {
+    policy_tree()?.insert(key.as_bytes(), rmp_serde::to_vec(policy)?)?;
+    record(key, now)?;
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    prune(&revision_tree()?, key, policy, now)
+}
+
+/// Turns off the history of `key` and drops its revisions
+pub fn disable(key: &str) -> Result<bool, DatabaseError> {
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let removed = policy_tree()?.remove(key.as_bytes())?;
+    let tree = revision_tree()?;
+    for (number, _) in revisions(&tree, key)? {
+        tree.remove(revision_key(key, number))?;
+    }
+    Ok(removed.is_some())
+}
+
+/// Revision numbers of `key` with the time they were written in milliseconds, oldest first
+pub fn list(key: &str) -> Result<Vec<(u64, u64)>, DatabaseError> {
+    Ok(revisions(&revision_tree()?, key)?.into_iter()
+        .map(|(n, r)| (n, r.timestamp))
+        .collect())
+}
+
+pub fn get(key: &str, revision: u64) -> Result<Option<Value>, DatabaseError> {
+    match revision_tree()?.get(revision_key(key, revision))? {
+        Some(b) => {
+            let revision: Revision = rmp_serde::from_slice(&b)?;
+            Ok(Some(json_pack::unpack(&revision.doc)))
+        }
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outdated() {
+        let revisions = vec![(1, 100), (2, 200), (3, 300), (4, 400)];
+        let last = |n| HistoryPolicy { max_revisions: Some(n), window_ms: None };
+        assert_eq!(outdated(&revisions, &last(2), 400), vec![1, 2]);
+        assert_eq!(outdated(&revisions, &last(10), 400), Vec::<u64>::new());
+        assert_eq!(outdated(&revisions, &last(0), 400), vec![1, 2, 3]);
+
+        let window = HistoryPolicy { max_revisions: None, window_ms: Some(150) };
+        assert_eq!(outdated(&revisions, &window, 400), vec![1, 2]);
+        // the latest revision outlives the window
+        assert_eq!(outdated(&revisions, &window, 10_000), vec![1, 2, 3]);
+        let forever = HistoryPolicy { max_revisions: None, window_ms: Some(u64::max_value()) };
+        assert_eq!(outdated(&revisions, &forever, 10_000), Vec::<u64>::new());
+
+        let both = HistoryPolicy { max_revisions: Some(3), window_ms: Some(1000) };
+        assert_eq!(outdated(&revisions, &both, 400), vec![1]);
+        assert_eq!(outdated(&[], &both, 400), Vec::<u64>::new());
+    }
+}
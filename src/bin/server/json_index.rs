@@ -0,0 +1,415 @@
+use std::cmp::Ordering;
+use std::sync::Mutex;
+use dashmap::DashMap;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use sled::Tree;
+use crate::db;
+use crate::error::DatabaseError;
+use crate::geo::{FilterCondition, FilterOp, GeoFilter};
+use crate::{json, json_pack};
+use tracing::{info, warn};
+
+/// Index definitions, keyed by index name
+const DEF_TREE: &str = "__jindexdef__";
+/// Entries of an index, keyed by the encoded field value followed by the document key
+const ENTRY_TREE_PREFIX: &str = "__jindex__:";
+/// Entry of each indexed document, keyed by the document key, so stale entries can be found
+const DOC_TREE_PREFIX: &str = "__jindexdoc__:";
+
+const TAG_NULL: u8 = 1;
+const TAG_BOOL: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+
+lazy_static! {
+    static ref INDEXES: DashMap<String, IndexDef> = DashMap::new();
+    /// Serializes index maintenance so the entries always follow the latest stored document
+    static ref WRITE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Secondary index over the scalar at `path` of every json key starting with `prefix`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexDef {
+    pub name: String,
+    pub prefix: String,
+    pub path: String,
+}
+
+/// Query of `JFIND`
+#[derive(Debug, Clone, Default)]
+pub struct FindQuery {
+    pub prefix: String,
+    pub filter: GeoFilter,
+    pub sort_by: Option<String>,
+    pub descending: bool,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+fn entry_tree(name: &str) -> Result<Tree, DatabaseError> {
+    db::get_db().open_tree(format!("{}{}", ENTRY_TREE_PREFIX, name)).map_err(DatabaseError::from)
+}
+
+fn doc_tree(name: &str) -> Result<Tree, DatabaseError> {
+    db::get_db().open_tree(format!("{}{}", DOC_TREE_PREFIX, name)).map_err(DatabaseError::from)
+}
+
+/// Order preserving encoding of a json scalar, values of one kind sort together and byte order follows
+/// `compare_values`. Strings escape their zero bytes and end with two zeros, so an encoding never
+/// prefixes another one. Arrays and objects are not indexed.
+pub fn encode_value(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Null => Some(vec![TAG_NULL]),
+        Value::Bool(b) => Some(vec![TAG_BOOL, *b as u8]),
+        Value::Number(n) => {
+            // adding zero turns -0.0 into 0.0
+            let bits = (n.as_f64()? + 0.0).to_bits();
+            let bits = if bits >> 63 == 1 { !bits } else { bits | 1 << 63 };
+            let mut bytes = vec![TAG_NUMBER];
+            bytes.extend_from_slice(&bits.to_be_bytes());
+            Some(bytes)
+        }
+        Value::String(s) => {
+            let mut bytes = vec![TAG_STRING];
+            for b in s.bytes() {
+                bytes.push(b);
+                if b == 0 {
+                    bytes.push(1);
+                }
+            }
+            bytes.extend_from_slice(&[0, 0]);
+            Some(bytes)
+        }
+        _ => None
+    }
+}
+
+/// Splits an entry key into the encoded value and the document key
+fn split_entry(entry: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = match *entry.first()? {
+        TAG_NULL => 1,
+        TAG_BOOL => 2,
+        TAG_NUMBER => 9,
+        TAG_STRING => {
+            let mut i = 1;
+            loop {
+                match (entry.get(i)?, entry.get(i + 1)?) {
+                    (0, 0) => break i + 2,
+                    (0, _) => i += 2,
+                    _ => i += 1
+                }
+            }
+        }
+        _ => return None
+    };
+    if entry.len() < len {
+        return None;
+    }
+    Some(entry.split_at(len))
+}
+
+fn entry_key(def: &IndexDef, key: &str, packed: Option<&[u8]>) -> Option<Vec<u8>> {
+    let mut entry = encode_value(&json_pack::read_path(packed?, &def.path)?)?;
+    entry.extend_from_slice(key.as_bytes());
+    Some(entry)
+}
+
+fn read_doc(key: &str) -> Result<Option<Value>, DatabaseError> {
+    Ok(json_pack::read_stored(key)?.map(|p| json_pack::unpack(&p)))
+}
+
+/// Brings the entry of `key` in one index in line with its stored document
+fn update_entry(def: &IndexDef, key: &str, packed: Option<&[u8]>) -> Result<(), DatabaseError> {
+    write_entry(&entry_tree(&def.name)?, &doc_tree(&def.name)?, def, key, packed)
+}
+
+fn write_entry(entries: &Tree, docs: &Tree, def: &IndexDef, key: &str, packed: Option<&[u8]>) -> Result<(), DatabaseError> {
+    let new_entry = entry_key(def, key, packed);
+    let old_entry = docs.get(key.as_bytes())?;
+    if old_entry.as_deref() == new_entry.as_deref() {
+        return Ok(());
+    }
+    if let Some(old) = old_entry {
+        entries.remove(old)?;
+    }
+    match new_entry {
+        Some(new) => {
+            entries.insert(new.as_slice(), vec![])?;
+            docs.insert(key.as_bytes(), new)?;
+        }
+        None => {
+            docs.remove(key.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Re-reads `key` after a write and updates every index whose prefix covers it,
+/// a deleted key or one that no longer holds json drops out of the indexes
+pub fn reindex(key: &str) -> Result<(), DatabaseError> {
+    let defs: Vec<IndexDef> = INDEXES.iter()
+        .filter(|d| key.starts_with(&d.prefix))
+        .map(|d| d.value().clone())
+        .collect();
+    if defs.is_empty() {
+        return Ok(());
+    }
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let packed = json_pack::read_stored(key)?;
+    for def in &defs {
+        update_entry(def, key, packed.as_deref())?;
+    }
+    Ok(())
+}
+
+/// Registers an index and fills it from the keys already stored under its prefix
+pub fn create(def: IndexDef) -> Result<bool, DatabaseError> {
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let db = db::get_db();
+    let defs = db.open_tree(DEF_TREE)?;
+    if defs.contains_key(def.name.as_bytes())? {
+        return Ok(false);
+    }
+    let record = rmp_serde::to_vec_named(&def)?;
+    defs.insert(def.name.as_bytes(), record)?;
+    // writes racing the backfill wait on the lock and reindex their key afterwards
+    INDEXES.insert(def.name.to_owned(), def.clone());
+    for r in db.scan_prefix(def.prefix.as_bytes()).keys() {
+        let key = r?;
+        let key = String::from_utf8_lossy(&key).to_string();
+        let packed = json_pack::read_stored(&key)?;
+        update_entry(&def, &key, packed.as_deref())?;
+    }
+    Ok(true)
+}
+
+pub fn remove(name: &str) -> Result<bool, DatabaseError> {
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let db = db::get_db();
+    let removed = db.open_tree(DEF_TREE)?
+        .remove(name.as_bytes())?;
+    INDEXES.remove(name);
+    db.drop_tree(format!("{}{}", ENTRY_TREE_PREFIX, name).as_bytes())?;
+    db.drop_tree(format!("{}{}", DOC_TREE_PREFIX, name).as_bytes())?;
+    Ok(removed.is_some())
+}
+
+pub fn list() -> Vec<IndexDef> {
+    let mut defs: Vec<IndexDef> = INDEXES.iter().map(|d| d.value().clone()).collect();
+    defs.sort_by(|a, b| a.name.cmp(&b.name));
+    defs
+}
+
+/// Keys of the index entries matching a condition, in index order
+fn scan(def: &IndexDef, condition: &FilterCondition) -> Result<Vec<String>, DatabaseError> {
+    scan_entries(&entry_tree(&def.name)?, condition)
+}
+
+fn scan_entries(entries: &Tree, condition: &FilterCondition) -> Result<Vec<String>, DatabaseError> {
+    let bound = match encode_value(&condition.value) {
+        Some(b) => b,
+        None => return Ok(vec![])
+    };
+    let start = match condition.op {
+        FilterOp::Lt | FilterOp::Lte => vec![bound[0]],
+        _ => bound.clone()
+    };
+    let mut keys = vec![];
+    for r in entries.range(start..).keys() {
+        let entry = r?;
+        let (value, key) = match split_entry(&entry) {
+            Some(parts) => parts,
+            None => continue
+        };
+        if value[0] != bound[0] {
+            break;
+        }
+        match (condition.op, value.cmp(&bound)) {
+            (FilterOp::Eq, Ordering::Greater) | (FilterOp::Lt, Ordering::Equal) | (FilterOp::Lt, Ordering::Greater) |
+            (FilterOp::Lte, Ordering::Greater) => break,
+            (FilterOp::Gt, Ordering::Equal) => continue,
+            _ => keys.push(String::from_utf8_lossy(key).to_string())
+        }
+    }
+    Ok(keys)
+}
+
+fn plan(query: &FindQuery) -> Option<(IndexDef, &FilterCondition)> {
+    let defs: Vec<IndexDef> = INDEXES.iter().map(|d| d.value().clone()).collect();
+    choose_index(&defs, query)
+}
+
+/// Picks the index serving a condition of the query, equality conditions are the most selective
+fn choose_index<'a>(defs: &[IndexDef], query: &'a FindQuery) -> Option<(IndexDef, &'a FilterCondition)> {
+    let mut best: Option<(IndexDef, &FilterCondition)> = None;
+    for condition in &query.filter.conditions {
+        if condition.op == FilterOp::Ne {
+            continue;
+        }
+        let def = defs.iter()
+            .find(|d| d.path == condition.path && query.prefix.starts_with(&d.prefix))
+            .cloned();
+        if let Some(def) = def {
+            let better = match &best {
+                None => true,
+                Some((_, c)) => c.op != FilterOp::Eq && condition.op == FilterOp::Eq
+            };
+            if better {
+                best = Some((def, condition));
+            }
+        }
+    }
+    best
+}
+
+/// Sort order of documents by a field, documents without the field come last in either direction
+fn compare_field(a: &Value, b: &Value, path: &str, descending: bool) -> Ordering {
+    let a = json::get_path(a, path).and_then(|v| encode_value(&v));
+    let b = json::get_path(b, path).and_then(|v| encode_value(&v));
+    match (a, b) {
+        (Some(a), Some(b)) if descending => b.cmp(&a),
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal
+    }
+}
+
+/// Runs a `JFIND` query, candidates come from an index when one covers a condition and from a scan of
+/// the prefix otherwise. Every candidate is checked against the whole filter before sorting.
+pub fn find(query: &FindQuery) -> Result<Vec<(String, Value)>, DatabaseError> {
+    let keys = match plan(query) {
+        Some((def, condition)) => scan(&def, condition)?
+            .into_iter()
+            .filter(|k| k.starts_with(&query.prefix))
+            .collect(),
+        None => {
+            let mut keys = vec![];
+            for r in db::get_db().scan_prefix(query.prefix.as_bytes()).keys() {
+                keys.push(String::from_utf8_lossy(&r?).to_string());
+            }
+            keys
+        }
+    };
+    let mut hits = vec![];
+    for key in keys {
+        if let Some(doc) = read_doc(&key)? {
+            if query.filter.matches_data(&Some(doc.clone())) {
+                hits.push((key, doc));
+            }
+        }
+    }
+    if let Some(path) = &query.sort_by {
+        hits.sort_by(|(_, a), (_, b)| compare_field(a, b, path, query.descending));
+    }
+    Ok(hits.into_iter()
+        .skip(query.offset)
+        .take(query.limit.unwrap_or(usize::MAX))
+        .collect())
+}
+
+/// Loads the index definitions, the entries themselves live in sled and need no rebuild
+pub fn init() -> Result<(), DatabaseError> {
+    INDEXES.clear();
+    for r in db::get_db().open_tree(DEF_TREE)?.iter().values() {
+        let bytes = r?;
+        match rmp_serde::from_slice::<IndexDef>(&bytes) {
+            Ok(def) => {
+                INDEXES.insert(def.name.to_owned(), def);
+            }
+            Err(_) => warn!("Skipping corrupt json index definition")
+        }
+    }
+    info!("Loaded {} json indexes", INDEXES.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_encoding_order() {
+        let values = vec![json!(null), json!(false), json!(true), json!(-10.5), json!(-1), json!(0), json!(2),
+                          json!(30.25), json!(1e10), json!(""), json!("a\u{0}b"), json!("ab"), json!("abc"), json!("b")];
+        let encoded: Vec<Vec<u8>> = values.iter().map(|v| encode_value(v).unwrap()).collect();
+        let mut sorted = encoded.clone();
+        sorted.sort();
+        assert_eq!(encoded, sorted);
+        assert_eq!(encode_value(&json!(-0.0)), encode_value(&json!(0)));
+        assert_eq!(encode_value(&json!([1])), None);
+
+        for (value, bytes) in values.iter().zip(&encoded) {
+            let mut entry = bytes.clone();
+            entry.extend_from_slice(b"user:1");
+            let (v, k) = split_entry(&entry).unwrap();
+            assert_eq!(v, bytes.as_slice(), "{}", value);
+            assert_eq!(k, b"user:1");
+        }
+    }
+
+    fn condition(path: &str, op: FilterOp, value: Value) -> FilterCondition {
+        FilterCondition { path: path.to_owned(), op, value }
+    }
+
+    fn def(name: &str, prefix: &str, path: &str) -> IndexDef {
+        IndexDef { name: name.to_owned(), prefix: prefix.to_owned(), path: path.to_owned() }
+    }
+
+    #[test]
+    fn test_scan_entries() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let (entries, docs) = (db.open_tree("entries").unwrap(), db.open_tree("docs").unwrap());
+        let index = def("age", "", "age");
+        let docs_by_key = vec![("n", json!(null)), ("f", json!(false)), ("t", json!(true)), ("m", json!(-5)),
+                               ("a", json!(10)), ("b", json!(10)), ("c", json!(20.5)), ("s", json!("10")),
+                               ("u", json!("abc"))];
+        for (key, age) in &docs_by_key {
+            let packed = json_pack::pack(&json!({ "age": age }));
+            write_entry(&entries, &docs, &index, key, Some(&packed)).unwrap();
+        }
+        write_entry(&entries, &docs, &index, "x", Some(&json_pack::pack(&json!({"name": "x"})))).unwrap();
+        let scan = |op, value| scan_entries(&entries, &condition("age", op, value)).unwrap();
+
+        assert_eq!(scan(FilterOp::Eq, json!(10)), vec!["a", "b"]);
+        assert_eq!(scan(FilterOp::Eq, json!(11)), Vec::<String>::new());
+        assert_eq!(scan(FilterOp::Lt, json!(10)), vec!["m"]);
+        assert_eq!(scan(FilterOp::Lte, json!(10)), vec!["m", "a", "b"]);
+        assert_eq!(scan(FilterOp::Gt, json!(10)), vec!["c"]);
+        assert_eq!(scan(FilterOp::Gte, json!(10)), vec!["a", "b", "c"]);
+        // ranges stay within the type of the bound
+        assert_eq!(scan(FilterOp::Lt, json!(-100)), Vec::<String>::new());
+        assert_eq!(scan(FilterOp::Gt, json!(100)), Vec::<String>::new());
+        assert_eq!(scan(FilterOp::Lte, json!("abc")), vec!["s", "u"]);
+        assert_eq!(scan(FilterOp::Gt, json!("10")), vec!["u"]);
+        assert_eq!(scan(FilterOp::Gte, json!(false)), vec!["f", "t"]);
+        assert_eq!(scan(FilterOp::Lt, json!(true)), vec!["f"]);
+        assert_eq!(scan(FilterOp::Eq, json!(null)), vec!["n"]);
+        assert_eq!(scan(FilterOp::Eq, json!([10])), Vec::<String>::new());
+
+        // a changed value moves the entry and a missing one drops it
+        write_entry(&entries, &docs, &index, "a", Some(&json_pack::pack(&json!({"age": 30})))).unwrap();
+        write_entry(&entries, &docs, &index, "b", None).unwrap();
+        assert_eq!(scan(FilterOp::Gte, json!(10)), vec!["c", "a"]);
+        assert_eq!(entries.len(), docs.len());
+    }
+
+    #[test]
+    fn test_choose_index() {
+        let defs = vec![def("age", "user:", "age"), def("city", "user:", "city"), def("size", "item:", "size")];
+        let query = |conditions| FindQuery { prefix: "user:".to_owned(), filter: GeoFilter { conditions }, ..Default::default() };
+        let name = |q: &FindQuery| choose_index(&defs, q).map(|(d, c)| (d.name, c.op));
+
+        let q = query(vec![condition("age", FilterOp::Gt, json!(3)), condition("city", FilterOp::Eq, json!("x"))]);
+        assert_eq!(name(&q), Some(("city".to_owned(), FilterOp::Eq)));
+        let q = query(vec![condition("city", FilterOp::Ne, json!("x")), condition("age", FilterOp::Lte, json!(3))]);
+        assert_eq!(name(&q), Some(("age".to_owned(), FilterOp::Lte)));
+        let q = query(vec![condition("city", FilterOp::Ne, json!("x")), condition("size", FilterOp::Eq, json!(1))]);
+        assert_eq!(name(&q), None);
+        // an index under a narrower prefix than the query misses keys
+        let mut q = query(vec![condition("age", FilterOp::Eq, json!(3))]);
+        q.prefix = "us".to_owned();
+        assert_eq!(name(&q), None);
+    }
+}
@@ -0,0 +1,229 @@
+use serde_json::Value;
+use crate::db::{self, Data};
+use crate::error::DatabaseError;
+use tracing::info;
+
+const META_TREE: &str = "__meta__";
+const JSON_LAYOUT_KEY: &str = "json_layout";
+const JSON_LAYOUT_VERSION: u8 = 1;
+
+/// Encodes a document as msgpack, the layout of `Data::Json`
+pub fn pack(value: &Value) -> Vec<u8> {
+    rmp_serde::to_vec(value).unwrap_or_else(|_| vec![0xc0])
+}
+
+pub fn unpack(bytes: &[u8]) -> Value {
+    rmp_serde::from_slice(bytes).unwrap_or(Value::Null)
+}
+
+/// Reads the value at a dot path straight from the msgpack bytes, only the members along the path are
+/// decoded and every other subtree is skipped over. Like `json::get_path` an explicit null is `Some(Null)`.
+pub fn read_path(bytes: &[u8], path: &str) -> Option<Value> {
+    let path = if path == "." { "" } else { path };
+    let mut pos = 0;
+    let mut rest = if path.is_empty() { None } else { Some(path) };
+    while let Some(p) = rest {
+        let (segment, sub) = split_segment(p);
+        pos = child(bytes, pos, &segment)?;
+        rest = sub;
+    }
+    let end = skip(bytes, pos)?;
+    rmp_serde::from_slice(&bytes[pos..end]).ok()
+}
+
+/// First segment of a dot path, a backslash escapes the next character as in `json_dotpath`
+pub fn split_segment(path: &str) -> (String, Option<&str>) {
+    let mut segment = String::new();
+    let mut escaped = false;
+    for (i, c) in path.char_indices() {
+        match c {
+            _ if escaped => {
+                segment.push(c);
+                escaped = false;
+            }
+            '\\' => escaped = true,
+            '.' => return (segment, Some(&path[i + 1..])),
+            _ => segment.push(c)
+        }
+    }
+    (segment, None)
+}
+
+/// Offset of the member `segment` of the map or array starting at `pos`, `<` and `>` are the first and last element
+fn child(bytes: &[u8], pos: usize, segment: &str) -> Option<usize> {
+    if let Some((len, mut at)) = map_header(bytes, pos) {
+        for _ in 0..len {
+            let (key, value_at) = read_str(bytes, at)?;
+            if key == segment.as_bytes() {
+                return Some(value_at);
+            }
+            at = skip(bytes, value_at)?;
+        }
+        return None;
+    }
+    let (len, mut at) = array_header(bytes, pos)?;
+    let index = match segment {
+        "<" => 0,
+        ">" => len.checked_sub(1)?,
+        i => i.parse::<usize>().ok()?
+    };
+    if index >= len {
+        return None;
+    }
+    for _ in 0..index {
+        at = skip(bytes, at)?;
+    }
+    Some(at)
+}
+
+fn be(bytes: &[u8], pos: usize, width: usize) -> Option<usize> {
+    let b = bytes.get(pos..pos + width)?;
+    Some(b.iter().fold(0usize, |n, b| n << 8 | *b as usize))
+}
+
+/// Entry count and offset of the first entry of a map
+fn map_header(bytes: &[u8], pos: usize) -> Option<(usize, usize)> {
+    match *bytes.get(pos)? {
+        m @ 0x80..=0x8f => Some(((m & 0x0f) as usize, pos + 1)),
+        0xde => Some((be(bytes, pos + 1, 2)?, pos + 3)),
+        0xdf => Some((be(bytes, pos + 1, 4)?, pos + 5)),
+        _ => None
+    }
+}
+
+fn array_header(bytes: &[u8], pos: usize) -> Option<(usize, usize)> {
+    match *bytes.get(pos)? {
+        m @ 0x90..=0x9f => Some(((m & 0x0f) as usize, pos + 1)),
+        0xdc => Some((be(bytes, pos + 1, 2)?, pos + 3)),
+        0xdd => Some((be(bytes, pos + 1, 4)?, pos + 5)),
+        _ => None
+    }
+}
+
+/// String at `pos` and the offset following it
+fn read_str(bytes: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let (len, start) = match *bytes.get(pos)? {
+        m @ 0xa0..=0xbf => ((m & 0x1f) as usize, pos + 1),
+        0xd9 => (be(bytes, pos + 1, 1)?, pos + 2),
+        0xda => (be(bytes, pos + 1, 2)?, pos + 3),
+        0xdb => (be(bytes, pos + 1, 4)?, pos + 5),
+        _ => return None
+    };
+    Some((bytes.get(start..start + len)?, start + len))
+}
+
+/// Offset just past the value starting at `pos`
+fn skip(bytes: &[u8], pos: usize) -> Option<usize> {
+    let marker = *bytes.get(pos)?;
+    let end = match marker {
+        0x00..=0x7f | 0xe0..=0xff | 0xc0 | 0xc2 | 0xc3 => pos + 1,
+        0x80..=0x8f | 0xde | 0xdf => {
+            let (len, mut at) = map_header(bytes, pos)?;
+            for _ in 0..len * 2 {
+                at = skip(bytes, at)?;
+            }
+            at
+        }
+        0x90..=0x9f | 0xdc | 0xdd => {
+            let (len, mut at) = array_header(bytes, pos)?;
+            for _ in 0..len {
+                at = skip(bytes, at)?;
+            }
+            at
+        }
+        0xa0..=0xbf | 0xd9..=0xdb => read_str(bytes, pos)?.1,
+        0xc4 => pos + 2 + be(bytes, pos + 1, 1)?,
+        0xc5 => pos + 3 + be(bytes, pos + 1, 2)?,
+        0xc6 => pos + 5 + be(bytes, pos + 1, 4)?,
+        0xc7 => pos + 3 + be(bytes, pos + 1, 1)?,
+        0xc8 => pos + 4 + be(bytes, pos + 1, 2)?,
+        0xc9 => pos + 6 + be(bytes, pos + 1, 4)?,
+        0xca => pos + 5,
+        0xcb => pos + 9,
+        0xcc | 0xd0 => pos + 2,
+        0xcd | 0xd1 => pos + 3,
+        0xce | 0xd2 => pos + 5,
+        0xcf | 0xd3 => pos + 9,
+        0xd4 => pos + 3,
+        0xd5 => pos + 4,
+        0xd6 => pos + 6,
+        0xd7 => pos + 10,
+        0xd8 => pos + 18,
+        _ => return None
+    };
+    if end > bytes.len() { None } else { Some(end) }
+}
+
+/// Msgpack document stored at `key`, `None` when the key is missing or holds another type
+pub fn read_stored(key: &str) -> Result<Option<Vec<u8>>, DatabaseError> {
+    let bytes = match db::get_db().get(key.as_bytes())? {
+        Some(b) => b,
+        None => return Ok(None)
+    };
+    match Data::from_vec(&bytes) {
+        Ok(Data::Json(packed)) => Ok(Some(packed)),
+        _ => Ok(None)
+    }
+}
+
+/// Re-encodes documents stored as json text into msgpack, runs once per database
+pub fn migrate() -> Result<(), DatabaseError> {
+    let db = db::get_db();
+    let meta = db.open_tree(META_TREE)?;
+    let layout = meta.get(JSON_LAYOUT_KEY)?
+        .and_then(|v| v.first().cloned())
+        .unwrap_or(0);
+    if layout >= JSON_LAYOUT_VERSION {
+        return Ok(());
+    }
+    let mut migrated = 0;
+    for r in db.iter() {
+        let (k, v) = r?;
+        let text = match Data::from_vec(&v) {
+            Ok(Data::JsonText(t)) => t,
+            _ => continue
+        };
+        let doc: Value = serde_json::from_slice(&text).unwrap_or(Value::Null);
+        db.insert(k, bincode::serialize(&Data::Json(pack(&doc))).unwrap())?;
+        migrated += 1;
+    }
+    if migrated > 0 {
+        info!("Migrated {} json keys to msgpack", migrated);
+    }
+    meta.insert(JSON_LAYOUT_KEY, vec![JSON_LAYOUT_VERSION])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_path() {
+        let mut doc = json!({
+            "name": "kofi",
+            "a.b": 1,
+            "none": null,
+            "tags": ["x", {"deep": [1, 2.5, -3]}],
+            "blob": "x".repeat(300),
+            "big": u64::max_value(),
+        });
+        let list: Vec<Value> = (0..20).map(|i| json!({"i": i})).collect();
+        doc["list"] = Value::Array(list);
+        let bytes = pack(&doc);
+        assert_eq!(unpack(&bytes), doc);
+
+        assert_eq!(read_path(&bytes, "."), Some(doc.clone()));
+        assert_eq!(read_path(&bytes, "name"), Some(json!("kofi")));
+        assert_eq!(read_path(&bytes, "a\\.b"), Some(json!(1)));
+        assert_eq!(read_path(&bytes, "none"), Some(Value::Null));
+        assert_eq!(read_path(&bytes, "tags.1.deep.>"), Some(json!(-3)));
+        assert_eq!(read_path(&bytes, "tags.<"), Some(json!("x")));
+        assert_eq!(read_path(&bytes, "list.17.i"), Some(json!(17)));
+        assert_eq!(read_path(&bytes, "big"), Some(json!(u64::max_value())));
+        assert_eq!(read_path(&bytes, "blob").map(|b| b.as_str().unwrap().len()), Some(300));
+        assert_eq!(read_path(&bytes, "tags.2"), None);
+        assert_eq!(read_path(&bytes, "name.x"), None);
+        assert_eq!(read_path(&bytes, "missing"), None);
+    }
+}
@@ -0,0 +1,491 @@
+use dashmap::DashMap;
+use regex::Regex;
+use serde_json::{Map, Value};
+use crate::db;
+use crate::error::DatabaseError;
+use crate::json::json_eq;
+use tracing::{info, warn};
+
+/// Schemas bound to key globs, keyed by the glob
+const SCHEMA_TREE: &str = "__jschema__";
+const TYPE_NAMES: [&str; 7] = ["null", "boolean", "object", "array", "number", "integer", "string"];
+/// Bound on `$ref` hops that do not descend into the document, so self references cannot loop
+const MAX_REF_DEPTH: usize = 64;
+
+lazy_static! {
+    static ref SCHEMAS: DashMap<String, BoundSchema> = DashMap::new();
+    static ref PATTERNS: DashMap<String, Regex> = DashMap::new();
+}
+
+/// A JSON Schema (draft 7) that every json write to a key matching `pattern` has to satisfy
+#[derive(Debug, Clone)]
+struct BoundSchema {
+    pattern: glob::Pattern,
+    schema: Value,
+}
+
+/// First failed keyword, `path` is the dot path into the document and `schema_path` the keyword location
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub path: String,
+    pub schema_path: String,
+    pub message: String,
+}
+
+impl Violation {
+    fn new(path: &str, schema_path: &str, message: String) -> Violation {
+        Violation {
+            path: path.to_owned(),
+            schema_path: schema_path.to_owned(),
+            message,
+        }
+    }
+
+    /// RESP error text, the document root is reported as `.`
+    pub fn to_error(&self) -> String {
+        let path = if self.path.is_empty() { "." } else { &self.path };
+        format!("ERR schema violation at {} ({}): {}", path, self.schema_path, self.message)
+    }
+}
+
+fn child(path: &str, segment: &str) -> String {
+    if path.is_empty() { segment.to_owned() } else { format!("{}.{}", path, segment) }
+}
+
+fn regex(pattern: &str) -> Option<Regex> {
+    if let Some(r) = PATTERNS.get(pattern) {
+        return Some(r.value().clone());
+    }
+    let r = Regex::new(pattern).ok()?;
+    PATTERNS.insert(pattern.to_owned(), r.clone());
+    Some(r)
+}
+
+fn resolve<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    let pointer = reference.strip_prefix('#')?;
+    if pointer.is_empty() { Some(root) } else { root.pointer(pointer) }
+}
+
+fn is_integer(value: &Value) -> bool {
+    match value {
+        Value::Number(n) => n.is_i64() || n.is_u64() || n.as_f64().map_or(false, |f| f.fract() == 0.0),
+        _ => false
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "number" => value.is_number(),
+        "integer" => is_integer(value),
+        "string" => value.is_string(),
+        _ => false
+    }
+}
+
+/// Checks that a schema only uses local references, valid type names and compilable patterns
+pub fn check_schema(schema: &Value) -> Result<(), String> {
+    check_node(schema, schema, "#")
+}
+
+fn check_node(root: &Value, schema: &Value, at: &str) -> Result<(), String> {
+    let schema = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(map) => map,
+        _ => return Err(format!("{} is not a schema", at))
+    };
+    if let Some(reference) = schema.get("$ref") {
+        match reference.as_str() {
+            Some(r) if resolve(root, r).is_some() => {}
+            _ => return Err(format!("{}/$ref cannot be resolved, only local references are supported", at))
+        }
+    }
+    if let Some(types) = schema.get("type") {
+        let names: Vec<&Value> = match types {
+            Value::Array(names) => names.iter().collect(),
+            t => vec![t]
+        };
+        if !names.iter().all(|n| n.as_str().map_or(false, |n| TYPE_NAMES.contains(&n))) {
+            return Err(format!("{}/type is not a valid type", at));
+        }
+    }
+    if let Some(pattern) = schema.get("pattern") {
+        if pattern.as_str().and_then(regex).is_none() {
+            return Err(format!("{}/pattern is not a valid regex", at));
+        }
+    }
+    for keyword in &["properties", "patternProperties", "definitions", "$defs", "dependencies"] {
+        if let Some(Value::Object(children)) = schema.get(*keyword) {
+            for (name, child) in children {
+                if *keyword == "patternProperties" && regex(name).is_none() {
+                    return Err(format!("{}/patternProperties/{} is not a valid regex", at, name));
+                }
+                if *keyword == "dependencies" && child.is_array() {
+                    continue;
+                }
+                check_node(root, child, &format!("{}/{}/{}", at, keyword, name))?;
+            }
+        }
+    }
+    for keyword in &["allOf", "anyOf", "oneOf", "items"] {
+        if let Some(Value::Array(children)) = schema.get(*keyword) {
+            for (i, child) in children.iter().enumerate() {
+                check_node(root, child, &format!("{}/{}/{}", at, keyword, i))?;
+            }
+        }
+    }
+    for keyword in &["items", "additionalItems", "additionalProperties", "contains", "propertyNames", "not", "if", "then", "else"] {
+        match schema.get(*keyword) {
+            Some(Value::Array(_)) | None => {}
+            Some(child) => check_node(root, child, &format!("{}/{}", at, keyword))?
+        }
+    }
+    Ok(())
+}
+
+/// Validates a document against a draft 7 schema, `format` is treated as an annotation
+pub fn validate(schema: &Value, doc: &Value) -> Result<(), Violation> {
+    Validator { root: schema }.check(schema, doc, "", "#", 0)
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+}
+
+impl<'a> Validator<'a> {
+    fn is_valid(&self, schema: &Value, value: &Value, depth: usize) -> bool {
+        self.check(schema, value, "", "#", depth).is_ok()
+    }
+
+    fn check(&self, schema: &Value, value: &Value, path: &str, at: &str, depth: usize) -> Result<(), Violation> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(()),
+            Value::Object(map) => map,
+            _ => return Err(Violation::new(path, at, "no value is allowed here".to_owned()))
+        };
+
+        // in draft 7 the keywords next to a $ref are ignored
+        if let Some(reference) = schema.get("$ref").and_then(|r| r.as_str()) {
+            let at = format!("{}/$ref", at);
+            if depth >= MAX_REF_DEPTH {
+                return Err(Violation::new(path, &at, "schema references nest too deep".to_owned()));
+            }
+            return match resolve(self.root, reference) {
+                Some(target) => self.check(target, value, path, &at, depth + 1),
+                None => Err(Violation::new(path, &at, format!("cannot resolve {}", reference)))
+            };
+        }
+
+        let fail = |keyword: &str, message: String| Err(Violation::new(path, &format!("{}/{}", at, keyword), message));
+
+        if let Some(types) = schema.get("type") {
+            let names: Vec<&str> = match types {
+                Value::Array(names) => names.iter().filter_map(|n| n.as_str()).collect(),
+                t => t.as_str().into_iter().collect()
+            };
+            if !names.iter().any(|n| has_type(value, n)) {
+                return fail("type", format!("expected {}", names.join(" or ")));
+            }
+        }
+        if let Some(Value::Array(options)) = schema.get("enum") {
+            if !options.iter().any(|o| json_eq(o, value)) {
+                return fail("enum", "value is not one of the allowed values".to_owned());
+            }
+        }
+        if let Some(constant) = schema.get("const") {
+            if !json_eq(constant, value) {
+                return fail("const", format!("expected {}", constant));
+            }
+        }
+
+        match value {
+            Value::Number(n) => self.check_number(schema, n.as_f64().unwrap_or(0.0), &fail)?,
+            Value::String(s) => self.check_string(schema, s, &fail)?,
+            Value::Array(items) => self.check_array(schema, items, path, at, depth, &fail)?,
+            Value::Object(map) => self.check_object(schema, map, path, at, depth, &fail)?,
+            _ => {}
+        }
+
+        if let Some(Value::Array(all)) = schema.get("allOf") {
+            for (i, s) in all.iter().enumerate() {
+                self.check(s, value, path, &format!("{}/allOf/{}", at, i), depth)?;
+            }
+        }
+        if let Some(Value::Array(any)) = schema.get("anyOf") {
+            if !any.iter().any(|s| self.is_valid(s, value, depth)) {
+                return fail("anyOf", "value matches none of the schemas".to_owned());
+            }
+        }
+        if let Some(Value::Array(one)) = schema.get("oneOf") {
+            let matched = one.iter().filter(|s| self.is_valid(s, value, depth)).count();
+            if matched != 1 {
+                return fail("oneOf", format!("value matches {} schemas instead of exactly one", matched));
+            }
+        }
+        if let Some(not) = schema.get("not") {
+            if self.is_valid(not, value, depth) {
+                return fail("not", "value matches a schema it must not match".to_owned());
+            }
+        }
+        if let Some(condition) = schema.get("if") {
+            let (keyword, branch) = if self.is_valid(condition, value, depth) { ("then", schema.get("then")) } else { ("else", schema.get("else")) };
+            if let Some(branch) = branch {
+                self.check(branch, value, path, &format!("{}/{}", at, keyword), depth)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_number<F>(&self, schema: &Map<String, Value>, n: f64, fail: &F) -> Result<(), Violation>
+        where F: Fn(&str, String) -> Result<(), Violation> {
+        let limit = |keyword: &str| schema.get(keyword).and_then(|v| v.as_f64());
+        if let Some(m) = limit("multipleOf") {
+            let q = n / m;
+            if (q - q.round()).abs() > 1e-9 {
+                return fail("multipleOf", format!("{} is not a multiple of {}", n, m));
+            }
+        }
+        if let Some(max) = limit("maximum") {
+            if n > max { return fail("maximum", format!("{} is greater than {}", n, max)); }
+        }
+        if let Some(max) = limit("exclusiveMaximum") {
+            if n >= max { return fail("exclusiveMaximum", format!("{} is not less than {}", n, max)); }
+        }
+        if let Some(min) = limit("minimum") {
+            if n < min { return fail("minimum", format!("{} is less than {}", n, min)); }
+        }
+        if let Some(min) = limit("exclusiveMinimum") {
+            if n <= min { return fail("exclusiveMinimum", format!("{} is not greater than {}", n, min)); }
+        }
+        Ok(())
+    }
+
+    fn check_string<F>(&self, schema: &Map<String, Value>, s: &str, fail: &F) -> Result<(), Violation>
+        where F: Fn(&str, String) -> Result<(), Violation> {
+        let len = s.chars().count() as u64;
+        if let Some(max) = schema.get("maxLength").and_then(|v| v.as_u64()) {
+            if len > max { return fail("maxLength", format!("string is longer than {} characters", max)); }
+        }
+        if let Some(min) = schema.get("minLength").and_then(|v| v.as_u64()) {
+            if len < min { return fail("minLength", format!("string is shorter than {} characters", min)); }
+        }
+        if let Some(pattern) = schema.get("pattern").and_then(|v| v.as_str()) {
+            if !regex(pattern).map_or(false, |r| r.is_match(s)) {
+                return fail("pattern", format!("string does not match {}", pattern));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_array<F>(&self, schema: &Map<String, Value>, items: &[Value], path: &str, at: &str, depth: usize, fail: &F) -> Result<(), Violation>
+        where F: Fn(&str, String) -> Result<(), Violation> {
+        let len = items.len() as u64;
+        if let Some(max) = schema.get("maxItems").and_then(|v| v.as_u64()) {
+            if len > max { return fail("maxItems", format!("array has more than {} items", max)); }
+        }
+        if let Some(min) = schema.get("minItems").and_then(|v| v.as_u64()) {
+            if len < min { return fail("minItems", format!("array has fewer than {} items", min)); }
+        }
+        if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+            for (i, a) in items.iter().enumerate() {
+                if items[..i].iter().any(|b| json_eq(a, b)) {
+                    return fail("uniqueItems", format!("item {} is a duplicate", i));
+                }
+            }
+        }
+        match schema.get("items") {
+            Some(Value::Array(tuple)) => {
+                for (i, item) in items.iter().enumerate() {
+                    let (s, at) = match tuple.get(i) {
+                        Some(s) => (s, format!("{}/items/{}", at, i)),
+                        None => match schema.get("additionalItems") {
+                            Some(s) => (s, format!("{}/additionalItems", at)),
+                            None => break
+                        }
+                    };
+                    self.check(s, item, &child(path, &i.to_string()), &at, depth)?;
+                }
+            }
+            Some(s) => {
+                for (i, item) in items.iter().enumerate() {
+                    self.check(s, item, &child(path, &i.to_string()), &format!("{}/items", at), depth)?;
+                }
+            }
+            None => {}
+        }
+        if let Some(contains) = schema.get("contains") {
+            if !items.iter().any(|item| self.is_valid(contains, item, depth)) {
+                return fail("contains", "no item matches the contains schema".to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    fn check_object<F>(&self, schema: &Map<String, Value>, map: &Map<String, Value>, path: &str, at: &str, depth: usize, fail: &F) -> Result<(), Violation>
+        where F: Fn(&str, String) -> Result<(), Violation> {
+        let len = map.len() as u64;
+        if let Some(max) = schema.get("maxProperties").and_then(|v| v.as_u64()) {
+            if len > max { return fail("maxProperties", format!("object has more than {} properties", max)); }
+        }
+        if let Some(min) = schema.get("minProperties").and_then(|v| v.as_u64()) {
+            if len < min { return fail("minProperties", format!("object has fewer than {} properties", min)); }
+        }
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for name in required.iter().filter_map(|r| r.as_str()) {
+                if !map.contains_key(name) {
+                    return Err(Violation::new(&child(path, name), &format!("{}/required", at), format!("missing required property {}", name)));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(|p| p.as_object());
+        let pattern_properties = schema.get("patternProperties").and_then(|p| p.as_object());
+        for (name, item) in map {
+            let item_path = child(path, name);
+            let mut matched = false;
+            if let Some(s) = properties.and_then(|p| p.get(name)) {
+                matched = true;
+                self.check(s, item, &item_path, &format!("{}/properties/{}", at, name), depth)?;
+            }
+            for (pattern, s) in pattern_properties.into_iter().flatten() {
+                if regex(pattern).map_or(false, |r| r.is_match(name)) {
+                    matched = true;
+                    self.check(s, item, &item_path, &format!("{}/patternProperties/{}", at, pattern), depth)?;
+                }
+            }
+            if !matched {
+                if let Some(s) = schema.get("additionalProperties") {
+                    if s == &Value::Bool(false) {
+                        return Err(Violation::new(&item_path, &format!("{}/additionalProperties", at), format!("property {} is not allowed", name)));
+                    }
+                    self.check(s, item, &item_path, &format!("{}/additionalProperties", at), depth)?;
+                }
+            }
+            if let Some(s) = schema.get("propertyNames") {
+                self.check(s, &Value::String(name.to_owned()), &item_path, &format!("{}/propertyNames", at), depth)?;
+            }
+        }
+        if let Some(Value::Object(dependencies)) = schema.get("dependencies") {
+            for (name, dependency) in dependencies.iter().filter(|(name, _)| map.contains_key(*name)) {
+                let dep_at = format!("{}/dependencies/{}", at, name);
+                match dependency {
+                    Value::Array(names) => {
+                        for needed in names.iter().filter_map(|n| n.as_str()) {
+                            if !map.contains_key(needed) {
+                                return Err(Violation::new(&child(path, needed), &dep_at, format!("{} is required when {} is present", needed, name)));
+                            }
+                        }
+                    }
+                    s => self.check(s, &Value::Object(map.clone()), path, &dep_at, depth)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks a json document about to be written to `key` against every schema bound to a matching glob
+pub fn validate_key(key: &str, doc: &Value) -> Result<(), String> {
+    for bound in SCHEMAS.iter() {
+        if bound.pattern.matches(key) {
+            validate(&bound.schema, doc).map_err(|v| v.to_error())?;
+        }
+    }
+    Ok(())
+}
+
+/// Binds a schema to a key glob, replacing the schema bound to the same glob
+pub fn register(pattern: &str, schema: Value) -> Result<(), DatabaseError> {
+    let glob = glob::Pattern::new(pattern).map_err(|_| DatabaseError::new("ERR invalid pattern"))?;
+    check_schema(&schema).map_err(|e| DatabaseError::new(&format!("ERR invalid schema: {}", e)))?;
+    db::get_db().open_tree(SCHEMA_TREE)?
+        .insert(pattern.as_bytes(), serde_json::to_vec(&schema).unwrap())?;
+    SCHEMAS.insert(pattern.to_owned(), BoundSchema { pattern: glob, schema });
+    Ok(())
+}
+
+pub fn remove(pattern: &str) -> Result<bool, DatabaseError> {
+    let removed = db::get_db().open_tree(SCHEMA_TREE)?
+        .remove(pattern.as_bytes())?;
+    SCHEMAS.remove(pattern);
+    Ok(removed.is_some())
+}
+
+pub fn list() -> Vec<(String, Value)> {
+    let mut schemas: Vec<(String, Value)> = SCHEMAS.iter()
+        .map(|b| (b.key().to_owned(), b.schema.clone()))
+        .collect();
+    schemas.sort_by(|a, b| a.0.cmp(&b.0));
+    schemas
+}
+
+/// Loads the bound schemas, existing documents are not revalidated
+pub fn init() -> Result<(), DatabaseError> {
+    SCHEMAS.clear();
+    for r in db::get_db().open_tree(SCHEMA_TREE)?.iter() {
+        let (pattern, schema) = r?;
+        let pattern = String::from_utf8_lossy(&pattern).to_string();
+        match (glob::Pattern::new(&pattern), serde_json::from_slice::<Value>(&schema)) {
+            (Ok(glob), Ok(schema)) => {
+                SCHEMAS.insert(pattern, BoundSchema { pattern: glob, schema });
+            }
+            _ => warn!("Skipping corrupt json schema bound to {}", pattern)
+        }
+    }
+    info!("Loaded {} json schemas", SCHEMAS.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let schema = json!({
+            "type": "object",
+            "required": ["name", "age"],
+            "properties": {
+                "name": {"type": "string", "minLength": 1},
+                "age": {"type": "integer", "minimum": 0},
+                "tags": {"type": "array", "items": {"type": "string"}, "uniqueItems": true},
+                "address": {"$ref": "#/definitions/address"}
+            },
+            "additionalProperties": false,
+            "definitions": {
+                "address": {"type": "object", "properties": {"city": {"enum": ["Accra", "Kumasi"]}}}
+            }
+        });
+        assert!(check_schema(&schema).is_ok());
+        assert!(validate(&schema, &json!({"name": "ama", "age": 30.0, "tags": ["a"], "address": {"city": "Accra"}})).is_ok());
+
+        let violation = |doc: Value| validate(&schema, &doc).unwrap_err();
+        assert_eq!(violation(json!({"name": "ama"})).path, "age");
+        let v = violation(json!({"name": "ama", "age": 1.5}));
+        assert_eq!((v.path.as_str(), v.schema_path.as_str()), ("age", "#/properties/age/type"));
+        assert_eq!(violation(json!({"name": "ama", "age": 1, "tags": ["a", 2]})).path, "tags.1");
+        assert_eq!(violation(json!({"name": "ama", "age": 1, "tags": ["a", "a"]})).schema_path, "#/properties/tags/uniqueItems");
+        assert_eq!(violation(json!({"name": "ama", "age": 1, "address": {"city": "Tema"}})).schema_path,
+                   "#/properties/address/$ref/properties/city/enum");
+        assert_eq!(violation(json!({"name": "ama", "age": 1, "x": 1})).path, "x");
+        assert_eq!(violation(json!([])).to_error(), "ERR schema violation at . (#/type): expected object");
+    }
+
+    #[test]
+    fn test_combinators() {
+        let schema = json!({
+            "oneOf": [{"type": "integer"}, {"type": "number", "multipleOf": 0.5}],
+            "not": {"const": 3}
+        });
+        assert!(validate(&schema, &json!(1.5)).is_ok());
+        assert!(validate(&schema, &json!(2)).is_err());
+        assert!(validate(&schema, &json!(3)).is_err());
+        assert!(validate(&json!({"if": {"minimum": 10}, "then": {"multipleOf": 5}}), &json!(12)).is_err());
+        assert!(validate(&json!({"$ref": "#"}), &json!(1)).is_err());
+        assert!(check_schema(&json!({"$ref": "http://example.com/schema"})).is_err());
+        assert!(check_schema(&json!({"properties": {"a": {"pattern": "("}}})).is_err());
+        assert!(check_schema(&json!({"type": "float"})).is_err());
+    }
+}
@@ -0,0 +1,506 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+use dashmap::DashMap;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use sled::Tree;
+use crate::db;
+use crate::error::DatabaseError;
+use crate::json_pack;
+use tracing::{info, warn};
+
+/// Full-text index definitions, keyed by index name
+const DEF_TREE: &str = "__jftdef__";
+/// Postings of an index, keyed by the term, a zero byte and the document key, valued with the term positions
+const POSTING_TREE_PREFIX: &str = "__jft__:";
+/// Length and terms of each indexed document, keyed by the document key, so stale postings can be found
+const DOC_TREE_PREFIX: &str = "__jftdoc__:";
+
+/// BM25 term frequency saturation and length normalization
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+/// Upper bound on the terms a prefix query expands to
+const MAX_PREFIX_TERMS: usize = 256;
+
+lazy_static! {
+    static ref INDEXES: DashMap<String, FtIndex> = DashMap::new();
+    /// Serializes index maintenance so the postings always follow the latest stored document
+    static ref WRITE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Full-text index over the strings at `paths` of every json key starting with `prefix`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FtIndexDef {
+    pub name: String,
+    pub prefix: String,
+    pub paths: Vec<String>,
+}
+
+/// A definition with the collection statistics BM25 needs, rebuilt from the document tree on start up
+#[derive(Debug, Clone)]
+struct FtIndex {
+    def: FtIndexDef,
+    docs: u64,
+    total_len: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct DocEntry {
+    len: u32,
+    terms: Vec<String>,
+}
+
+/// Clause of a search, a document has to match every clause of the query
+#[derive(Debug, Clone, PartialEq)]
+pub enum Clause {
+    Term(String),
+    Prefix(String),
+    Phrase(Vec<String>),
+}
+
+/// Query of `JFTSEARCH`
+#[derive(Debug, Clone, Default)]
+pub struct SearchQuery {
+    pub index: String,
+    pub clauses: Vec<Clause>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+fn posting_tree(name: &str) -> Result<Tree, DatabaseError> {
+    db::get_db().open_tree(format!("{}{}", POSTING_TREE_PREFIX, name)).map_err(DatabaseError::from)
+}
+
+fn doc_tree(name: &str) -> Result<Tree, DatabaseError> {
+    db::get_db().open_tree(format!("{}{}", DOC_TREE_PREFIX, name)).map_err(DatabaseError::from)
+}
+
+/// Lowercase runs of letters and digits, everything else separates words
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+        .collect()
+}
+
+/// Parses a search string, `"quoted words"` are phrases and a trailing `*` makes a prefix
+pub fn parse_query(query: &str) -> Vec<Clause> {
+    let mut clauses = vec![];
+    for (i, part) in query.split('"').enumerate() {
+        if i % 2 == 1 {
+            match tokenize(part) {
+                ref t if t.is_empty() => {}
+                t if t.len() == 1 => clauses.push(Clause::Term(t[0].to_owned())),
+                t => clauses.push(Clause::Phrase(t))
+            }
+            continue;
+        }
+        for word in part.split_whitespace() {
+            let is_prefix = word.ends_with('*');
+            let mut tokens = tokenize(word);
+            match tokens.len() {
+                0 => {}
+                1 if is_prefix => clauses.push(Clause::Prefix(tokens.remove(0))),
+                1 => clauses.push(Clause::Term(tokens.remove(0))),
+                // a word like `wi-fi` tokenizes into a phrase
+                _ => clauses.push(Clause::Phrase(tokens))
+            }
+        }
+    }
+    clauses
+}
+
+fn posting_key(term: &str, key: &str) -> Vec<u8> {
+    let mut k = term.as_bytes().to_vec();
+    k.push(0);
+    k.extend_from_slice(key.as_bytes());
+    k
+}
+
+/// Splits a posting key into the term and the document key
+fn split_posting(posting: &[u8]) -> Option<(String, String)> {
+    let at = posting.iter().position(|b| *b == 0)?;
+    Some((String::from_utf8_lossy(&posting[..at]).to_string(), String::from_utf8_lossy(&posting[at + 1..]).to_string()))
+}
+
+/// Term positions of a document, the fields follow each other with a gap so phrases never span two of them
+fn positions(def: &FtIndexDef, packed: Option<&[u8]>) -> (u32, BTreeMap<String, Vec<u32>>) {
+    let mut terms: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    let mut pos = 0;
+    let packed = match packed {
+        Some(p) => p,
+        None => return (0, terms)
+    };
+    for path in &def.paths {
+        let texts = match json_pack::read_path(packed, path) {
+            Some(Value::String(s)) => vec![s],
+            Some(Value::Array(a)) => a.into_iter().filter_map(|v| v.as_str().map(|s| s.to_owned())).collect(),
+            _ => continue
+        };
+        for text in texts {
+            for token in tokenize(&text) {
+                terms.entry(token).or_default().push(pos);
+                pos += 1;
+            }
+            pos += 1;
+        }
+    }
+    let len = terms.values().map(|p| p.len() as u32).sum();
+    (len, terms)
+}
+
+/// Replaces the postings of `key` in one index with those of its stored document,
+/// returns the change to the document count and to the total length
+fn update_doc(def: &FtIndexDef, key: &str, packed: Option<&[u8]>) -> Result<(i64, i64), DatabaseError> {
+    write_doc(&posting_tree(&def.name)?, &doc_tree(&def.name)?, def, key, packed)
+}
+
+fn write_doc(postings: &Tree, docs: &Tree, def: &FtIndexDef, key: &str, packed: Option<&[u8]>) -> Result<(i64, i64), DatabaseError> {
+    let old: Option<DocEntry> = match docs.get(key.as_bytes())? {
+        Some(b) => rmp_serde::from_slice(&b).ok(),
+        None => None
+    };
+    let (len, terms) = positions(def, packed);
+    let mut delta = (0, 0);
+    if let Some(old) = &old {
+        for term in &old.terms {
+            postings.remove(posting_key(term, key))?;
+        }
+        delta = (-1, -(old.len as i64));
+    }
+    if terms.is_empty() {
+        docs.remove(key.as_bytes())?;
+        return Ok(delta);
+    }
+    for (term, pos) in &terms {
+        postings.insert(posting_key(term, key), rmp_serde::to_vec(pos)?)?;
+    }
+    let entry = DocEntry { len, terms: terms.into_iter().map(|(t, _)| t).collect() };
+    docs.insert(key.as_bytes(), rmp_serde::to_vec(&entry)?)?;
+    Ok((delta.0 + 1, delta.1 + len as i64))
+}
+
+fn apply_delta(name: &str, delta: (i64, i64)) {
+    if let Some(mut index) = INDEXES.get_mut(name) {
+        index.docs = (index.docs as i64 + delta.0).max(0) as u64;
+        index.total_len = (index.total_len as i64 + delta.1).max(0) as u64;
+    }
+}
+
+/// Re-posts the terms of `key` after a write in every full-text index over its prefix and keeps the
+/// document count and total length used by BM25 in step, a key without a json document loses its postings
+pub fn reindex(key: &str) -> Result<(), DatabaseError> {
+    let defs: Vec<FtIndexDef> = INDEXES.iter()
+        .filter(|i| key.starts_with(&i.def.prefix))
+        .map(|i| i.def.clone())
+        .collect();
+    if defs.is_empty() {
+        return Ok(());
+    }
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let packed = json_pack::read_stored(key)?;
+    for def in &defs {
+        let delta = update_doc(def, key, packed.as_deref())?;
+        apply_delta(&def.name, delta);
+    }
+    Ok(())
+}
+
+/// Registers a full-text index and fills it from the keys already stored under its prefix
+pub fn create(def: FtIndexDef) -> Result<bool, DatabaseError> {
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let db = db::get_db();
+    let defs = db.open_tree(DEF_TREE)?;
+    if defs.contains_key(def.name.as_bytes())? {
+        return Ok(false);
+    }
+    let record = rmp_serde::to_vec_named(&def)?;
+    defs.insert(def.name.as_bytes(), record)?;
+    // the index is visible before the scan, so a document written meanwhile is posted by its own
+    // reindex once the scan releases the lock, and the statistics count it once
+    INDEXES.insert(def.name.to_owned(), FtIndex { def: def.clone(), docs: 0, total_len: 0 });
+    for r in db.scan_prefix(def.prefix.as_bytes()).keys() {
+        let key = r?;
+        let key = String::from_utf8_lossy(&key).to_string();
+        let packed = json_pack::read_stored(&key)?;
+        let delta = update_doc(&def, &key, packed.as_deref())?;
+        apply_delta(&def.name, delta);
+    }
+    Ok(true)
+}
+
+pub fn remove(name: &str) -> Result<bool, DatabaseError> {
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let db = db::get_db();
+    let removed = db.open_tree(DEF_TREE)?
+        .remove(name.as_bytes())?;
+    INDEXES.remove(name);
+    db.drop_tree(format!("{}{}", POSTING_TREE_PREFIX, name).as_bytes())?;
+    db.drop_tree(format!("{}{}", DOC_TREE_PREFIX, name).as_bytes())?;
+    Ok(removed.is_some())
+}
+
+pub fn list() -> Vec<FtIndexDef> {
+    let mut defs: Vec<FtIndexDef> = INDEXES.iter().map(|i| i.def.clone()).collect();
+    defs.sort_by(|a, b| a.name.cmp(&b.name));
+    defs
+}
+
+/// Positions of a term in every document holding it
+fn postings(tree: &Tree, term: &str) -> Result<HashMap<String, Vec<u32>>, DatabaseError> {
+    let mut prefix = term.as_bytes().to_vec();
+    prefix.push(0);
+    let mut docs = HashMap::new();
+    for r in tree.scan_prefix(&prefix) {
+        let (k, v) = r?;
+        let key = String::from_utf8_lossy(&k[prefix.len()..]).to_string();
+        docs.insert(key, rmp_serde::from_slice(&v).unwrap_or_default());
+    }
+    Ok(docs)
+}
+
+/// Terms of the index starting with `prefix`, in term order
+fn expand(tree: &Tree, prefix: &str) -> Result<Vec<String>, DatabaseError> {
+    let mut terms: Vec<String> = vec![];
+    for r in tree.scan_prefix(prefix.as_bytes()).keys() {
+        let (term, _) = match split_posting(&r?) {
+            Some(parts) => parts,
+            None => continue
+        };
+        if terms.last() != Some(&term) {
+            if terms.len() == MAX_PREFIX_TERMS {
+                break;
+            }
+            terms.push(term);
+        }
+    }
+    Ok(terms)
+}
+
+/// BM25 weight of a term in a document
+fn bm25(tf: usize, df: usize, dl: u32, docs: u64, avg_len: f64) -> f64 {
+    let idf = (1.0 + (docs as f64 - df as f64 + 0.5) / (df as f64 + 0.5)).ln();
+    let tf = tf as f64;
+    idf * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * dl as f64 / avg_len))
+}
+
+/// Documents matching one clause with the term and document frequency of every term they matched,
+/// the caller weighs them once the document lengths are read
+fn match_clause(tree: &Tree, clause: &Clause) -> Result<HashMap<String, Vec<(usize, usize)>>, DatabaseError> {
+    let mut hits: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+    match clause {
+        Clause::Term(term) => {
+            let docs = postings(tree, term)?;
+            let df = docs.len();
+            for (key, pos) in docs {
+                hits.entry(key).or_default().push((pos.len(), df));
+            }
+        }
+        Clause::Prefix(prefix) => {
+            for term in expand(tree, prefix)? {
+                let docs = postings(tree, &term)?;
+                let df = docs.len();
+                for (key, pos) in docs {
+                    hits.entry(key).or_default().push((pos.len(), df));
+                }
+            }
+        }
+        Clause::Phrase(terms) => {
+            let mut lists = vec![];
+            for term in terms {
+                lists.push(postings(tree, term)?);
+            }
+            let first = match lists.first() {
+                Some(l) => l,
+                None => return Ok(hits)
+            };
+            for (key, starts) in first {
+                let found = starts.iter().any(|start| {
+                    lists.iter().enumerate().skip(1).all(|(i, list)| {
+                        list.get(key).map_or(false, |pos| pos.binary_search(&(start + i as u32)).is_ok())
+                    })
+                });
+                if found {
+                    let weights = lists.iter().map(|l| (l[key].len(), l.len())).collect();
+                    hits.insert(key.to_owned(), weights);
+                }
+            }
+        }
+    }
+    Ok(hits)
+}
+
+/// Runs a `JFTSEARCH` query, documents have to match every clause and are ranked by their BM25 score
+pub fn search(query: &SearchQuery) -> Result<Vec<(String, f64)>, DatabaseError> {
+    let index = match INDEXES.get(&query.index) {
+        Some(i) => i.value().clone(),
+        None => return Err(DatabaseError::new("ERR no such full-text index"))
+    };
+    rank(&posting_tree(&index.def.name)?, &doc_tree(&index.def.name)?, index.docs, index.total_len, query)
+}
+
+/// Matches and scores a query against the postings and documents of one index holding `doc_count`
+/// documents of `total_len` terms
+fn rank(tree: &Tree, docs: &Tree, doc_count: u64, total_len: u64, query: &SearchQuery) -> Result<Vec<(String, f64)>, DatabaseError> {
+    let avg_len = if doc_count == 0 { 1.0 } else { total_len as f64 / doc_count as f64 };
+
+    let mut matches: Option<HashMap<String, Vec<(usize, usize)>>> = None;
+    for clause in &query.clauses {
+        let hits = match_clause(tree, clause)?;
+        matches = Some(match matches {
+            None => hits,
+            Some(mut m) => {
+                m.retain(|k, _| hits.contains_key(k));
+                for (k, w) in m.iter_mut() {
+                    w.extend_from_slice(&hits[k]);
+                }
+                m
+            }
+        });
+    }
+
+    let keys: BTreeSet<String> = matches.as_ref().map_or(BTreeSet::new(), |m| m.keys().cloned().collect());
+    let mut ranked = vec![];
+    for key in keys {
+        let dl = match docs.get(key.as_bytes())? {
+            Some(b) => rmp_serde::from_slice::<DocEntry>(&b).map(|e| e.len).unwrap_or(1),
+            None => continue
+        };
+        let score = matches.as_ref().unwrap()[&key].iter()
+            .map(|(tf, df)| bm25(*tf, *df, dl, doc_count, avg_len))
+            .sum();
+        ranked.push((key, score));
+    }
+    ranked.sort_by(|(ka, a): &(String, f64), (kb, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal).then_with(|| ka.cmp(kb)));
+    Ok(ranked.into_iter()
+        .skip(query.offset)
+        .take(query.limit.unwrap_or(usize::MAX))
+        .collect())
+}
+
+/// Loads the full-text index definitions and counts their documents for the BM25 statistics
+pub fn init() -> Result<(), DatabaseError> {
+    INDEXES.clear();
+    for r in db::get_db().open_tree(DEF_TREE)?.iter().values() {
+        let bytes = r?;
+        let def = match rmp_serde::from_slice::<FtIndexDef>(&bytes) {
+            Ok(def) => def,
+            Err(_) => {
+                warn!("Skipping corrupt full-text index definition");
+                continue;
+            }
+        };
+        let (mut docs, mut total_len) = (0, 0);
+        for r in doc_tree(&def.name)?.iter().values() {
+            let entry: DocEntry = rmp_serde::from_slice(&r?).unwrap_or_default();
+            docs += 1;
+            total_len += entry.len as u64;
+        }
+        INDEXES.insert(def.name.to_owned(), FtIndex { def, docs, total_len });
+    }
+    info!("Loaded {} full-text indexes", INDEXES.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(tokenize("Wi-Fi  Router, 5GHz!"), vec!["wi", "fi", "router", "5ghz"]);
+        assert_eq!(parse_query("Red \"running shoes\" snea* wi-fi \"Light\""), vec![
+            Clause::Term("red".to_owned()),
+            Clause::Phrase(vec!["running".to_owned(), "shoes".to_owned()]),
+            Clause::Prefix("snea".to_owned()),
+            Clause::Phrase(vec!["wi".to_owned(), "fi".to_owned()]),
+            Clause::Term("light".to_owned()),
+        ]);
+        assert!(parse_query(" \"\" - ").is_empty());
+    }
+
+    #[test]
+    fn test_bm25() {
+        // rarer terms and shorter documents weigh more
+        assert!(bm25(1, 1, 10, 100, 10.0) > bm25(1, 50, 10, 100, 10.0));
+        assert!(bm25(1, 5, 5, 100, 10.0) > bm25(1, 5, 20, 100, 10.0));
+        assert!(bm25(3, 5, 10, 100, 10.0) > bm25(1, 5, 10, 100, 10.0));
+        assert!(bm25(1, 100, 10, 100, 10.0) > 0.0);
+    }
+
+    /// A temporary index over `title` and `body` holding `docs`, with its document count and total length
+    fn index(docs: &[(&str, Value)]) -> (Tree, Tree, u64, u64) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let (postings, doc_entries) = (db.open_tree("postings").unwrap(), db.open_tree("docs").unwrap());
+        let def = FtIndexDef { name: "t".to_owned(), prefix: String::new(), paths: vec!["title".to_owned(), "body".to_owned()] };
+        let (mut count, mut total_len) = (0, 0);
+        for (key, doc) in docs {
+            let (d, l) = write_doc(&postings, &doc_entries, &def, key, Some(&json_pack::pack(doc))).unwrap();
+            count += d;
+            total_len += l;
+        }
+        (postings, doc_entries, count as u64, total_len as u64)
+    }
+
+    fn keys(hits: HashMap<String, Vec<(usize, usize)>>) -> BTreeSet<String> {
+        hits.into_iter().map(|(k, _)| k).collect()
+    }
+
+    #[test]
+    fn test_match_clause() {
+        let (postings, _, _, _) = index(&[
+            ("a", json!({"title": "quick brown fox"})),
+            ("b", json!({"title": "brown quick fox"})),
+            ("c", json!({"title": "the quick", "body": "brown bear"})),
+            ("d", json!({"title": ["quick", "brown"]})),
+        ]);
+        let phrase = Clause::Phrase(vec!["quick".to_owned(), "brown".to_owned()]);
+        // only adjacent terms in order match, never across two fields or two array items
+        assert_eq!(keys(match_clause(&postings, &phrase).unwrap()), vec!["a".to_owned()].into_iter().collect());
+        assert_eq!(keys(match_clause(&postings, &Clause::Term("brown".to_owned())).unwrap()).len(), 4);
+
+        let hits = match_clause(&postings, &Clause::Prefix("qu".to_owned())).unwrap();
+        assert_eq!(hits.len(), 4);
+        assert_eq!(hits["a"], vec![(1, 4)]);
+    }
+
+    #[test]
+    fn test_prefix_expansion_limit() {
+        let words: Vec<String> = (0..MAX_PREFIX_TERMS + 10).map(|i| format!("w{:04}", i)).collect();
+        let (postings, _, _, _) = index(&[("a", json!({"title": words.join(" ")}))]);
+        let terms = expand(&postings, "w").unwrap();
+        assert_eq!(terms.len(), MAX_PREFIX_TERMS);
+        assert_eq!(terms[..2], ["w0000".to_owned(), "w0001".to_owned()]);
+        assert_eq!(expand(&postings, "w0001").unwrap(), vec!["w0001".to_owned()]);
+        assert!(expand(&postings, "x").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rank() {
+        let (postings, docs, count, total_len) = index(&[
+            ("long", json!({"title": "tales", "body": "a story about many animals and one red fox"})),
+            ("short", json!({"title": "fox fox"})),
+            ("dog", json!({"title": "lazy dog", "body": "the fox jumps over"})),
+            ("none", json!({"title": "nothing here"})),
+        ]);
+        let query = |text: &str| SearchQuery { index: "t".to_owned(), clauses: parse_query(text), offset: 0, limit: None };
+        let found = |text: &str| -> Vec<String> {
+            rank(&postings, &docs, count, total_len, &query(text)).unwrap().into_iter().map(|(k, _)| k).collect()
+        };
+
+        // more occurrences in a shorter document rank higher
+        assert_eq!(found("fox"), vec!["short", "dog", "long"]);
+        // every clause has to match
+        assert_eq!(found("fox dog"), vec!["dog"]);
+        assert_eq!(found("\"red fox\" animals"), vec!["long"]);
+        assert!(found("fox cat").is_empty());
+
+        let mut page = query("fox");
+        page.offset = 1;
+        page.limit = Some(1);
+        let hits = rank(&postings, &docs, count, total_len, &page).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].0, "dog");
+    }
+}
@@ -16,6 +16,7 @@ mod error;
 mod printer;
 mod util;
 mod geo;
+mod geo_store;
 mod unit_conv;
 mod tokenizer;
 mod config;
@@ -23,6 +24,12 @@ mod syntax_analyzer;
 mod file_dirs;
 mod codec;
 mod json;
+mod json_index;
+mod json_schema;
+mod json_pack;
+mod json_agg;
+mod json_search;
+mod json_history;
 mod storage;
 mod persistence;
 mod rpc;
@@ -128,6 +135,7 @@ async fn main() -> Result<()> {
     info!("PID: {}", std::process::id());
     config::load_conf(true).await?;
     db::init().await;
+    geo_store::start_sweeper().await;
     storage::monitor_metrics();
     let rpc_addrs = format!("127.0.0.1:{}", rpc);
     let n = network::start_up( addrs);
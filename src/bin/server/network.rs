@@ -17,6 +17,8 @@ use crate::codec::{RespCodec, ClientRequest, ServerResponse};
 use std::net::{SocketAddr, Shutdown};
 use serde_yaml::Value;
 use crate::config;
+use crate::util;
+use crate::error::SyntaxError;
 use async_raft::raft::{VoteRequest, InstallSnapshotRequest, AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotResponse, VoteResponse, ClientWriteRequest, ClientWriteResponse, ConflictOpt};
 
 use bytes::{BytesMut};
@@ -179,9 +181,21 @@ fn process_socket(socket: TcpStream) {
         while let Some(message) = lines.next().await {
             match message {
                 Ok(frame) => {
+                    if command::is_local_read(&frame) {
+                        let context = context.clone();
+                        let f = tokio::task::spawn_blocking(move || command::execute_frame(&frame, context)).await
+                            .unwrap_or_else(|_| Frame::Error("SERVER ERROR".to_owned()));
+                        lines.send(f).await;
+                        continue;
+                    }
+                    if command::is_internal(&frame) {
+                        lines.send(Frame::Error(SyntaxError.to_string())).await;
+                        continue;
+                    }
                     let r = RAFT.client_write(ClientWriteRequest::new(ClientRequest {
                         context: context.clone(),
                         frame,
+                        timestamp: util::now_ms(),
                     })).await;
 
                     match r {
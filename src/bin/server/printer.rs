@@ -23,6 +23,12 @@ pub trait GeoJsonFeature {
     fn geo_json_feature(&self) -> Value;
 }
 
+impl<T: GeoJsonFeature> GeoJsonFeature for &T {
+    fn geo_json_feature(&self) -> Value {
+        (*self).geo_json_feature()
+    }
+}
+
 pub fn print_err(msg: &str) -> String {
     format!("{}{}{}", ERROR_PREFIX, msg, CRLF)
 }
@@ -65,6 +71,16 @@ pub fn print_nested_arr<T: ToString>(arr: Vec<Vec<T>>) -> String {
     str
 }
 
+/// Array of replies that are already encoded, for arrays mixing strings and nils
+pub fn print_reply_arr(replies: Vec<String>) -> String {
+    let mut str = String::new();
+    str += &format!("{}{}{}", ARRAY_PREFIX, replies.len(), CRLF);
+    for r in replies {
+        str += &r
+    };
+    str
+}
+
 pub fn print_string(str: &String) -> String {
     format!("{}{}{}{}{}", STRING_PREFIX, str.len(), CRLF, str, CRLF)
 }
@@ -82,17 +98,81 @@ pub fn print_pong() -> String {
 }
 
 
-pub fn build_geo_json<T: GeoJsonFeature>(f: &Vec<T>) -> Value {
-    let features: Vec<Value> = f.iter().map(|m| {
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GeoJsonOptions {
+    pub bbox: bool,
+    pub precision: Option<u32>,
+}
+
+pub fn build_geo_json<T: GeoJsonFeature>(f: &Vec<T>, options: &GeoJsonOptions) -> Value {
+    let mut features: Vec<Value> = f.iter().map(|m| {
         m.geo_json_feature()
     }).collect();
 
-    json!(
+    if let Some(precision) = options.precision {
+        for feature in &mut features {
+            round_coordinates(&mut feature["geometry"]["coordinates"], precision);
+            if let Some(bbox) = feature.get_mut("bbox") {
+                round_coordinates(bbox, precision);
+            }
+        }
+    }
+
+    let mut collection = json!(
         {
           "type": "FeatureCollection",
           "features": features
         }
-    )
+    );
+
+    if options.bbox {
+        let mut bbox = [std::f64::MAX, std::f64::MAX, std::f64::MIN, std::f64::MIN];
+        for feature in &features {
+            extend_bbox(&feature["geometry"]["coordinates"], &mut bbox);
+        }
+        if !features.is_empty() {
+            collection["bbox"] = json!(bbox);
+        }
+    }
+    collection
+}
+
+fn round_coordinates(coordinates: &mut Value, precision: u32) {
+    let factor = 10_f64.powi(precision as i32);
+    match coordinates {
+        Value::Array(items) => {
+            for item in items {
+                round_coordinates(item, precision)
+            }
+        }
+        Value::Number(n) => {
+            let rounded = (n.as_f64().unwrap_or(0.0) * factor).round() / factor;
+            *coordinates = json!(rounded);
+        }
+        _ => {}
+    }
+}
+
+fn extend_bbox(coordinates: &Value, bbox: &mut [f64; 4]) {
+    let items = match coordinates.as_array() {
+        Some(items) => items,
+        None => {
+            return;
+        }
+    };
+    match (items.get(0).and_then(|x| x.as_f64()), items.get(1).and_then(|y| y.as_f64())) {
+        (Some(x), Some(y)) => {
+            bbox[0] = bbox[0].min(x);
+            bbox[1] = bbox[1].min(y);
+            bbox[2] = bbox[2].max(x);
+            bbox[3] = bbox[3].max(y);
+        }
+        _ => {
+            for item in items {
+                extend_bbox(item, bbox)
+            }
+        }
+    }
 }
 
 pub fn print_app_info() {
@@ -123,4 +203,21 @@ fn test_array_print() {
     let sample = print_string_arr(sample_arr);
 
     assert_eq!(sample, tester)
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_geo_json_options() {
+    struct Feature(f64, f64);
+    impl GeoJsonFeature for Feature {
+        fn geo_json_feature(&self) -> Value {
+            json!({"type": "Feature", "geometry": {"type": "Point", "coordinates": [self.0, self.1]}})
+        }
+    }
+
+    let features = vec![Feature(-1.596427, 5.506981), Feature(-1.372422, 5.194894)];
+    let collection = build_geo_json(&features, &GeoJsonOptions { bbox: true, precision: Some(2) });
+
+    assert_eq!(collection["features"][0]["geometry"]["coordinates"], json!([-1.6, 5.51]));
+    assert_eq!(collection["bbox"], json!([-1.6, 5.19, -1.37, 5.51]));
+    assert!(build_geo_json(&features, &GeoJsonOptions::default()).get("bbox").is_none());
+}
@@ -16,7 +16,7 @@ use serde::{Serialize, Deserialize};
 use itertools::Itertools;
 use bincode::ErrorKind;
 use rayon::prelude::*;
-use crate::{db, EscanorRaft};
+use crate::{db, util, EscanorRaft};
 use crate::command;
 use redis_protocol::types::{Frame, RedisProtocolError};
 use redis_protocol;
@@ -27,6 +27,7 @@ use byteorder::{ByteOrder, BigEndian};
 use tracing::Instrument;
 use tracing::{debug, error, info, span, warn, Level};
 use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::file_dirs::create_db_folder;
 use crate::command::{AddClusterCmd, RemClusterCmd, ClusterCmd, ClusterMetrics, ClusterSetNodeId};
@@ -49,10 +50,25 @@ lazy_static!(
   };
 );
 
+/// Clock of the log entry being applied, see `entry_time`
+static ENTRY_TIME: AtomicU64 = AtomicU64::new(0);
+
 pub fn init() {
     lazy_static::initialize(&SYS_STATE);
 }
 
+/// Milliseconds since the epoch when the log entry being applied was proposed. Writes that depend on
+/// the time read this instead of the local clock, so every replica and every replay of the log agrees.
+pub fn entry_time() -> u64 {
+    ENTRY_TIME.load(Ordering::SeqCst)
+}
+
+fn set_entry_time(request: &ClientRequest) {
+    // entries logged before requests carried a timestamp fall back to the local clock
+    let timestamp = if request.timestamp == 0 { util::now_ms() } else { request.timestamp };
+    ENTRY_TIME.store(timestamp, Ordering::SeqCst);
+}
+
 pub async fn monitor_metrics() {
     tokio::task::spawn(async {
         let state = SYS_STATE.clone();
@@ -377,6 +393,7 @@ impl RaftStorage<ClientRequest, ServerResponse> for Storage {
     async fn apply_entry_to_state_machine(&self, index: &u64, data: &ClientRequest) -> Result<ServerResponse> {
         let context = data.context.clone();
         let frame = &data.frame;
+        set_entry_time(data);
 
         let response_message = match command::compile_frame(frame) {
             Ok(cmd) => {
@@ -414,6 +431,7 @@ impl RaftStorage<ClientRequest, ServerResponse> for Storage {
         for (index, entry) in entries {
             let frame = &entry.frame;
             let context = entry.context.clone();
+            set_entry_time(entry);
             let cmd = command::compile_frame(frame)?;
             cmd.execute(context);
             let mut buff = [0; 16];
@@ -1,10 +1,18 @@
 use crate::command::*;
-use crate::{error, util, unit_conv};
+use crate::{error, storage, util, unit_conv};
 use serde_json::{Value};
 
 use crate::db::Data;
 use crate::error::ParseDataError;
 use std::str::FromStr;
+use std::slice::Iter;
+use crate::geo::{GeoFilter, FilterCondition, FilterOp, SpatialOp};
+use crate::printer::GeoJsonOptions;
+use crate::json::JsonFormat;
+use crate::json_index::FindQuery;
+use crate::json_agg::{AggOp, AggQuery};
+use crate::json_search::{self, SearchQuery};
+use crate::json_history::{self, HistoryPolicy};
 
 
 pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, error::SyntaxError> {
@@ -215,6 +223,30 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
     else if cmd == "geoadd" {
         let arg_key = itr.next().unwrap_or(&empty_string);
         if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        // options before the members: EX seconds | PX milliseconds, TRACK
+        let mut arg_ttl_ms: Option<u64> = None;
+        let mut arg_track = false;
+        loop {
+            let option = itr.as_slice().first().map(|t| t.to_lowercase()).unwrap_or_default();
+            if option == "ex" || option == "px" {
+                itr.next();
+                let ttl = match itr.next().unwrap_or(&empty_string).parse::<u64>() {
+                    Ok(t) if t > 0 => t,
+                    _ => {
+                        return Err(error::SyntaxError);
+                    }
+                };
+                let ttl = if option == "ex" { ttl.checked_mul(1000) } else { Some(ttl) };
+                arg_ttl_ms = Some(ttl.ok_or(error::SyntaxError)?);
+            } else if option == "track" {
+                itr.next();
+                arg_track = true;
+            } else {
+                break;
+            }
+        }
+
         let mut items_after_key: Vec<&String> = vec![];
 
         while let Some(i) = itr.next() {
@@ -247,8 +279,16 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
             items.push((lat, lng, tag))
         }
 
+        // absolute, so every replica expires the members at the same moment
+        let arg_expires_at = match arg_ttl_ms {
+            Some(ttl) => Some(storage::entry_time().checked_add(ttl).ok_or(error::SyntaxError)?),
+            None => None
+        };
         return Ok(Box::new(GeoAddCmd {
             arg_key: arg_key.to_owned(),
+            arg_expires_at,
+            arg_track,
+            arg_timestamp: storage::entry_time(),
             items,
         }));
     } else if cmd == "geojson" {
@@ -256,17 +296,24 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
         if arg_key.is_empty() { return Err(error::SyntaxError); }
         let mut items_after_key: Vec<String> = vec![];
 
-        while let Some(i) = itr.next() {
+        // no members exports the whole key
+        while let Some(i) = itr.as_slice().first() {
+            if is_geo_json_option(i) {
+                break;
+            }
             items_after_key.push(i.to_owned());
+            itr.next();
         }
 
-        if items_after_key.is_empty() {
+        let arg_options = parse_geo_json_options(&mut itr)?;
+        if itr.next().is_some() {
             return Err(error::SyntaxError);
         }
 
         return Ok(Box::new(GeoJsonCmd {
             arg_key: arg_key.to_owned(),
             items: items_after_key,
+            arg_options,
         }));
     } else if cmd == "geohash" {
         let arg_key = itr.next().unwrap_or(&empty_string);
@@ -325,15 +372,10 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
             }
         };
 
-        let arg_order_string = itr.next().unwrap_or(&empty_string).to_lowercase();
         let mut arg_order = ArgOrder::UNSPECIFIED;
-
-        match check_validate_arg_order(arg_order_string, &mut arg_order) {
-            Ok(()) => (),
-            Err(e) => {
-                return Err(e);
-            }
-        };
+        let mut arg_filter = None;
+        let mut arg_geo_json = None;
+        parse_geo_search_options(&mut itr, Some(&mut arg_order), &mut arg_filter, &mut arg_geo_json)?;
 
         if !(util::is_numeric(arg_lng) && util::is_numeric(arg_lng) && util::is_numeric(arg_radius)) {
             return Err(error::SyntaxError);
@@ -350,6 +392,8 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
             arg_radius: rads,
             arg_unit,
             arg_order,
+            arg_filter,
+            arg_geo_json,
         }));
     } else if cmd == "geodist" {
         let arg_key = itr.next().unwrap_or(&empty_string);
@@ -371,11 +415,20 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
             }
         };
 
+        let arg_wgs84 = match itr.next() {
+            None => false,
+            Some(option) if option.to_lowercase() == "wgs84" => true,
+            Some(_) => {
+                return Err(error::SyntaxError);
+            }
+        };
+
         return Ok(Box::new(GeoDistCmd {
             arg_key: arg_key.to_owned(),
             arg_mem_1: member_1.to_owned(),
             arg_mem_2: member_2.to_owned(),
             arg_unit,
+            arg_wgs84,
         }));
     } else if cmd == "georadiusbymember" {
         let arg_key = itr.next().unwrap_or(&empty_string);
@@ -397,15 +450,10 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
             }
         };
 
-        let arg_order_string = itr.next().unwrap_or(&empty_string).to_lowercase();
         let mut arg_order = ArgOrder::UNSPECIFIED;
-
-        match check_validate_arg_order(arg_order_string, &mut arg_order) {
-            Ok(()) => (),
-            Err(e) => {
-                return Err(e);
-            }
-        };
+        let mut arg_filter = None;
+        let mut arg_geo_json = None;
+        parse_geo_search_options(&mut itr, Some(&mut arg_order), &mut arg_filter, &mut arg_geo_json)?;
 
         if !(util::is_numeric(arg_radius)) {
             return Err(error::SyntaxError);
@@ -419,8 +467,259 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
                 arg_radius: rads,
                 arg_unit,
                 arg_order,
+                arg_filter,
+                arg_geo_json,
             }
         ));
+    } else if cmd == "geonearest" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_lng = itr.next().unwrap_or(&empty_string);
+        let arg_lat = itr.next().unwrap_or(&empty_string);
+        if !(util::is_numeric(arg_lng) && util::is_numeric(arg_lat)) {
+            return Err(error::SyntaxError);
+        }
+
+        let arg_count = match itr.next().unwrap_or(&empty_string).parse::<usize>() {
+            Ok(c) if c > 0 => c,
+            _ => {
+                return Err(error::SyntaxError);
+            }
+        };
+
+        let arg_unit_string = &itr.next().unwrap_or(&empty_string).to_lowercase();
+        let arg_unit = match unit_conv::parse(arg_unit_string) {
+            Ok(unit) => unit,
+            Err(_e) => {
+                return Err(error::SyntaxError);
+            }
+        };
+
+        let mut arg_max_dist = None;
+        let mut arg_filter = None;
+        let mut arg_geo_json = None;
+        while let Some(option) = itr.next() {
+            match option.to_lowercase().as_str() {
+                "maxdist" => {
+                    let arg_dist = itr.next().unwrap_or(&empty_string);
+                    if !util::is_numeric(arg_dist) { return Err(error::SyntaxError); }
+                    arg_max_dist = Some(arg_dist.parse::<f64>().unwrap());
+                }
+                "where" => arg_filter = Some(parse_where_clause(&mut itr)?),
+                "geojson" => arg_geo_json = Some(parse_geo_json_options(&mut itr)?),
+                _ => {
+                    return Err(error::SyntaxError);
+                }
+            }
+        }
+
+        return Ok(Box::new(GeoNearestCmd {
+            arg_key: arg_key.to_owned(),
+            arg_lng: arg_lng.parse::<f64>().unwrap(),
+            arg_lat: arg_lat.parse::<f64>().unwrap(),
+            arg_count,
+            arg_unit,
+            arg_max_dist,
+            arg_filter,
+            arg_geo_json,
+        }));
+    } else if cmd == "geobox" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let mut bounds: Vec<f64> = vec![];
+        for _ in 0..4 {
+            let arg = itr.next().unwrap_or(&empty_string);
+            if !util::is_numeric(arg) { return Err(error::SyntaxError); }
+            bounds.push(arg.parse::<f64>().unwrap());
+        }
+
+        let mut arg_filter = None;
+        let mut arg_geo_json = None;
+        parse_geo_search_options(&mut itr, None, &mut arg_filter, &mut arg_geo_json)?;
+
+        return Ok(Box::new(GeoBoxCmd {
+            arg_key: arg_key.to_owned(),
+            arg_min_lng: bounds[0],
+            arg_min_lat: bounds[1],
+            arg_max_lng: bounds[2],
+            arg_max_lat: bounds[3],
+            arg_filter,
+            arg_geo_json,
+        }));
+    } else if cmd == "geocluster" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let mut bounds: Vec<f64> = vec![];
+        for _ in 0..4 {
+            let arg = itr.next().unwrap_or(&empty_string);
+            if !util::is_numeric(arg) { return Err(error::SyntaxError); }
+            bounds.push(arg.parse::<f64>().unwrap());
+        }
+
+        let arg_zoom = match itr.next().unwrap_or(&empty_string).parse::<u8>() {
+            Ok(z) if z <= 24 => z,
+            _ => {
+                return Err(error::SyntaxError);
+            }
+        };
+
+        let arg_bounds = itr.as_slice().first().map_or(false, |t| t.to_lowercase() == "bounds");
+        if arg_bounds {
+            itr.next();
+        }
+
+        let mut arg_filter = None;
+        let mut arg_geo_json = None;
+        parse_geo_search_options(&mut itr, None, &mut arg_filter, &mut arg_geo_json)?;
+
+        return Ok(Box::new(GeoClusterCmd {
+            arg_key: arg_key.to_owned(),
+            arg_min_lng: bounds[0],
+            arg_min_lat: bounds[1],
+            arg_max_lng: bounds[2],
+            arg_max_lat: bounds[3],
+            arg_zoom,
+            arg_bounds,
+            arg_filter,
+            arg_geo_json,
+        }));
+    } else if cmd == "geopolygon" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let mut coordinates: Vec<f64> = vec![];
+        while let Some(i) = itr.as_slice().first() {
+            if !util::is_numeric(i) {
+                break;
+            }
+            coordinates.push(i.parse::<f64>().unwrap());
+            itr.next();
+        }
+        // a polygon needs at least three vertices given as lng lat pairs
+        if coordinates.len() < 6 || coordinates.len() % 2 != 0 {
+            return Err(error::SyntaxError);
+        }
+        let arg_vertices: Vec<(f64, f64)> = coordinates.chunks_exact(2).map(|c| (c[0], c[1])).collect();
+
+        let mut arg_filter = None;
+        let mut arg_geo_json = None;
+        parse_geo_search_options(&mut itr, None, &mut arg_filter, &mut arg_geo_json)?;
+
+        return Ok(Box::new(GeoPolygonCmd {
+            arg_key: arg_key.to_owned(),
+            arg_vertices,
+            arg_filter,
+            arg_geo_json,
+        }));
+    } else if cmd == "geoquery" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_op = match itr.next().unwrap_or(&empty_string).to_lowercase().as_str() {
+            "intersects" => SpatialOp::Intersects,
+            "contains" => SpatialOp::Contains,
+            "within" => SpatialOp::Within,
+            _ => {
+                return Err(error::SyntaxError);
+            }
+        };
+
+        let target = itr.next().unwrap_or(&empty_string).to_lowercase();
+        let arg_value = itr.next().unwrap_or(&empty_string);
+        if arg_value.is_empty() { return Err(error::SyntaxError); }
+        let arg_target = match target.as_str() {
+            "geometry" => GeoQueryTarget::Geometry(arg_value.to_owned()),
+            "member" => GeoQueryTarget::Member(arg_value.to_owned()),
+            _ => {
+                return Err(error::SyntaxError);
+            }
+        };
+
+        let mut arg_filter = None;
+        let mut arg_geo_json = None;
+        parse_geo_search_options(&mut itr, None, &mut arg_filter, &mut arg_geo_json)?;
+
+        return Ok(Box::new(GeoQueryCmd {
+            arg_key: arg_key.to_owned(),
+            arg_op,
+            arg_target,
+            arg_filter,
+            arg_geo_json,
+        }));
+    } else if cmd == "geoaddshape" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_member = itr.next().unwrap_or(&empty_string);
+        if arg_member.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_value = itr.next().unwrap_or(&empty_string);
+        if arg_value.is_empty() || itr.next().is_some() { return Err(error::SyntaxError); }
+
+        return Ok(Box::new(GeoAddShapeCmd {
+            arg_key: arg_key.to_owned(),
+            arg_member: arg_member.to_owned(),
+            arg_value: arg_value.to_owned(),
+        }));
+    } else if cmd == "geoimport" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_format = match itr.next().unwrap_or(&empty_string).to_lowercase().as_str() {
+            "geojson" => GeoImportFormat::GeoJson,
+            "csv" => GeoImportFormat::Csv,
+            _ => {
+                return Err(error::SyntaxError);
+            }
+        };
+
+        let arg_value = itr.next().unwrap_or(&empty_string);
+        if arg_value.is_empty() { return Err(error::SyntaxError); }
+
+        return Ok(Box::new(GeoImportCmd {
+            arg_key: arg_key.to_owned(),
+            arg_format,
+            arg_value: arg_value.to_owned(),
+        }));
+    } else if cmd == "geosetattr" || cmd == "geomergeattr" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_member = itr.next().unwrap_or(&empty_string);
+        if arg_member.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_value = itr.next().unwrap_or(&empty_string);
+        if arg_value.is_empty() { return Err(error::SyntaxError); }
+
+        if cmd == "geosetattr" {
+            return Ok(Box::new(GeoSetAttrCmd {
+                arg_key: arg_key.to_owned(),
+                arg_member: arg_member.to_owned(),
+                arg_value: arg_value.to_owned(),
+            }));
+        }
+        return Ok(Box::new(GeoMergeAttrCmd {
+            arg_key: arg_key.to_owned(),
+            arg_member: arg_member.to_owned(),
+            arg_value: arg_value.to_owned(),
+        }));
+    } else if cmd == "geogetattr" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_member = itr.next().unwrap_or(&empty_string);
+        if arg_member.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_value = itr.next().unwrap_or(&empty_string);
+
+        return Ok(Box::new(GeoGetAttrCmd {
+            arg_key: arg_key.to_owned(),
+            arg_member: arg_member.to_owned(),
+            arg_dot_path: if arg_value.is_empty() { None } else { Some(arg_value.to_owned()) },
+        }));
     } else if cmd == "geodel" {
         let arg_key = itr.next().unwrap_or(&empty_string);
         if arg_key.is_empty() { return Err(error::SyntaxError); }
@@ -444,6 +743,99 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
             arg_key: arg_key.to_owned(),
             items: items_after_key,
         }));
+    } else if cmd == "geotrack" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_member = itr.next().unwrap_or(&empty_string);
+        if arg_member.is_empty() { return Err(error::SyntaxError); }
+
+        let mut arg_from: u64 = 0;
+        let mut arg_to: u64 = std::u64::MAX;
+        let mut arg_geo_json: Option<GeoJsonOptions> = None;
+
+        while let Some(option) = itr.next() {
+            match option.to_lowercase().as_str() {
+                "from" => {
+                    arg_from = itr.next().unwrap_or(&empty_string).parse::<u64>().map_err(|_| error::SyntaxError)?;
+                }
+                "to" => {
+                    arg_to = itr.next().unwrap_or(&empty_string).parse::<u64>().map_err(|_| error::SyntaxError)?;
+                }
+                "geojson" => {
+                    arg_geo_json = Some(parse_geo_json_options(&mut itr)?);
+                }
+                _ => {
+                    return Err(error::SyntaxError);
+                }
+            }
+        }
+
+        return Ok(Box::new(GeoTrackCmd {
+            arg_key: arg_key.to_owned(),
+            arg_member: arg_member.to_owned(),
+            arg_from,
+            arg_to,
+            arg_geo_json,
+        }));
+    } else if cmd == "geojoin" {
+        let arg_left_key = itr.next().unwrap_or(&empty_string);
+        let arg_right_key = itr.next().unwrap_or(&empty_string);
+        if arg_left_key.is_empty() || arg_right_key.is_empty() { return Err(error::SyntaxError); }
+
+        let mode = itr.next().unwrap_or(&empty_string).to_lowercase();
+        let arg_value = itr.next().unwrap_or(&empty_string);
+        let arg_unit = match unit_conv::parse(&itr.next().unwrap_or(&empty_string).to_lowercase()) {
+            Ok(unit) => unit,
+            Err(_e) => {
+                return Err(error::SyntaxError);
+            }
+        };
+
+        let mut arg_mode = match mode.as_str() {
+            "within" if util::is_numeric(arg_value) => GeoJoinMode::Within(arg_value.parse::<f64>().unwrap()),
+            "nearest" => match arg_value.parse::<usize>() {
+                Ok(k) if k > 0 => GeoJoinMode::Nearest(k, None),
+                _ => {
+                    return Err(error::SyntaxError);
+                }
+            },
+            _ => {
+                return Err(error::SyntaxError);
+            }
+        };
+
+        let mut arg_store = None;
+        while let Some(option) = itr.next() {
+            match (option.to_lowercase().as_str(), &mut arg_mode) {
+                ("maxdist", GeoJoinMode::Nearest(_, max_dist)) => {
+                    let arg_dist = itr.next().unwrap_or(&empty_string);
+                    if !util::is_numeric(arg_dist) { return Err(error::SyntaxError); }
+                    *max_dist = Some(arg_dist.parse::<f64>().unwrap());
+                }
+                ("store", _) => {
+                    let dest = itr.next().unwrap_or(&empty_string);
+                    if dest.is_empty() { return Err(error::SyntaxError); }
+                    arg_store = Some(dest.to_owned());
+                }
+                _ => {
+                    return Err(error::SyntaxError);
+                }
+            }
+        }
+
+        return Ok(Box::new(GeoJoinCmd {
+            arg_left_key: arg_left_key.to_owned(),
+            arg_right_key: arg_right_key.to_owned(),
+            arg_mode,
+            arg_unit,
+            arg_store,
+        }));
+    } else if cmd == "geosweep" {
+        if itr.next().is_some() { return Err(error::SyntaxError); }
+        return Ok(Box::new(GeoSweepCmd {
+            arg_timestamp: storage::entry_time(),
+        }));
     } else if cmd == "jsetr" {
         let arg_key = itr.next().unwrap_or(&empty_string);
         if arg_key.is_empty() { return Err(error::SyntaxError); }
@@ -504,15 +896,21 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
             arg_key: arg_key.to_owned(),
             arg_value: arg_value.to_owned(),
         }));
-    } else if cmd == "jget" {
+    } else if cmd == "jpatch" {
         let arg_key = itr.next().unwrap_or(&empty_string);
         if arg_key.is_empty() { return Err(error::SyntaxError); }
-        let arg_value = itr.next().unwrap_or(&empty_string);
 
-        return Ok(Box::new(JGetCmd {
+        let arg_value = itr.next().unwrap_or(&empty_string);
+        if arg_value.is_empty() { return Err(error::SyntaxError); }
+        return Ok(Box::new(JPatchCmd {
             arg_key: arg_key.to_owned(),
-            arg_dot_path: if arg_value.is_empty() { None } else { Some(arg_value.to_owned()) },
+            arg_value: arg_value.to_owned(),
         }));
+    } else if cmd == "jget" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        return Ok(Box::new(parse_jget(arg_key, &mut itr)?));
     } else if cmd == "jpath" {
         let arg_key = itr.next().unwrap_or(&empty_string);
         if arg_key.is_empty() { return Err(error::SyntaxError); }
@@ -523,6 +921,26 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
             arg_key: arg_key.to_owned(),
             arg_selector: arg_selector.to_owned(),
         }));
+    } else if cmd == "jpathscan" {
+        let arg_pattern = itr.next().unwrap_or(&empty_string);
+        if arg_pattern.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_selector = itr.next().unwrap_or(&empty_string);
+        if arg_selector.is_empty() { return Err(error::SyntaxError); }
+        return Ok(Box::new(JPathScanCmd {
+            arg_pattern: arg_pattern.to_owned(),
+            arg_selector: arg_selector.to_owned(),
+        }));
+    } else if cmd == "jmget" {
+        let mut args: Vec<String> = itr.cloned().collect();
+        if args.len() < 2 { return Err(error::SyntaxError); }
+
+        // the path comes last, after every key
+        let arg_path = args.pop().unwrap();
+        return Ok(Box::new(JMGetCmd {
+            arg_keys: args,
+            arg_path,
+        }));
     } else if cmd == "jdel" {
         let arg_key = itr.next().unwrap_or(&empty_string);
         if arg_key.is_empty() { return Err(error::SyntaxError); }
@@ -550,6 +968,392 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
             arg_paths: items_after_key
         }));
     }
+    else if cmd == "jpathset" || cmd == "jpathdel" || cmd == "jpathincr" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_selector = itr.next().unwrap_or(&empty_string);
+        if arg_selector.is_empty() { return Err(error::SyntaxError); }
+
+        if cmd == "jpathdel" {
+            return Ok(Box::new(JPathDelCmd {
+                arg_key: arg_key.to_owned(),
+                arg_selector: arg_selector.to_owned(),
+            }));
+        }
+
+        let arg_value = itr.next().unwrap_or(&empty_string);
+        if arg_value.is_empty() { return Err(error::SyntaxError); }
+
+        if cmd == "jpathincr" {
+            if !util::is_numeric(arg_value) {
+                return Err(error::SyntaxError);
+            }
+            return Ok(Box::new(JPathIncrCmd {
+                arg_key: arg_key.to_owned(),
+                arg_selector: arg_selector.to_owned(),
+                arg_increment_value: arg_value.parse::<f64>().unwrap(),
+            }));
+        }
+
+        return Ok(Box::new(JPathSetCmd {
+            arg_key: arg_key.to_owned(),
+            arg_selector: arg_selector.to_owned(),
+            arg_value: parse_json_value(arg_value),
+        }));
+    }
+    else if cmd == "jtype" || cmd == "jobjkeys" || cmd == "jobjlen" || cmd == "jstrlen" {
+        let arg_key = itr.next().unwrap_or(&empty_string).to_owned();
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        // the path defaults to the whole document
+        let arg_path = itr.next().map_or(".".to_owned(), |p| p.to_owned());
+        if itr.next().is_some() { return Err(error::SyntaxError); }
+
+        return Ok(match cmd.as_str() {
+            "jtype" => Box::new(JTypeCmd { arg_key, arg_path }),
+            "jobjkeys" => Box::new(JObjKeysCmd { arg_key, arg_path }),
+            "jobjlen" => Box::new(JObjLenCmd { arg_key, arg_path }),
+            _ => Box::new(JStrLenCmd { arg_key, arg_path })
+        });
+    }
+    else if cmd == "jstrappend" || cmd == "jtoggle" {
+        let arg_key = itr.next().unwrap_or(&empty_string).to_owned();
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_path = itr.next().unwrap_or(&empty_string).to_owned();
+        if arg_path.is_empty() { return Err(error::SyntaxError); }
+
+        if cmd == "jtoggle" {
+            return Ok(Box::new(JToggleCmd { arg_key, arg_path }));
+        }
+
+        let arg_value = itr.next().unwrap_or(&empty_string);
+        if arg_value.is_empty() { return Err(error::SyntaxError); }
+
+        // a quoted json string is appended without its quotes
+        let arg_value = serde_json::from_str::<String>(arg_value).unwrap_or(arg_value.to_owned());
+        return Ok(Box::new(JStrAppendCmd { arg_key, arg_path, arg_value }));
+    }
+    else if cmd == "jindex" {
+        let arg_name = itr.next().unwrap_or(&empty_string);
+        let arg_prefix = itr.next().unwrap_or(&empty_string);
+        let arg_path = itr.next().unwrap_or(&empty_string);
+        if arg_name.is_empty() || arg_path.is_empty() || itr.next().is_some() {
+            return Err(error::SyntaxError);
+        }
+        return Ok(Box::new(JIndexCmd {
+            arg_name: arg_name.to_owned(),
+            arg_prefix: arg_prefix.trim_end_matches('*').to_owned(),
+            arg_path: arg_path.to_owned(),
+        }));
+    }
+    else if cmd == "jindexdrop" {
+        let arg_name = itr.next().unwrap_or(&empty_string);
+        if arg_name.is_empty() { return Err(error::SyntaxError); }
+        return Ok(Box::new(JIndexDropCmd {
+            arg_name: arg_name.to_owned(),
+        }));
+    }
+    else if cmd == "jindexlist" {
+        return Ok(Box::new(JIndexListCmd));
+    }
+    else if cmd == "jfind" {
+        let arg_prefix = itr.next().ok_or(error::SyntaxError)?;
+        let mut query = FindQuery {
+            prefix: arg_prefix.trim_end_matches('*').to_owned(),
+            ..FindQuery::default()
+        };
+        while let Some(option) = itr.next() {
+            match option.to_lowercase().as_str() {
+                "where" => query.filter = parse_where_clause(&mut itr)?,
+                "sortby" => {
+                    query.sort_by = Some(itr.next().ok_or(error::SyntaxError)?.to_owned());
+                    match itr.as_slice().first().map(|o| o.to_lowercase()) {
+                        Some(o) if o == "asc" => { itr.next(); }
+                        Some(o) if o == "desc" => {
+                            itr.next();
+                            query.descending = true;
+                        }
+                        _ => {}
+                    }
+                }
+                "limit" => {
+                    let offset = itr.next().and_then(|o| o.parse::<usize>().ok()).ok_or(error::SyntaxError)?;
+                    let count = itr.next().and_then(|c| c.parse::<usize>().ok()).ok_or(error::SyntaxError)?;
+                    query.offset = offset;
+                    query.limit = Some(count);
+                }
+                _ => {
+                    return Err(error::SyntaxError);
+                }
+            }
+        }
+        return Ok(Box::new(JFindCmd {
+            arg_query: query,
+        }));
+    }
+    else if cmd == "jftindex" {
+        let arg_name = itr.next().unwrap_or(&empty_string);
+        let arg_prefix = itr.next().unwrap_or(&empty_string);
+        let arg_paths: Vec<String> = itr.cloned().collect();
+        if arg_name.is_empty() || arg_paths.is_empty() {
+            return Err(error::SyntaxError);
+        }
+        return Ok(Box::new(JFtIndexCmd {
+            arg_name: arg_name.to_owned(),
+            arg_prefix: arg_prefix.trim_end_matches('*').to_owned(),
+            arg_paths,
+        }));
+    }
+    else if cmd == "jftdrop" {
+        let arg_name = itr.next().unwrap_or(&empty_string);
+        if arg_name.is_empty() { return Err(error::SyntaxError); }
+        return Ok(Box::new(JFtDropCmd {
+            arg_name: arg_name.to_owned(),
+        }));
+    }
+    else if cmd == "jftlist" {
+        return Ok(Box::new(JFtListCmd));
+    }
+    else if cmd == "jftsearch" {
+        let arg_name = itr.next().ok_or(error::SyntaxError)?;
+        let arg_text = itr.next().ok_or(error::SyntaxError)?;
+        let mut query = SearchQuery {
+            index: arg_name.to_owned(),
+            clauses: json_search::parse_query(arg_text),
+            ..SearchQuery::default()
+        };
+        if query.clauses.is_empty() {
+            return Err(error::SyntaxError);
+        }
+        while let Some(option) = itr.next() {
+            match option.to_lowercase().as_str() {
+                "limit" => {
+                    let offset = itr.next().and_then(|o| o.parse::<usize>().ok()).ok_or(error::SyntaxError)?;
+                    let count = itr.next().and_then(|c| c.parse::<usize>().ok()).ok_or(error::SyntaxError)?;
+                    query.offset = offset;
+                    query.limit = Some(count);
+                }
+                _ => {
+                    return Err(error::SyntaxError);
+                }
+            }
+        }
+        return Ok(Box::new(JFtSearchCmd {
+            arg_query: query,
+        }));
+    }
+    else if cmd == "jhistory" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let mut policy = HistoryPolicy::default();
+        while let Some(option) = itr.next() {
+            match option.to_lowercase().as_str() {
+                "off" if policy == HistoryPolicy::default() && itr.as_slice().is_empty() => {
+                    return Ok(Box::new(JHistoryCmd {
+                        arg_key: arg_key.to_owned(),
+                        arg_policy: None,
+                    }));
+                }
+                "maxlen" => {
+                    let n = itr.next().and_then(|n| n.parse::<usize>().ok()).ok_or(error::SyntaxError)?;
+                    if n == 0 { return Err(error::SyntaxError); }
+                    policy.max_revisions = Some(n);
+                }
+                "window" => {
+                    let seconds = itr.next().and_then(|s| s.parse::<u64>().ok()).ok_or(error::SyntaxError)?;
+                    policy.window_ms = Some(seconds.checked_mul(1000).ok_or(error::SyntaxError)?);
+                }
+                _ => {
+                    return Err(error::SyntaxError);
+                }
+            }
+        }
+        if policy == HistoryPolicy::default() {
+            policy.max_revisions = Some(json_history::DEFAULT_MAX_REVISIONS);
+        }
+        return Ok(Box::new(JHistoryCmd {
+            arg_key: arg_key.to_owned(),
+            arg_policy: Some(policy),
+        }));
+    }
+    else if cmd == "jrevlist" || cmd == "jrevget" || cmd == "jrevdiff" || cmd == "jrevert" {
+        let arg_key = itr.next().unwrap_or(&empty_string).to_owned();
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let mut revisions = vec![];
+        for r in itr {
+            revisions.push(r.parse::<u64>().map_err(|_| error::SyntaxError)?);
+        }
+        return match (cmd.as_str(), revisions.as_slice()) {
+            ("jrevlist", []) => Ok(Box::new(JRevListCmd { arg_key })),
+            ("jrevget", [revision]) => Ok(Box::new(JRevGetCmd { arg_key, arg_revision: *revision })),
+            ("jrevdiff", [from, to]) => Ok(Box::new(JRevDiffCmd { arg_key, arg_from: *from, arg_to: *to })),
+            ("jrevert", [revision]) => Ok(Box::new(JRevertCmd { arg_key, arg_revision: *revision })),
+            _ => Err(error::SyntaxError)
+        };
+    }
+    else if cmd == "jdiff" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        let other = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() || other.is_empty() { return Err(error::SyntaxError); }
+
+        // VALUE compares against a json literal instead of a second key
+        let (arg_other_key, arg_value) = if other.to_lowercase() == "value" {
+            let value = itr.next().and_then(|v| serde_json::from_str(v).ok()).ok_or(error::SyntaxError)?;
+            (None, Some(value))
+        } else {
+            (Some(other.to_owned()), None)
+        };
+        let arg_paths = match itr.next() {
+            None => false,
+            Some(f) if f.to_lowercase() == "paths" => true,
+            Some(f) if f.to_lowercase() == "patch" => false,
+            Some(_) => return Err(error::SyntaxError)
+        };
+        if itr.next().is_some() { return Err(error::SyntaxError); }
+        return Ok(Box::new(JDiffCmd {
+            arg_key: arg_key.to_owned(),
+            arg_other_key,
+            arg_value,
+            arg_paths,
+        }));
+    }
+    else if cmd == "jagg" {
+        let arg_pattern = itr.next().ok_or(error::SyntaxError)?;
+        let mut query = AggQuery {
+            pattern: arg_pattern.to_owned(),
+            ..AggQuery::default()
+        };
+        while let Some(option) = itr.next() {
+            if option.to_lowercase() == "groupby" {
+                query.group_by = Some(itr.next().ok_or(error::SyntaxError)?.to_owned());
+                continue;
+            }
+            let op = AggOp::from_name(option).ok_or(error::SyntaxError)?;
+            // COUNT alone counts documents, every other aggregate needs a selector
+            let selector = match itr.as_slice().first() {
+                Some(s) if op == AggOp::Count && (AggOp::from_name(s).is_some() || s.to_lowercase() == "groupby") => None,
+                Some(_) => itr.next().map(|s| s.to_owned()),
+                None if op == AggOp::Count => None,
+                None => return Err(error::SyntaxError)
+            };
+            query.aggregates.push((op, selector));
+        }
+        if query.aggregates.is_empty() {
+            return Err(error::SyntaxError);
+        }
+        return Ok(Box::new(JAggCmd {
+            arg_query: query,
+        }));
+    }
+    else if cmd == "jschema" {
+        let arg_pattern = itr.next().unwrap_or(&empty_string);
+        if arg_pattern.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_schema = itr.next().and_then(|s| serde_json::from_str(s).ok()).ok_or(error::SyntaxError)?;
+        return Ok(Box::new(JSchemaCmd {
+            arg_pattern: arg_pattern.to_owned(),
+            arg_schema,
+        }));
+    }
+    else if cmd == "jschemadel" {
+        let arg_pattern = itr.next().unwrap_or(&empty_string);
+        if arg_pattern.is_empty() { return Err(error::SyntaxError); }
+        return Ok(Box::new(JSchemaDelCmd {
+            arg_pattern: arg_pattern.to_owned(),
+        }));
+    }
+    else if cmd == "jschemalist" {
+        return Ok(Box::new(JSchemaListCmd));
+    }
+    else if cmd == "jarrappend" || cmd == "jarrinsert" || cmd == "jarrpop" || cmd == "jarrlen" || cmd == "jarrindex" || cmd == "jarrtrim" {
+        let arg_key = itr.next().unwrap_or(&empty_string);
+        if arg_key.is_empty() { return Err(error::SyntaxError); }
+
+        let arg_path = itr.next().unwrap_or(&empty_string).to_owned();
+        if arg_path.is_empty() { return Err(error::SyntaxError); }
+
+        if cmd == "jarrlen" {
+            return Ok(Box::new(JArrLenCmd {
+                arg_key: arg_key.to_owned(),
+                arg_path,
+            }));
+        }
+
+        if cmd == "jarrpop" {
+            let arg_index = match itr.next() {
+                Some(i) if util::is_integer(i) => i.parse::<i64>().unwrap(),
+                Some(_) => return Err(error::SyntaxError),
+                None => -1
+            };
+            return Ok(Box::new(JArrPopCmd {
+                arg_key: arg_key.to_owned(),
+                arg_path,
+                arg_index,
+            }));
+        }
+
+        if cmd == "jarrtrim" {
+            let arg_start = itr.next().unwrap_or(&empty_string);
+            let arg_stop = itr.next().unwrap_or(&empty_string);
+            if !util::is_integer(arg_start) || !util::is_integer(arg_stop) {
+                return Err(error::SyntaxError);
+            }
+            return Ok(Box::new(JArrTrimCmd {
+                arg_key: arg_key.to_owned(),
+                arg_path,
+                arg_start: arg_start.parse::<i64>().unwrap(),
+                arg_stop: arg_stop.parse::<i64>().unwrap(),
+            }));
+        }
+
+        if cmd == "jarrindex" {
+            let arg_value = itr.next().unwrap_or(&empty_string);
+            if arg_value.is_empty() { return Err(error::SyntaxError); }
+
+            let mut bounds = vec![];
+            for b in itr.by_ref() {
+                if !util::is_integer(b) || bounds.len() == 2 {
+                    return Err(error::SyntaxError);
+                }
+                bounds.push(b.parse::<i64>().unwrap());
+            }
+            return Ok(Box::new(JArrIndexCmd {
+                arg_key: arg_key.to_owned(),
+                arg_path,
+                arg_value: parse_json_value(arg_value),
+                arg_start: bounds.first().cloned().unwrap_or(0),
+                arg_stop: bounds.get(1).cloned(),
+            }));
+        }
+
+        let mut arg_index = 0;
+        if cmd == "jarrinsert" {
+            let index = itr.next().unwrap_or(&empty_string);
+            if !util::is_integer(index) { return Err(error::SyntaxError); }
+            arg_index = index.parse::<i64>().unwrap();
+        }
+
+        let arg_values: Vec<Value> = itr.map(|v| parse_json_value(v)).collect();
+        if arg_values.is_empty() { return Err(error::SyntaxError); }
+
+        if cmd == "jarrinsert" {
+            return Ok(Box::new(JArrInsertCmd {
+                arg_key: arg_key.to_owned(),
+                arg_path,
+                arg_index,
+                arg_values,
+            }));
+        }
+        return Ok(Box::new(JArrAppendCmd {
+            arg_key: arg_key.to_owned(),
+            arg_path,
+            arg_values,
+        }));
+    }
     else if cmd == "jincrby" {
         let arg_key = itr.next().unwrap_or(&empty_string);
         if arg_key.is_empty() { return Err(error::SyntaxError); }
@@ -651,19 +1455,150 @@ pub fn analyse_token_stream(tokens: Vec<String>) -> Result<Box<dyn Command>, err
     Err(error::SyntaxError)
 }
 
-fn check_validate_arg_order(arg_order_string: String, arg_order: &mut ArgOrder) -> Result<(), error::SyntaxError> {
-    if arg_order_string.is_empty() {
-        return Ok(());
-    } else if !arg_order_string.is_empty() && (arg_order_string == "asc" || arg_order_string == "desc") {
-        *arg_order = match arg_order_string.as_str() {
-            "asc" => ArgOrder::ASC,
-            "desc" => ArgOrder::DESC,
+/// Parses `JGET key [path ...] [FORMAT [INDENT s] [NEWLINE s] [SPACE s] [PROJECT]]`, a `FORMAT` with
+/// nothing after it is read as a path so every field name can still be fetched
+fn parse_jget(arg_key: &str, itr: &mut Iter<String>) -> Result<JGetCmd, error::SyntaxError> {
+    let mut arg_paths: Vec<String> = vec![];
+    while let Some(token) = itr.next() {
+        if token.to_lowercase() == "format" && !itr.as_slice().is_empty() {
+            break;
+        }
+        arg_paths.push(token.to_owned());
+    }
+
+    let mut arg_format = JsonFormat::default();
+    let mut arg_project = false;
+    while let Some(option) = itr.next() {
+        match option.to_lowercase().as_str() {
+            "indent" => arg_format.indent = itr.next().ok_or(error::SyntaxError)?.to_owned(),
+            "newline" => arg_format.newline = itr.next().ok_or(error::SyntaxError)?.to_owned(),
+            "space" => arg_format.space = itr.next().ok_or(error::SyntaxError)?.to_owned(),
+            "project" => arg_project = true,
             _ => {
                 return Err(error::SyntaxError);
             }
-        };
-        return Ok(());
+        }
     }
-    Err(error::SyntaxError)
+
+    Ok(JGetCmd {
+        arg_key: arg_key.to_owned(),
+        arg_paths,
+        arg_format,
+        arg_project,
+    })
+}
+
+/// Parses the trailing `[ASC|DESC] [WHERE path op value [AND ...]] [GEOJSON [BBOX] [PRECISION n]]` options
+/// of geo searches, ordering is only accepted by searches that measure distances
+fn parse_geo_search_options(itr: &mut Iter<String>, mut arg_order: Option<&mut ArgOrder>, arg_filter: &mut Option<GeoFilter>, arg_geo_json: &mut Option<GeoJsonOptions>) -> Result<(), error::SyntaxError> {
+    while let Some(option) = itr.next() {
+        match (option.to_lowercase().as_str(), arg_order.as_mut()) {
+            ("asc", Some(order)) => **order = ArgOrder::ASC,
+            ("desc", Some(order)) => **order = ArgOrder::DESC,
+            ("where", _) => *arg_filter = Some(parse_where_clause(itr)?),
+            ("geojson", _) => *arg_geo_json = Some(parse_geo_json_options(itr)?),
+            _ => {
+                return Err(error::SyntaxError);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_geo_json_option(token: &String) -> bool {
+    let token = token.to_lowercase();
+    token == "bbox" || token == "precision"
+}
+
+fn parse_geo_json_options(itr: &mut Iter<String>) -> Result<GeoJsonOptions, error::SyntaxError> {
+    let mut options = GeoJsonOptions::default();
+    while let Some(option) = itr.as_slice().first() {
+        if !is_geo_json_option(option) {
+            break;
+        }
+        itr.next();
+        if option.to_lowercase() == "bbox" {
+            options.bbox = true;
+            continue;
+        }
+        match itr.next().map(|p| p.parse::<u32>()) {
+            Some(Ok(precision)) if precision <= 15 => options.precision = Some(precision),
+            _ => {
+                return Err(error::SyntaxError);
+            }
+        }
+    }
+    Ok(options)
 }
 
+fn parse_where_clause(itr: &mut Iter<String>) -> Result<GeoFilter, error::SyntaxError> {
+    let mut filter = GeoFilter::default();
+    loop {
+        let path = itr.next().ok_or(error::SyntaxError)?;
+        let op = itr.next().and_then(|op| FilterOp::parse(op)).ok_or(error::SyntaxError)?;
+        let value = itr.next().ok_or(error::SyntaxError)?;
+
+        filter.conditions.push(FilterCondition {
+            path: path.to_owned(),
+            op,
+            value: parse_filter_value(value),
+        });
+
+        match itr.as_slice().first() {
+            Some(next) if next.to_lowercase() == "and" => {
+                itr.next();
+            }
+            _ => {
+                return Ok(filter);
+            }
+        }
+    }
+}
+
+fn parse_filter_value(value_string: &String) -> Value {
+    if util::is_numeric(value_string) {
+        return json!(value_string.parse::<f64>().unwrap());
+    }
+    match value_string.as_str() {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        "null" => Value::Null,
+        s => Value::String(s.trim_matches('"').to_owned())
+    }
+}
+
+/// Values that are not valid json are taken as strings
+fn parse_json_value(value_string: &String) -> Value {
+    serde_json::from_str(value_string).unwrap_or(Value::String(value_string.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jget(args: &[&str]) -> Result<JGetCmd, error::SyntaxError> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        parse_jget("doc", &mut args.iter())
+    }
+
+    #[test]
+    fn test_parse_jget() {
+        // option names are plain paths until FORMAT
+        let cmd = jget(&["space"]).unwrap();
+        assert_eq!(cmd.arg_paths, vec!["space".to_owned()]);
+        assert_eq!(cmd.arg_format, JsonFormat::default());
+        let cmd = jget(&["indent", "name"]).unwrap();
+        assert_eq!(cmd.arg_paths, vec!["indent".to_owned(), "name".to_owned()]);
+        assert_eq!(jget(&["a", "format"]).unwrap().arg_paths, vec!["a".to_owned(), "format".to_owned()]);
+
+        let cmd = jget(&["a", "b", "FORMAT", "INDENT", "  ", "SPACE", " ", "PROJECT"]).unwrap();
+        assert_eq!(cmd.arg_paths, vec!["a".to_owned(), "b".to_owned()]);
+        assert_eq!(cmd.arg_format.indent, "  ");
+        assert_eq!(cmd.arg_format.space, " ");
+        assert!(cmd.arg_project);
+        assert!(jget(&["FORMAT", "PROJECT"]).unwrap().arg_paths.is_empty());
+
+        assert!(jget(&["a", "FORMAT", "INDENT"]).is_err());
+        assert!(jget(&["a", "FORMAT", "b"]).is_err());
+    }
+}
@@ -23,3 +23,18 @@ pub fn mi_m(mi: f64) -> f64 { mi * 1609.344 }
 
 pub fn km_m(km: f64) -> f64 { km * 1000.000 }
 
+pub fn to_m(value: f64, unit: Units) -> f64 {
+    match unit {
+        Units::Kilometers => km_m(value),
+        Units::Miles => mi_m(value),
+        Units::Meters => value,
+    }
+}
+
+pub fn from_m(m: f64, unit: Units) -> f64 {
+    match unit {
+        Units::Kilometers => m_km(m),
+        Units::Miles => m_mi(m),
+        Units::Meters => m,
+    }
+}
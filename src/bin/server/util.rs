@@ -1,4 +1,5 @@
 use serde_json::Value;
+use crate::unit_conv;
 use crate::unit_conv::Units;
 
 pub fn merge(a: &mut Value, b: &Value) {
@@ -21,6 +22,19 @@ pub const ALPHA_NUMERIC: [char; 62] = [
     'T', 'U', 'V', 'W', 'X', 'Y', 'Z',
 ];
 
+/// Milliseconds since the unix epoch
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Literal start of a glob, the part of the key space a prefix scan has to visit
+pub fn glob_prefix(pattern: &str) -> String {
+    pattern.chars().take_while(|c| !matches!(c, '*' | '?' | '[')).collect()
+}
+
 pub fn is_numeric(num_str: &String) -> bool {
     if num_str.is_empty() {
         return false;
@@ -59,6 +73,7 @@ pub fn is_json(json_str: &String) -> bool {
     return is_json;
 }
 
+/// Great circle distance in meters between two `(lat, lng)` pairs
 pub fn get_distance(a: (f64, f64), b: (f64, f64)) -> f64 {
     let loc_a = Location { latitude: a.0, longitude: a.1 };
     let loc_b = Location { latitude: b.0, longitude: b.1 };
@@ -66,23 +81,16 @@ pub fn get_distance(a: (f64, f64), b: (f64, f64)) -> f64 {
     haversine_distance(loc_a, loc_b, Units::Meters)
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct Location {
     pub latitude: f64,
     pub longitude: f64,
 }
 
 
+/// Great circle distance on a sphere with the mean earth radius
 pub fn haversine_distance(start: Location, end: Location, units: Units) -> f64 {
-    let kilometers: f64 = 6371.0;
-    let miles: f64 = 3960.0;
-    let meters: f64 = 6_371_000.000000;
-    let mut r: f64 = 0.0;
-
-    match units {
-        Units::Miles => r = miles,
-        Units::Kilometers => r = kilometers,
-        Units::Meters => r = meters
-    }
+    let r: f64 = 6_371_000.000000;
 
     let d_lat: f64 = (end.latitude - start.latitude).to_radians();
     let d_lon: f64 = (end.longitude - start.longitude).to_radians();
@@ -92,9 +100,66 @@ pub fn haversine_distance(start: Location, end: Location, units: Units) -> f64 {
     let a: f64 = ((d_lat / 2.0).sin()) * ((d_lat / 2.0).sin()) + ((d_lon / 2.0).sin()) * ((d_lon / 2.0).sin()) * (lat1.cos()) * (lat2.cos());
     let c: f64 = 2.0 * ((a.sqrt()).atan2((1.0 - a).sqrt()));
 
-    return r * c;
+    unit_conv::from_m(r * c, units)
+}
+
+/// Distance in meters on the WGS84 ellipsoid using Vincenty's inverse formula,
+/// `None` when the iteration does not converge, which happens for nearly antipodal points
+pub fn vincenty_distance(start: Location, end: Location) -> Option<f64> {
+    let a: f64 = 6_378_137.0;
+    let f: f64 = 1.0 / 298.257_223_563;
+    let b: f64 = a * (1.0 - f);
+
+    let l = (end.longitude - start.longitude).to_radians();
+    let u1 = ((1.0 - f) * start.latitude.to_radians().tan()).atan();
+    let u2 = ((1.0 - f) * end.latitude.to_radians().tan()).atan();
+    let (sin_u1, cos_u1) = u1.sin_cos();
+    let (sin_u2, cos_u2) = u2.sin_cos();
+
+    let mut lambda = l;
+    for _ in 0..200 {
+        let (sin_lambda, cos_lambda) = lambda.sin_cos();
+        let sin_sigma = ((cos_u2 * sin_lambda).powi(2)
+            + (cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda).powi(2)).sqrt();
+        if sin_sigma == 0.0 {
+            // coincident points
+            return Some(0.0);
+        }
+        let cos_sigma = sin_u1 * sin_u2 + cos_u1 * cos_u2 * cos_lambda;
+        let sigma = sin_sigma.atan2(cos_sigma);
+        let sin_alpha = cos_u1 * cos_u2 * sin_lambda / sin_sigma;
+        let cos_2_alpha = 1.0 - sin_alpha * sin_alpha;
+        // both points on the equator
+        let cos_2_sigma_m = if cos_2_alpha == 0.0 { 0.0 } else { cos_sigma - 2.0 * sin_u1 * sin_u2 / cos_2_alpha };
+        let c = f / 16.0 * cos_2_alpha * (4.0 + f * (4.0 - 3.0 * cos_2_alpha));
+        let lambda_prev = lambda;
+        lambda = l + (1.0 - c) * f * sin_alpha
+            * (sigma + c * sin_sigma * (cos_2_sigma_m + c * cos_sigma * (-1.0 + 2.0 * cos_2_sigma_m * cos_2_sigma_m)));
+
+        if (lambda - lambda_prev).abs() < 1e-12 {
+            let u_2 = cos_2_alpha * (a * a - b * b) / (b * b);
+            let k_a = 1.0 + u_2 / 16384.0 * (4096.0 + u_2 * (-768.0 + u_2 * (320.0 - 175.0 * u_2)));
+            let k_b = u_2 / 1024.0 * (256.0 + u_2 * (-128.0 + u_2 * (74.0 - 47.0 * u_2)));
+            let delta_sigma = k_b * sin_sigma * (cos_2_sigma_m + k_b / 4.0
+                * (cos_sigma * (-1.0 + 2.0 * cos_2_sigma_m * cos_2_sigma_m)
+                - k_b / 6.0 * cos_2_sigma_m * (-3.0 + 4.0 * sin_sigma * sin_sigma) * (-3.0 + 4.0 * cos_2_sigma_m * cos_2_sigma_m)));
+            return Some(b * k_a * (sigma - delta_sigma));
+        }
+    }
+    None
 }
 
+/// Initial bearing from `start` to `end` in degrees clockwise from north, in [0, 360)
+pub fn bearing(start: Location, end: Location) -> f64 {
+    let lat1 = start.latitude.to_radians();
+    let lat2 = end.latitude.to_radians();
+    let d_lon = (end.longitude - start.longitude).to_radians();
+
+    let y = d_lon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos();
+
+    (y.atan2(x).to_degrees() + 360.0) % 360.0
+}
 
 #[cfg(test)]
 mod tests {
@@ -155,6 +220,17 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_vincenty_distance() {
+        // Flinders Peak to Buninyong, the worked example of Vincenty's paper
+        let flinders = Location { latitude: -37.0 - 57.0 / 60.0 - 3.72030 / 3600.0, longitude: 144.0 + 25.0 / 60.0 + 29.52440 / 3600.0 };
+        let buninyong = Location { latitude: -37.0 - 39.0 / 60.0 - 10.15610 / 3600.0, longitude: 143.0 + 55.0 / 60.0 + 35.38390 / 3600.0 };
+        let distance = vincenty_distance(flinders, buninyong).unwrap();
+        assert!((distance - 54_972.271).abs() < 0.01);
+        assert_eq!(vincenty_distance(flinders, flinders), Some(0.0));
+        assert!(vincenty_distance(Location { latitude: 0.0, longitude: 0.0 }, Location { latitude: 0.5, longitude: 179.7 }).is_none());
+    }
+
     #[test]
     fn test_numeric() {
         let float_str = String::from("8.5");